@@ -0,0 +1,273 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+use indicatif::HumanBytes;
+use log::{debug, info, warn};
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+use walkdir::WalkDir;
+use crate::fs_utils::{ensure_parent, sha1_file};
+use crate::context::Context;
+
+// Tells apart the temp files of stores running at the same time
+static STORE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Debug)]
+pub struct DownloadCache {
+    dir: PathBuf,
+    max_size: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum CacheKey<'a> {
+    // The sha1 the source published for the file, identical files are only stored once.
+    Sha1(&'a str),
+    Url(&'a str),
+}
+
+struct CacheEntry {
+    path: PathBuf,
+    size: u64,
+    last_used: SystemTime,
+}
+
+impl DownloadCache {
+    pub fn new<T: AsRef<Path>>(dir: T, max_size: Option<u64>) -> Self {
+        DownloadCache {
+            dir: dir.as_ref().to_path_buf(),
+            max_size,
+        }
+    }
+
+    pub fn default_dir() -> PathBuf {
+        dirs::cache_dir()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("mcsi")
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn entry_path(&self, key: CacheKey) -> PathBuf {
+        let (kind, hash) = match key {
+            CacheKey::Sha1(sha1) => ("sha1", sha1.to_lowercase()),
+            CacheKey::Url(url) => ("url", format!("{:x}", Sha1::digest(url.as_bytes()))),
+        };
+
+        self.dir
+            .join(kind)
+            .join(&hash[..2])
+            .join(hash)
+    }
+
+    // Copies the cached file to `dst`, returns false if the file isn't cached.
+    pub async fn fetch<T: AsRef<Path>>(&self, key: CacheKey<'_>, dst: T) -> color_eyre::Result<bool> {
+        let entry = self.entry_path(key);
+        if !entry.is_file() {
+            return Ok(false);
+        }
+
+        if let CacheKey::Sha1(sha1) = key
+            && !sha1_file(&entry)?.eq_ignore_ascii_case(sha1) {
+            warn!("Evicting {:?} from the download cache, it doesn't match its sha1", entry);
+            std::fs::remove_file(&entry)?;
+            return Ok(false);
+        }
+
+        tokio::fs::copy(&entry, dst)
+            .await?;
+
+        // Mark the entry as recently used so it's evicted last
+        File::options()
+            .write(true)
+            .open(&entry)?
+            .set_modified(SystemTime::now())?;
+
+        Ok(true)
+    }
+
+    pub async fn store<T: AsRef<Path>>(&self, key: CacheKey<'_>, src: T) -> color_eyre::Result<()> {
+        let entry = self.entry_path(key);
+        ensure_parent(&entry)
+            .await?;
+
+        // Only complete files are renamed into place, so an interrupted store leaves no broken entry
+        let tmp = entry.with_extension(format!("{}-{}.tmp", std::process::id(), STORE_COUNTER.fetch_add(1, Ordering::Relaxed)));
+        if let Err(err) = tokio::fs::copy(src, &tmp).await {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return Err(err)?;
+        }
+        tokio::fs::rename(&tmp, &entry)
+            .await?;
+
+        if let Some(max_size) = self.max_size {
+            self.trim_to(max_size)?;
+        }
+
+        Ok(())
+    }
+
+    fn entries(&self) -> color_eyre::Result<Vec<CacheEntry>> {
+        if !self.dir.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut entries = Vec::new();
+        for entry in WalkDir::new(&self.dir) {
+            let entry = entry?;
+            if !entry.path().is_file() {
+                continue;
+            }
+
+            let metadata = entry.metadata()?;
+            entries.push(CacheEntry {
+                path: entry.path().to_path_buf(),
+                size: metadata.len(),
+                last_used: metadata.modified()?,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    pub fn size(&self) -> color_eyre::Result<u64> {
+        Ok(self.entries()?.iter().map(|entry| entry.size).sum())
+    }
+
+    // The least recently used files that have to go for the cache to fit in `max_size` bytes.
+    fn evictions(&self, max_size: u64) -> color_eyre::Result<Vec<CacheEntry>> {
+        let mut entries = self.entries()?;
+        let mut size: u64 = entries.iter().map(|entry| entry.size).sum();
+
+        entries.sort_by_key(|entry| entry.last_used);
+        let evictions = entries.into_iter()
+            .take_while(|entry| {
+                let evict = size > max_size;
+                size = size.saturating_sub(entry.size);
+                evict
+            })
+            .collect();
+
+        Ok(evictions)
+    }
+
+    pub fn trim_to(&self, max_size: u64) -> color_eyre::Result<()> {
+        for entry in self.evictions(max_size)? {
+            debug!("Evicting {:?} from the download cache", entry.path);
+            std::fs::remove_file(&entry.path)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn clean(&self) -> color_eyre::Result<()> {
+        if self.dir.is_dir() {
+            std::fs::remove_dir_all(&self.dir)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn handle_clean(ctx: &Context, cache: DownloadCache, max_size: Option<u64>) -> color_eyre::Result<()> {
+    let before = cache.size()?;
+
+    if ctx.is_dry_run() {
+        let freed = match max_size {
+            Some(max_size) => cache.evictions(max_size)?.iter().map(|entry| entry.size).sum(),
+            None => before,
+        };
+        ctx.plan.change(format!("Free {} from {:?}", HumanBytes(freed), cache.dir()));
+        return Ok(());
+    }
+
+    match max_size {
+        Some(max_size) => cache.trim_to(max_size)?,
+        None => cache.clean()?,
+    }
+
+    let after = cache.size()?;
+    info!("Freed {} from {:?}, {} left", HumanBytes(before - after), cache.dir(), HumanBytes(after));
+
+    Ok(())
+}
+
+// Parses sizes like `500M`, `10G` or a plain number of bytes.
+pub fn parse_size(size: &str) -> Result<u64, CacheError> {
+    let invalid = || CacheError::InvalidSize(size.to_string());
+
+    let unit_start = size.find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (amount, unit) = size.split_at(unit_start);
+    let amount: u64 = amount.parse().map_err(|_| invalid())?;
+
+    let multiplier: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        "T" | "TB" => 1 << 40,
+        _ => return Err(invalid()),
+    };
+
+    amount.checked_mul(multiplier)
+        .ok_or_else(invalid)
+}
+
+#[derive(Error, Debug)]
+pub enum CacheError {
+    #[error("`{0}` is not a valid size, expected something like `500M` or `10G`")]
+    InvalidSize(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{read_to_string, remove_dir_all, write};
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+    use crate::cache::{CacheKey, DownloadCache, parse_size};
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("500M").unwrap(), 500 * 1024 * 1024);
+        assert_eq!(parse_size("10gb").unwrap(), 10 * 1024 * 1024 * 1024);
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used() {
+        let dir = PathBuf::from("./.mcsi-test-dir")
+            .join("tests")
+            .join("download-cache");
+        if dir.is_dir() {
+            remove_dir_all(&dir).unwrap();
+        }
+        let cache = DownloadCache::new(dir.join("cache"), Some(10));
+        let src = dir.join("src");
+        std::fs::create_dir_all(&src).unwrap();
+
+        write(src.join("a"), "aaaaaa").unwrap();
+        cache.store(CacheKey::Url("https://example.com/a"), src.join("a")).await.unwrap();
+        let a = cache.entry_path(CacheKey::Url("https://example.com/a"));
+        std::fs::File::options().write(true).open(&a).unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(60)).unwrap();
+
+        write(src.join("b"), "bbbbbb").unwrap();
+        cache.store(CacheKey::Sha1("0e03c6205ea671d7d41a0e3aabfc9d15d97e5ed3"), src.join("b")).await.unwrap();
+
+        assert!(!cache.fetch(CacheKey::Url("https://example.com/a"), src.join("a2")).await.unwrap());
+        assert!(cache.fetch(CacheKey::Sha1("0E03C6205EA671D7D41A0E3AABFC9D15D97E5ED3"), src.join("b2")).await.unwrap());
+        assert_eq!(read_to_string(src.join("b2")).unwrap(), "bbbbbb");
+
+        // A truncated entry is evicted instead of served
+        let b = cache.entry_path(CacheKey::Sha1("0e03c6205ea671d7d41a0e3aabfc9d15d97e5ed3"));
+        write(&b, "bbb").unwrap();
+        assert!(!cache.fetch(CacheKey::Sha1("0e03c6205ea671d7d41a0e3aabfc9d15d97e5ed3"), src.join("b3")).await.unwrap());
+        assert!(!b.exists());
+    }
+}
@@ -1,6 +1,16 @@
-use clap::{command, Subcommand, Parser, ValueEnum};
-use indicatif::ProgressStyle;
+use std::path::PathBuf;
+use std::time::Duration;
+use clap::{Args, Subcommand, Parser, ValueEnum};
 use log::LevelFilter;
+use mc_server_installer::cache;
+use mc_server_installer::cache::DownloadCache;
+use mc_server_installer::http;
+use mc_server_installer::http::{Endpoints, HttpConfig};
+use mc_server_installer::modpack::{backup, jvm, server_files};
+use mc_server_installer::modpack::jvm::JvmConfig;
+use mc_server_installer::modpack::server_files::ServerSettings;
+use mc_server_installer::modpack::service::{Restart, ServiceConfig};
+use mc_server_installer::version::{McVersion, McVersionRange};
 
 #[derive(Parser, Clone, Debug)]
 pub struct Cli {
@@ -8,35 +18,252 @@ pub struct Cli {
     pub sub_command: CliSubCommand,
     #[clap(env, long, default_value = "info")]
     pub rust_log: LogLevel,
+    /// Keep downloaded files in a cache shared between installs
+    #[clap(env, long, global = true)]
+    pub cache: bool,
+    /// Where downloads are cached, defaults to the user cache dir. Enables the cache
+    #[clap(env, long, global = true)]
+    pub cache_dir: Option<PathBuf>,
+    /// Evict the least recently used downloads once the cache grows past this, e.g. `10G`
+    #[clap(env, long, global = true, value_parser = cache::parse_size)]
+    pub cache_max_size: Option<u64>,
+    /// Resolve everything and print what would change, without downloading, running or changing anything
+    #[clap(env, long, global = true)]
+    pub dry_run: bool,
+    /// `json` prints newline delimited events to stdout instead of logs
+    #[clap(env, long, global = true, default_value = "text")]
+    pub output: OutputFormat,
+    #[command(flatten)]
+    pub http: HttpArgs,
+    #[command(flatten)]
+    pub jvm: JvmArgs,
+    #[command(flatten)]
+    pub server: ServerArgs,
+    #[command(flatten)]
+    pub service: ServiceArgs,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct HttpArgs {
+    /// Send every request through this proxy, e.g. `http://proxy.local:3128`
+    #[clap(env, long, global = true)]
+    pub proxy: Option<String>,
+    /// Seconds to wait for a connection
+    #[clap(env, long, global = true, default_value_t = 30)]
+    pub connect_timeout: u64,
+    /// Seconds a request may go without receiving any data
+    #[clap(env, long, global = true, default_value_t = 60)]
+    pub read_timeout: u64,
+    /// PEM file with extra root certificates to trust
+    #[clap(env, long, global = true)]
+    pub ca_cert: Option<PathBuf>,
+    #[clap(env, long, global = true)]
+    pub user_agent: Option<String>,
+    #[clap(env, long, global = true)]
+    pub curseforge_api_url: Option<String>,
+    #[clap(env, long, global = true)]
+    pub ftb_api_url: Option<String>,
+    #[clap(env, long, global = true)]
+    pub forge_maven_url: Option<String>,
+    #[clap(env, long, global = true)]
+    pub neoforge_maven_url: Option<String>,
+    #[clap(env, long, global = true)]
+    pub fabric_meta_url: Option<String>,
+    #[clap(env, long, global = true)]
+    pub mojang_api_url: Option<String>,
+}
+
+/// How the generated start script runs the server, `mcsi.toml` settings win over these
+#[derive(Args, Clone, Debug)]
+pub struct JvmArgs {
+    /// Initial heap, e.g. `2G`
+    #[clap(env, long, global = true)]
+    pub min_memory: Option<String>,
+    /// Heap used when `SERVER_MEMORY` is unset, e.g. `8G`
+    #[clap(env, long, global = true)]
+    pub max_memory: Option<String>,
+    /// Tuned JVM flags to start with
+    #[clap(env, long, global = true)]
+    pub jvm_preset: Option<JvmPreset>,
+    /// Extra JVM arg, can be repeated
+    #[clap(env, long = "jvm-arg", global = true, allow_hyphen_values = true)]
+    pub jvm_args: Vec<String>,
+    /// Extra arg passed to the server, can be repeated
+    #[clap(env, long = "program-arg", global = true, allow_hyphen_values = true)]
+    pub program_args: Vec<String>,
+    /// Java executable, defaults to `java` on the path
+    #[clap(env, long, global = true)]
+    pub java_path: Option<String>,
+}
+
+impl JvmArgs {
+    pub fn config(&self) -> JvmConfig {
+        JvmConfig {
+            min_memory: self.min_memory.clone(),
+            max_memory: self.max_memory.clone(),
+            preset: self.jvm_preset.map(Into::into),
+            args: self.jvm_args.clone(),
+            program_args: self.program_args.clone(),
+            java: self.java_path.clone(),
+        }
+    }
+}
+
+/// Written for the server after every install, `mcsi.toml` settings win over these
+#[derive(Args, Clone, Debug)]
+pub struct ServerArgs {
+    /// Agree to the minecraft EULA (https://aka.ms/MinecraftEULA) by writing eula.txt
+    #[clap(env, long, global = true)]
+    pub accept_eula: bool,
+    /// Set a key in server.properties, like `motd=My Server`, can be repeated
+    #[clap(long = "server-property", global = true, value_parser = server_files::parse_property)]
+    pub server_properties: Vec<(String, String)>,
+    /// Make a player, by name or UUID, an operator, can be repeated
+    #[clap(env, long = "op", global = true, value_delimiter = ',')]
+    pub ops: Vec<String>,
+    /// Permission level of the ops, 1 to 4
+    #[clap(env, long, global = true)]
+    pub op_level: Option<u8>,
+    /// Add a player, by name or UUID, to the whitelist, can be repeated
+    #[clap(env, long = "whitelist", global = true, value_delimiter = ',')]
+    pub whitelist: Vec<String>,
+    /// Ban a player, by name or UUID, can be repeated
+    #[clap(env, long = "ban", global = true, value_delimiter = ',')]
+    pub banned: Vec<String>,
+}
+
+impl ServerArgs {
+    pub fn settings(&self) -> ServerSettings {
+        ServerSettings {
+            accept_eula: self.accept_eula,
+            properties: self.server_properties.iter().cloned().collect(),
+            ops: self.ops.clone(),
+            op_level: self.op_level,
+            whitelist: self.whitelist.clone(),
+            banned: self.banned.clone(),
+        }
+    }
+}
+
+/// How the systemd unit of the server runs it
+#[derive(Args, Clone, Debug)]
+pub struct ServiceArgs {
+    /// Write a systemd unit, mc-server.service, next to mc-start.sh after every install
+    #[clap(env, long, global = true)]
+    pub systemd_unit: bool,
+    /// Name of the unit, defaults to `mc-` and the name of the server dir
+    #[clap(env, long, global = true)]
+    pub service_name: Option<String>,
+    /// User the server runs as, defaults to the user running the install
+    #[clap(env, long, global = true)]
+    pub service_user: Option<String>,
+    /// Hard memory limit of the whole service, e.g. `10G`
+    #[clap(env, long, global = true)]
+    pub service_memory_max: Option<String>,
+    #[clap(env, long, global = true, default_value = "on-failure")]
+    pub service_restart: ServiceRestart,
+    /// Seconds the server gets to save and stop before it is killed
+    #[clap(env, long, global = true, default_value_t = 120)]
+    pub service_stop_timeout: u64,
+}
+
+impl ServiceArgs {
+    pub fn config(&self) -> ServiceConfig {
+        ServiceConfig {
+            write_unit: self.systemd_unit,
+            name: self.service_name.clone(),
+            user: self.service_user.clone(),
+            memory_max: self.service_memory_max.clone(),
+            restart: self.service_restart.into(),
+            stop_timeout: self.service_stop_timeout,
+        }
+    }
+}
+
+impl HttpArgs {
+    pub fn config(&self) -> HttpConfig {
+        let defaults = Endpoints::default();
+
+        HttpConfig {
+            proxy: self.proxy.clone(),
+            connect_timeout: Duration::from_secs(self.connect_timeout),
+            read_timeout: Duration::from_secs(self.read_timeout),
+            ca_cert: self.ca_cert.clone(),
+            user_agent: self.user_agent.clone().unwrap_or_else(|| http::USER_AGENT.to_string()),
+            endpoints: Endpoints {
+                curseforge_api: self.curseforge_api_url.clone().unwrap_or(defaults.curseforge_api),
+                ftb_api: self.ftb_api_url.clone().unwrap_or(defaults.ftb_api),
+                forge_maven: self.forge_maven_url.clone().unwrap_or(defaults.forge_maven),
+                neoforge_maven: self.neoforge_maven_url.clone().unwrap_or(defaults.neoforge_maven),
+                fabric_meta: self.fabric_meta_url.clone().unwrap_or(defaults.fabric_meta),
+                mojang_api: self.mojang_api_url.clone().unwrap_or(defaults.mojang_api),
+            },
+        }
+    }
+}
+
+impl Cli {
+    pub fn download_cache(&self) -> DownloadCache {
+        let dir = self.cache_dir.clone()
+            .unwrap_or_else(DownloadCache::default_dir);
+
+        DownloadCache::new(dir, self.cache_max_size)
+    }
 }
 
 #[derive(Clone, Debug, Subcommand)]
 pub enum CliSubCommand {
     Flame {
-        #[clap(env, long)]
-        api_key: String,
-        #[clap(env, long)]
-        project_id: u64,
-        #[clap(env, long)]
-        version: String,
+        #[clap(env, long, required_unless_present = "offline")]
+        api_key: Option<String>,
+        #[clap(env, long, required_unless_present_any = ["locked", "offline"])]
+        project_id: Option<u64>,
+        #[clap(env, long, required_unless_present_any = ["locked", "offline"])]
+        version: Option<String>,
         #[clap(env, long)]
         target_dir: String,
         #[clap(env, long)]
         skip_server_pack: bool,
+        /// Reinstall exactly what the mcsi.lock in the target dir records, instead of resolving the pack
+        #[clap(env, long)]
+        locked: bool,
+        /// Install this bundle from export-bundle instead, without any network access
+        #[clap(env, long, value_name = "BUNDLE")]
+        offline: Option<PathBuf>,
+        /// Remove the previously installed pack files without backing them up
+        #[clap(env, long)]
+        no_backup: bool,
+        /// How the previously installed pack files are backed up
+        #[clap(env, long, default_value = "dir")]
+        backup_format: BackupFormat,
     },
     Ftb {
-        #[clap(env, long,  required_unless_present="id", conflicts_with="id")]
+        #[clap(env, long,  required_unless_present_any=["id", "locked", "offline"], conflicts_with="id")]
         search_terms: Option<Vec<String>>,
-        #[clap(env, long, required_unless_present="search_terms")]
+        #[clap(env, long, required_unless_present_any=["search_terms", "locked", "offline"])]
         id: Option<String>,
+        /// Only pick packs for these minecraft versions, like `1.20.1` or `>=1.20.5`
         #[clap(env, long, requires="search_terms")]
-        mc_version: Option<String>,
-        #[clap(env, long)]
-        version: String,
+        mc_version: Option<McVersionRange>,
+        #[clap(env, long, required_unless_present_any = ["locked", "offline"])]
+        version: Option<String>,
         #[clap(env, long)]
         target_dir: String,
+        /// Reinstall exactly what the mcsi.lock in the target dir records, instead of resolving the pack
+        #[clap(env, long)]
+        locked: bool,
+        /// Install this bundle from export-bundle instead, without any network access
+        #[clap(env, long, value_name = "BUNDLE")]
+        offline: Option<PathBuf>,
+        /// Remove the previously installed pack files without backing them up
+        #[clap(env, long)]
+        no_backup: bool,
+        /// How the previously installed pack files are backed up
+        #[clap(env, long, default_value = "dir")]
+        backup_format: BackupFormat,
     },
     NeoForge {
+        /// Like `20.4.237`, or `1.20.1-47.1.106` for the 1.20.1 fork
         #[clap(env, long)]
         version: String,
         #[clap(env, long)]
@@ -44,7 +271,7 @@ pub enum CliSubCommand {
     },
     Forge {
         #[clap(env, long)]
-        mc_version: String,
+        mc_version: McVersion,
         #[clap(env, long)]
         version: String,
         #[clap(env, long)]
@@ -52,12 +279,172 @@ pub enum CliSubCommand {
     },
     Fabric {
         #[clap(env, long)]
-        mc_version: String,
+        mc_version: McVersion,
         #[clap(env, long)]
         version: String,
         #[clap(env, long)]
         target_dir: String,
-    }
+    },
+    /// Converges the target dir to the server described by its mcsi.toml
+    Apply {
+        #[clap(env, long)]
+        target_dir: String,
+        /// Use this config instead of the mcsi.toml in the target dir
+        #[clap(env, long)]
+        config: Option<PathBuf>,
+        /// Required for CurseForge packs
+        #[clap(env, long)]
+        api_key: Option<String>,
+        /// Remove the previously installed pack files without backing them up
+        #[clap(env, long)]
+        no_backup: bool,
+        /// How the previously installed pack files are backed up
+        #[clap(env, long, default_value = "dir")]
+        backup_format: BackupFormat,
+    },
+    /// Resolves a pack and writes everything needed to install it into a single archive, installed with `--offline`
+    ExportBundle {
+        /// Where to write the bundle
+        #[clap(env, long)]
+        output: PathBuf,
+        #[command(subcommand)]
+        source: BundleSource,
+    },
+    /// Checks the installed pack files against the pack manifest
+    Verify {
+        #[clap(env, long)]
+        target_dir: String,
+        /// Restore missing and corrupted files from the original pack source
+        #[clap(env, long)]
+        repair: bool,
+        /// Required to repair CurseForge packs
+        #[clap(env, long)]
+        api_key: Option<String>,
+    },
+    /// Removes the installed pack files, leaving worlds and user created files in place
+    Uninstall {
+        #[clap(env, long)]
+        target_dir: String,
+        /// Back up the pack files before removing them
+        #[clap(long, num_args = 0..=1, default_missing_value = "dir")]
+        backup: Option<BackupFormat>,
+    },
+    /// Installs or updates the pack when the requested version changed, then becomes the server. Meant as the `CMD` of a server image
+    ContainerEntrypoint {
+        #[clap(env, long)]
+        target_dir: String,
+        /// Where the pack comes from, leave unset to only start what is installed
+        #[clap(env, long)]
+        pack: Option<ContainerPack>,
+        /// Required for CurseForge packs
+        #[clap(env, long, required_if_eq("pack", "flame"))]
+        api_key: Option<String>,
+        #[clap(env, long, required_if_eq("pack", "flame"))]
+        project_id: Option<u64>,
+        /// Id of the FTB pack
+        #[clap(env, long, required_if_eq("pack", "ftb"))]
+        id: Option<String>,
+        /// A file id, a file name for CurseForge, or `latest` to follow the newest release
+        #[clap(env, long, default_value = "latest")]
+        version: String,
+        #[clap(env, long)]
+        skip_server_pack: bool,
+        /// Remove the previously installed pack files without backing them up
+        #[clap(env, long)]
+        no_backup: bool,
+        /// How the previously installed pack files are backed up
+        #[clap(env, long, default_value = "dir")]
+        backup_format: BackupFormat,
+    },
+    /// Installs the server in the target dir as a systemd service
+    InstallService {
+        #[clap(env, long)]
+        target_dir: String,
+        /// Where the unit is installed
+        #[clap(env, long, default_value = "/etc/systemd/system")]
+        unit_dir: PathBuf,
+        /// Start the service right away
+        #[clap(env, long)]
+        start: bool,
+    },
+    /// Manages the download cache
+    Cache {
+        #[command(subcommand)]
+        command: CacheSubCommand,
+    },
+    /// Manages the backups taken before a pack is updated
+    Backup {
+        #[command(subcommand)]
+        command: BackupSubCommand,
+    },
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum BundleSource {
+    Flame {
+        #[clap(env, long)]
+        api_key: String,
+        #[clap(env, long)]
+        project_id: u64,
+        #[clap(env, long)]
+        version: String,
+        #[clap(env, long)]
+        skip_server_pack: bool,
+    },
+    Ftb {
+        #[clap(env, long,  required_unless_present="id", conflicts_with="id")]
+        search_terms: Option<Vec<String>>,
+        #[clap(env, long, required_unless_present="search_terms")]
+        id: Option<String>,
+        /// Only pick packs for these minecraft versions, like `1.20.1` or `>=1.20.5`
+        #[clap(env, long, requires="search_terms")]
+        mc_version: Option<McVersionRange>,
+        #[clap(env, long)]
+        version: String,
+    },
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum CacheSubCommand {
+    /// Removes cached downloads
+    Clean {
+        /// Only evict the least recently used downloads until the cache is this small, e.g. `5G`
+        #[clap(long, value_parser = cache::parse_size)]
+        max_size: Option<u64>,
+    },
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum BackupSubCommand {
+    /// Lists all backups, newest first
+    List {
+        #[clap(env, long)]
+        target_dir: String,
+    },
+    /// Swaps the installed pack files with the files of a backup
+    Restore {
+        name: String,
+        #[clap(env, long)]
+        target_dir: String,
+        /// Remove the currently installed pack files without backing them up
+        #[clap(env, long)]
+        no_backup: bool,
+        /// How the currently installed pack files are backed up
+        #[clap(env, long, default_value = "dir")]
+        backup_format: BackupFormat,
+    },
+    /// Removes old backups
+    #[clap(group = clap::ArgGroup::new("policy").required(true).multiple(true))]
+    Prune {
+        #[clap(env, long)]
+        target_dir: String,
+        /// Always keep the newest N backups
+        #[clap(long, group = "policy")]
+        keep: Option<usize>,
+        /// Only remove backups older than this, e.g. `12h`, `7d` or `2w`
+        #[clap(long, group = "policy")]
+        older_than: Option<String>,
+    },
 }
 
 #[derive(ValueEnum, Clone, Copy, Debug)]
@@ -70,6 +457,76 @@ pub enum LogLevel {
     Trace,
 }
 
+#[derive(ValueEnum, Eq, PartialEq, Clone, Copy, Debug)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum JvmPreset {
+    /// Aikar's G1GC flags
+    Aikar,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ContainerPack {
+    /// A CurseForge pack
+    Flame,
+    Ftb,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ServiceRestart {
+    Always,
+    OnFailure,
+    No,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum BackupFormat {
+    /// Copy the files into a directory
+    Dir,
+    /// Compress the files into a zip archive
+    Zip,
+    /// Store each unique file once, shared between backups
+    Dedup,
+}
+
+impl BackupFormat {
+    pub fn unless(self, no_backup: bool) -> Option<backup::BackupFormat> {
+        (!no_backup).then_some(self.into())
+    }
+}
+
+impl From<BackupFormat> for backup::BackupFormat {
+    fn from(value: BackupFormat) -> Self {
+        match value {
+            BackupFormat::Dir => backup::BackupFormat::Dir,
+            BackupFormat::Zip => backup::BackupFormat::Zip,
+            BackupFormat::Dedup => backup::BackupFormat::Dedup,
+        }
+    }
+}
+
+impl From<JvmPreset> for jvm::JvmPreset {
+    fn from(value: JvmPreset) -> Self {
+        match value {
+            JvmPreset::Aikar => jvm::JvmPreset::Aikar,
+        }
+    }
+}
+
+impl From<ServiceRestart> for Restart {
+    fn from(value: ServiceRestart) -> Self {
+        match value {
+            ServiceRestart::Always => Restart::Always,
+            ServiceRestart::OnFailure => Restart::OnFailure,
+            ServiceRestart::No => Restart::No,
+        }
+    }
+}
+
 impl From<LogLevel> for LevelFilter {
     fn from(value: LogLevel) -> Self {
         match value {
@@ -82,24 +539,3 @@ impl From<LogLevel> for LevelFilter {
         }
     }
 }
-
-pub fn download_progress_style() -> ProgressStyle {
-    ProgressStyle::with_template("[File: {msg}]\n{bar:40.cyan/blue} {percent}% [{bytes} / {total_bytes}] [Eta: {eta}]").unwrap()
-}
-
-pub fn copy_progress_style() -> ProgressStyle {
-    ProgressStyle::with_template("{prefix.bold.dim} {spinner} {msg} {elapsed}")
-        .unwrap()
-        .tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ ")
-}
-
-pub fn backup_progress_style() -> ProgressStyle {
-    ProgressStyle::with_template("{bar:40.cyan/blue} [Eta: {eta}]\n[{pos}/{len}] {wide_msg}")
-        .unwrap()
-}
-
-pub fn spinner_progress_style() -> ProgressStyle {
-    ProgressStyle::with_template("{spinner} {prefix} {elapsed}\n{wide_msg}")
-        .unwrap()
-        .tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ ")
-}
\ No newline at end of file
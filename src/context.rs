@@ -0,0 +1,154 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use log::{debug, info};
+use reqwest::Client;
+use crate::cache::DownloadCache;
+use crate::events::{Event, Events, Outcome};
+use crate::fs_utils::mcsi_dir;
+use crate::http::{Endpoints, Http, HttpConfig};
+use crate::lock::LockRecorder;
+use crate::plan::{Plan, Planner};
+use crate::progress::{IndicatifReporter, Progress, Reporter, Task};
+
+// Gives every context of the process its own staging dir
+static CONTEXT_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// What every command shares besides its arguments, passed to every call of the library.
+pub struct Context {
+    http: Http,
+    reporter: Box<dyn Reporter>,
+    cache: Option<DownloadCache>,
+    staging_dir: PathBuf,
+    pub(crate) plan: Planner,
+    pub(crate) events: Events,
+    pub(crate) lock: LockRecorder,
+}
+
+pub struct ContextBuilder {
+    http: HttpConfig,
+    reporter: Box<dyn Reporter>,
+    cache: Option<DownloadCache>,
+    staging_dir: Option<PathBuf>,
+    dry_run: bool,
+    events: Events,
+}
+
+impl Context {
+    pub fn builder() -> ContextBuilder {
+        ContextBuilder {
+            http: HttpConfig::default(),
+            reporter: Box::new(IndicatifReporter),
+            // Tests always cache downloads so they don't hit the network more than once
+            #[cfg(test)]
+            cache: Some(DownloadCache::new(std::path::PathBuf::from(".mcsi-test-dir").join("cache"), None)),
+            #[cfg(not(test))]
+            cache: None,
+            staging_dir: None,
+            dry_run: false,
+            events: Events::default(),
+        }
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.http.client
+    }
+
+    pub fn endpoints(&self) -> &Endpoints {
+        &self.http.endpoints
+    }
+
+    pub fn cache(&self) -> Option<&DownloadCache> {
+        self.cache.as_ref()
+    }
+
+    // Where packs are downloaded and staged, nothing else uses it while this context is alive.
+    pub fn staging_dir(&self) -> &Path {
+        &self.staging_dir
+    }
+
+    pub fn work_dir(&self) -> PathBuf {
+        self.staging_dir.join("work_dir")
+    }
+
+    pub fn progress(&self, task: Task) -> Box<dyn Progress> {
+        self.reporter.start(task)
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.plan.is_dry_run()
+    }
+
+    pub fn record_outcome(&self, f: impl FnOnce(&mut Outcome)) {
+        self.events.record_outcome(f);
+    }
+
+    // Sends the final result of the command, returns what a dry run would have done.
+    pub fn finish(&self, result: &color_eyre::Result<()>) -> Option<Plan> {
+        let plan = self.is_dry_run().then(|| self.plan.take());
+        self.events.finish(result, plan.clone());
+
+        plan
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        if self.staging_dir.is_dir() {
+            let _ = std::fs::remove_dir_all(&self.staging_dir);
+        }
+    }
+}
+
+impl ContextBuilder {
+    pub fn with_http(mut self, config: HttpConfig) -> Self {
+        self.http = config;
+        self
+    }
+
+    pub fn with_reporter(mut self, reporter: impl Reporter + 'static) -> Self {
+        self.reporter = Box::new(reporter);
+        self
+    }
+
+    pub fn with_cache(mut self, cache: Option<DownloadCache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    // Defaults to a new dir in `.mcsi/staging`, it is removed along with the context.
+    pub fn with_staging_dir<T: AsRef<Path>>(mut self, dir: T) -> Self {
+        self.staging_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    // Nothing is downloaded, run or changed, everything that would have been is collected into a `Plan`.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn with_listener(mut self, listener: impl Fn(&Event) + Send + Sync + 'static) -> Self {
+        self.events.subscribe(listener);
+        self
+    }
+
+    pub fn finish(self) -> color_eyre::Result<Context> {
+        debug!("{:?}", self.http);
+        if let Some(cache) = &self.cache {
+            info!("Using download cache at {:?}", cache.dir());
+        }
+
+        Ok(Context {
+            http: Http::new(self.http)?,
+            reporter: self.reporter,
+            cache: self.cache,
+            staging_dir: self.staging_dir.unwrap_or_else(|| {
+                let id = CONTEXT_COUNTER.fetch_add(1, Ordering::Relaxed);
+                mcsi_dir().join("staging").join(format!("{}-{id}", std::process::id()))
+            }),
+            plan: Planner::new(self.dry_run),
+            events: self.events,
+            lock: LockRecorder::default(),
+        })
+    }
+}
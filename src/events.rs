@@ -0,0 +1,283 @@
+use std::future::Future;
+use std::io::Write;
+use std::sync::{mpsc, Mutex};
+use color_eyre::Report;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
+use simplelog::{Config, SharedLogger};
+use crate::cache::CacheError;
+use crate::fs_utils::{DownloadError, FsError};
+use crate::lock::LockError;
+use crate::modloader::{ModLoader, ModLoaderError, ModLoaderParseError};
+use crate::modpack::{ManifestError, PackSource};
+use crate::modpack::backup::{BackupError, BackupInfo};
+use crate::modpack::bundle::BundleError;
+use crate::modpack::config::ConfigError;
+use crate::modpack::flame::FlameError;
+use crate::modpack::jvm::JvmError;
+use crate::modpack::players::PlayerError;
+use crate::modpack::server_files::ServerFilesError;
+use crate::modpack::service::ServiceError;
+use crate::modpack::ftb::FtbError;
+use crate::modpack::server_pack::ServerPack;
+use crate::modpack::verify::VerifyError;
+use crate::plan::Plan;
+use crate::version::McVersionParseError;
+
+type Listener = Box<dyn Fn(&Event) + Send + Sync>;
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    StageStarted {
+        stage: Stage,
+    },
+    StageFinished {
+        stage: Stage,
+    },
+    DownloadProgress {
+        file: String,
+        downloaded: u64,
+        total: Option<u64>,
+    },
+    Warning {
+        message: String,
+    },
+    Result {
+        success: bool,
+        #[serde(flatten)]
+        outcome: Box<Outcome>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        plan: Option<Plan>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<ErrorInfo>,
+    },
+}
+
+#[derive(Serialize, Eq, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Resolve,
+    Download,
+    InstallLoader,
+    RunInstaller,
+    RemoveOld,
+    Install,
+    Verify,
+}
+
+#[derive(Serialize, Default, Clone, Debug)]
+pub struct Outcome {
+    pub source: Option<PackSource>,
+    pub mod_loader: Option<ModLoader>,
+    pub target_dir: Option<String>,
+    pub server_pack: Option<ServerPack>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backups: Option<Vec<BackupInfo>>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ErrorInfo {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+// Stable identifier of what went wrong, for callers that can't parse error messages.
+#[derive(Serialize, Eq, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    Network,
+    Download,
+    HashMismatch,
+    ManifestNotFound,
+    Lock,
+    Config,
+    Unhealthy,
+    Backup,
+    Bundle,
+    Cache,
+    Pack,
+    ModLoader,
+    Service,
+    Io,
+    Unknown,
+}
+
+impl ErrorCode {
+    pub fn of(report: &Report) -> Self {
+        report.chain()
+            .find_map(|err| {
+                if let Some(err) = err.downcast_ref::<DownloadError>() {
+                    return Some(match err {
+                        DownloadError::HashMismatch { .. } => ErrorCode::HashMismatch,
+                        _ => ErrorCode::Download,
+                    });
+                }
+
+                let code = if err.is::<reqwest::Error>() {
+                    ErrorCode::Network
+                } else if err.is::<ManifestError>() {
+                    ErrorCode::ManifestNotFound
+                } else if err.is::<LockError>() {
+                    ErrorCode::Lock
+                } else if err.is::<ConfigError>() || err.is::<toml::de::Error>() || err.is::<JvmError>() || err.is::<ServerFilesError>() || err.is::<PlayerError>() {
+                    ErrorCode::Config
+                } else if err.is::<VerifyError>() {
+                    ErrorCode::Unhealthy
+                } else if err.is::<BackupError>() {
+                    ErrorCode::Backup
+                } else if err.is::<BundleError>() {
+                    ErrorCode::Bundle
+                } else if err.is::<CacheError>() {
+                    ErrorCode::Cache
+                } else if err.is::<FlameError>() || err.is::<FtbError>() || err.is::<McVersionParseError>() {
+                    ErrorCode::Pack
+                } else if err.is::<ModLoaderError>() || err.is::<ModLoaderParseError>() {
+                    ErrorCode::ModLoader
+                } else if err.is::<ServiceError>() {
+                    ErrorCode::Service
+                } else if err.is::<std::io::Error>() || err.is::<FsError>() {
+                    ErrorCode::Io
+                } else {
+                    return None;
+                };
+
+                Some(code)
+            })
+            .unwrap_or(ErrorCode::Unknown)
+    }
+}
+
+// The listeners of a `Context` and the outcome they are sent at the end.
+#[derive(Default)]
+pub(crate) struct Events {
+    listeners: Vec<Listener>,
+    outcome: Mutex<Outcome>,
+}
+
+impl Events {
+    pub(crate) fn subscribe(&mut self, listener: impl Fn(&Event) + Send + Sync + 'static) {
+        self.listeners.push(Box::new(listener));
+    }
+
+    pub(crate) fn has_listeners(&self) -> bool {
+        !self.listeners.is_empty()
+    }
+
+    pub(crate) fn emit(&self, event: Event) {
+        for listener in &self.listeners {
+            listener(&event);
+        }
+    }
+
+    // Wraps `stage` in started and finished events, finished is only sent when it succeeds.
+    pub(crate) async fn stage<T>(&self, stage: Stage, fut: impl Future<Output = color_eyre::Result<T>>) -> color_eyre::Result<T> {
+        self.emit(Event::StageStarted { stage });
+        let result = fut.await;
+        if result.is_ok() {
+            self.emit(Event::StageFinished { stage });
+        }
+
+        result
+    }
+
+    pub(crate) fn record_outcome(&self, f: impl FnOnce(&mut Outcome)) {
+        f(&mut self.outcome.lock().unwrap());
+    }
+
+    pub(crate) fn finish(&self, result: &color_eyre::Result<()>, plan: Option<Plan>) {
+        let outcome = std::mem::take(&mut *self.outcome.lock().unwrap());
+        let error = result.as_ref().err().map(|report| ErrorInfo {
+            code: ErrorCode::of(report),
+            message: format!("{report:#}"),
+        });
+
+        self.emit(Event::Result {
+            success: result.is_ok(),
+            outcome: Box::new(outcome),
+            plan,
+            error,
+        });
+    }
+}
+
+pub fn channel() -> (impl Fn(&Event) + Send + Sync + 'static, mpsc::Receiver<Event>) {
+    let (sender, receiver) = mpsc::channel();
+    let listener = move |event: &Event| {
+        let _ = sender.send(event.clone());
+    };
+
+    (listener, receiver)
+}
+
+pub fn print_json(event: &Event) {
+    let mut stdout = std::io::stdout().lock();
+    if serde_json::to_writer(&mut stdout, event).is_ok() {
+        let _ = writeln!(stdout);
+        let _ = stdout.flush();
+    }
+}
+
+// Prints warnings and errors that are logged as json events.
+pub struct EventLogger;
+
+impl Log for EventLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Warn
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            print_json(&Event::Warning {
+                message: record.args().to_string(),
+            });
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+impl SharedLogger for EventLogger {
+    fn level(&self) -> LevelFilter {
+        LevelFilter::Warn
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        Box::new(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::Report;
+    use crate::events::{Event, ErrorCode, Stage};
+    use crate::fs_utils::DownloadError;
+    use crate::lock::LockError;
+
+    #[test]
+    fn error_codes_follow_the_error_chain() {
+        let hash = Report::new(DownloadError::HashMismatch {
+            url: String::from("https://example.com/a.jar"),
+            expected: String::from("a"),
+            actual: String::from("b"),
+        });
+        assert_eq!(ErrorCode::of(&hash), ErrorCode::HashMismatch);
+
+        let lock = Report::new(LockError::LockNotFound)
+            .wrap_err("Failed to install");
+        assert_eq!(ErrorCode::of(&lock), ErrorCode::Lock);
+
+        assert_eq!(ErrorCode::of(&Report::msg("something else")), ErrorCode::Unknown);
+    }
+
+    #[test]
+    fn events_serialize_as_tagged_json() {
+        let event = Event::StageStarted { stage: Stage::InstallLoader };
+
+        assert_eq!(serde_json::to_string(&event).unwrap(), r#"{"event":"stage_started","stage":"install_loader"}"#);
+    }
+}
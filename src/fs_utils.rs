@@ -1,23 +1,30 @@
 use std::collections::HashMap;
-use std::fs;
 use std::fs::File;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::time::{Duration, Instant};
 use chrono::Utc;
 use futures_util::StreamExt;
-use indicatif::ProgressBar;
+use indicatif::HumanBytes;
 use log::{info, warn};
-use regex::Regex;
+use reqwest::StatusCode;
+use reqwest::header::{HeaderMap, CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE, RETRY_AFTER};
+use sha1::{Digest, Sha1};
 use thiserror::Error;
-use tokio::fs::{create_dir_all, read, remove_dir, remove_file, write};
+use tokio::fs::{create_dir_all, read, remove_dir, remove_file, write, OpenOptions};
 use tokio::io::AsyncWriteExt;
 use walkdir::WalkDir;
-use crate::cli;
-
-pub async fn recursive_copy_to_dir<TSrc: AsRef<Path>, TDst: AsRef<Path>>(src_dir: TSrc, dst_dir: TDst) -> color_eyre::Result<()> {
+use zip::{CompressionMethod, ZipWriter};
+use zip::write::SimpleFileOptions;
+use crate::cache::CacheKey;
+use crate::context::Context;
+use crate::events::Event;
+use crate::progress::{Progress, Task};
+
+pub async fn recursive_copy_to_dir<TSrc: AsRef<Path>, TDst: AsRef<Path>>(ctx: &Context, src_dir: TSrc, dst_dir: TDst) -> color_eyre::Result<()> {
     let src_dir = src_dir.as_ref();
-    let dst_dir = dst_dir.as_ref().to_str().unwrap();
+    let dst_dir = dst_dir.as_ref();
 
     // Log and return if we try to copy from an invalid directory
     if !src_dir.is_dir() {
@@ -26,36 +33,28 @@ pub async fn recursive_copy_to_dir<TSrc: AsRef<Path>, TDst: AsRef<Path>>(src_dir
     }
 
     info!("Copying files...");
-    let copy_bar = ProgressBar::new_spinner()
-        .with_style(cli::copy_progress_style());
+    let copy_progress = ctx.progress(Task::Copy);
 
     let root = src_dir.canonicalize()?;
-    let mut count = 0;
     for entry in WalkDir::new(src_dir) {
         let entry = entry?;
         if entry.path().is_dir() {
             continue;
         }
         let relative = entry.path().canonicalize()?;
-        let mut relative = relative.to_str().unwrap().replace(root.to_str().unwrap(), "");
-        if relative.starts_with('/') || relative.starts_with('\\') {
-            relative.remove(0);
-        }
-        let relative = PathBuf::from(relative);
+        let relative = relative.strip_prefix(&root)?;
 
-        let dst = PathBuf::from(dst_dir)
-            .join(&relative);
+        let dst = dst_dir
+            .join(relative);
 
-        if let Some(parent) = dst.parent() {
-            if !parent.exists() {
-                create_dir_all(parent)
-                    .await?;
-            }
+        if let Some(parent) = dst.parent()
+            && !parent.exists() {
+            create_dir_all(parent)
+                .await?;
         }
 
-        count += 1;
-        copy_bar.set_prefix(format!("[{}/?]", count));
-        copy_bar.set_message(String::from(relative.to_str().unwrap()));
+        copy_progress.inc(1);
+        copy_progress.set_message(relative.to_string_lossy().to_string());
 
         if dst.is_file() {
             std::fs::remove_file(&dst)?;
@@ -67,12 +66,13 @@ pub async fn recursive_copy_to_dir<TSrc: AsRef<Path>, TDst: AsRef<Path>>(src_dir
         dst_file.write_all(&bytes)?;
     }
 
-    copy_bar.finish();
+    copy_progress.finish();
 
     Ok(())
 }
 
 pub async fn backup_and_remove_files<TSrc: AsRef<Path>, TDst: AsRef<Path>>(
+    ctx: &Context,
     src_dir: TSrc,
     backup_dir: TDst,
     rel_files: Vec<String>,
@@ -86,8 +86,7 @@ pub async fn backup_and_remove_files<TSrc: AsRef<Path>, TDst: AsRef<Path>>(
     }
 
     info!("Starting backup of files...");
-    let backup_bar = ProgressBar::new(rel_files.len() as u64)
-        .with_style(cli::backup_progress_style());
+    let backup_progress = ctx.progress(Task::Backup { files: rel_files.len() as u64 });
 
     for rel_file in rel_files {
         let src_file = PathBuf::from(&src_dir)
@@ -96,11 +95,11 @@ pub async fn backup_and_remove_files<TSrc: AsRef<Path>, TDst: AsRef<Path>>(
             .join(&rel_file);
 
         if !src_file.is_file() {
-            warn!("File {} doesn't exist!", &src_file.to_str().unwrap());
+            warn!("File {} doesn't exist!", src_file.display());
             continue;
         }
 
-        backup_bar.set_message(rel_file);
+        backup_progress.set_message(rel_file);
 
         ensure_parent(&dst_file)
             .await?;
@@ -110,154 +109,446 @@ pub async fn backup_and_remove_files<TSrc: AsRef<Path>, TDst: AsRef<Path>>(
         write(dst_file, bytes)
             .await?;
 
-        if let Some(parent_dir) = src_file.parent() {
-            let src_parent_dir = parent_dir.to_path_buf();
+        remove_file_and_empty_parents(src_dir, &src_file)
+            .await?;
 
-            remove_file(&src_file)
-                .await?;
+        backup_progress.inc(1);
+    }
 
-            let children = WalkDir::new(&src_parent_dir)
-                .max_depth(1)
-                .into_iter()
-                .count();
+    backup_progress.finish();
 
-            if children == 0 {
-                remove_dir(src_parent_dir)
-                    .await?;
-            }
-        } else {
-            remove_file(&src_file)
-                .await?;
+    Ok(())
+}
+
+pub async fn archive_and_remove_files<TSrc: AsRef<Path>, TDst: AsRef<Path>>(
+    ctx: &Context,
+    src_dir: TSrc,
+    archive: TDst,
+    rel_files: Vec<String>,
+) -> color_eyre::Result<()> {
+    let src_dir = src_dir.as_ref();
+
+    info!("Starting backup of files...");
+    let backup_progress = ctx.progress(Task::Backup { files: rel_files.len() as u64 });
+
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated);
+    let mut zip = ZipWriter::new(File::create(archive)?);
+    let mut archived = Vec::new();
+
+    for rel_file in rel_files {
+        let src_file = PathBuf::from(&src_dir)
+            .join(&rel_file);
+
+        if !src_file.is_file() {
+            warn!("File {} doesn't exist!", src_file.display());
+            continue;
         }
 
-        backup_bar.inc(1);
+        backup_progress.set_message(rel_file.clone());
+
+        zip.start_file(rel_file.replace('\\', "/"), options)?;
+        std::io::copy(&mut File::open(&src_file)?, &mut zip)?;
+
+        archived.push(src_file);
+        backup_progress.inc(1);
     }
 
-    backup_bar.finish();
+    // Only remove anything once the archive is complete
+    zip.finish()?;
+    backup_progress.finish();
+
+    for src_file in archived {
+        remove_file_and_empty_parents(src_dir, &src_file)
+            .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn remove_files<T: AsRef<Path>>(
+    ctx: &Context,
+    src_dir: T,
+    rel_files: Vec<String>,
+) -> color_eyre::Result<()> {
+    let src_dir = src_dir.as_ref();
+
+    info!("Removing files...");
+    let remove_progress = ctx.progress(Task::Remove { files: rel_files.len() as u64 });
+
+    for rel_file in rel_files {
+        let src_file = PathBuf::from(&src_dir)
+            .join(&rel_file);
+
+        if !src_file.is_file() {
+            warn!("File {} doesn't exist!", src_file.display());
+            continue;
+        }
+
+        remove_progress.set_message(rel_file);
+
+        remove_file_and_empty_parents(src_dir, &src_file)
+            .await?;
+
+        remove_progress.inc(1);
+    }
+
+    remove_progress.finish();
+
+    Ok(())
+}
+
+// Removes the file along with any parent dirs left empty, stopping at `root`.
+async fn remove_file_and_empty_parents(root: &Path, file: &Path) -> color_eyre::Result<()> {
+    remove_file(file)
+        .await?;
+
+    let mut parent_dir = file.parent();
+    while let Some(dir) = parent_dir {
+        if dir == root || std::fs::read_dir(dir)?.next().is_some() {
+            break;
+        }
+
+        remove_dir(dir)
+            .await?;
+        parent_dir = dir.parent();
+    }
 
     Ok(())
 }
 
+pub fn dir_size<T: AsRef<Path>>(dir: T) -> color_eyre::Result<u64> {
+    let mut size = 0;
+    for entry in WalkDir::new(dir) {
+        let entry = entry?;
+        if entry.path().is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+
+    Ok(size)
+}
+
 pub fn file_path_relative_to<TFile: AsRef<Path>, TDir: AsRef<Path>>(file: TFile, dir: TDir) -> color_eyre::Result<PathBuf> {
     let file = file.as_ref();
     let dir = dir.as_ref();
 
-    let relative = file.canonicalize()?;
-    let mut relative = relative.to_str().unwrap().replace(dir.canonicalize()?.to_str().unwrap(), "");
-    if relative.starts_with('/') || relative.starts_with('\\') {
-        relative.remove(0);
+    let file = file.canonicalize()?;
+    let dir = dir.canonicalize()?;
+    let relative = file.strip_prefix(&dir)
+        .map_err(|_| FsError::NotInDir(file.clone(), dir.clone()))?;
+
+    Ok(relative.to_path_buf())
+}
+
+pub fn sha1_file<T: AsRef<Path>>(file: T) -> color_eyre::Result<String> {
+    let mut file = File::open(file)?;
+    let mut hasher = Sha1::new();
+    std::io::copy(&mut file, &mut hasher)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+const DOWNLOAD_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+pub async fn download_file<T: AsRef<Path>>(ctx: &Context, url: &str, dst: T) -> color_eyre::Result<PathBuf> {
+    download_file_with_hash(ctx, url, dst, None)
+        .await
+}
+
+// Downloads the file, checking it against the sha1 published by the source if there is one.
+pub async fn download_file_with_hash<T: AsRef<Path>>(ctx: &Context, url: &str, dst: T, sha1: Option<&str>) -> color_eyre::Result<PathBuf> {
+    if ctx.is_dry_run() {
+        ctx.lock.locked_sha1(url)?;
+        ctx.plan.download(url, dst.as_ref(), remote_size(ctx, url).await);
+
+        return Ok(dst.as_ref().to_path_buf());
     }
-    let relative = PathBuf::from(relative);
-    
-    Ok(relative)
+
+    resolve_file_with_hash(ctx, url, dst, sha1)
+        .await
 }
 
-#[cfg(not(test))]
-pub async fn download_file<T: AsRef<Path>>(url: &str, dst: T) -> color_eyre::Result<PathBuf> {
-    let file_name = dst.as_ref().file_name().unwrap().to_str().unwrap().to_string();
+// Downloads even during a dry run, for files that are needed to resolve the rest of the install.
+pub async fn resolve_file_with_hash<T: AsRef<Path>>(ctx: &Context, url: &str, dst: T, sha1: Option<&str>) -> color_eyre::Result<PathBuf> {
     let file_path = dst.as_ref();
+    // An enforced lock decides which hash the file must have
+    let locked_sha1 = ctx.lock.locked_sha1(url)?;
+    let sha1 = locked_sha1.as_deref().or(sha1);
+    let key = match sha1 {
+        Some(sha1) => CacheKey::Sha1(sha1),
+        None => CacheKey::Url(url),
+    };
+
+    if let Some(cache) = ctx.cache()
+        && cache.fetch(key, file_path).await? {
+        info!("Using cached {0}", file_path.display());
+        ctx.lock.record_download(url, sha1_file(file_path)?);
+        return Ok(file_path.to_path_buf());
+    }
 
-    if file_path.is_file() {
-        std::fs::remove_file(file_path)?;
+    fetch_file(ctx, url, file_path)
+        .await?;
+
+    if let Some(expected) = sha1 {
+        let actual = sha1_file(file_path)?;
+        if !actual.eq_ignore_ascii_case(expected) {
+            std::fs::remove_file(file_path)?;
+            return Err(DownloadError::HashMismatch {
+                url: url.to_string(),
+                expected: expected.to_string(),
+                actual,
+            })?;
+        }
     }
 
-    let mut file = tokio::fs::File::create(&file_path).await?;
+    if let Some(cache) = ctx.cache() {
+        cache.store(key, file_path)
+            .await?;
+    }
+
+    ctx.lock.record_download(url, sha1_file(file_path)?);
+
+    Ok(file_path.to_path_buf())
+}
+
+async fn remote_size(ctx: &Context, url: &str) -> Option<u64> {
+    let resp = ctx.client().head(url)
+        .send()
+        .await
+        .ok()?;
+
+    resp.headers()
+        .get(CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+async fn fetch_file<T: AsRef<Path>>(ctx: &Context, url: &str, dst: T) -> color_eyre::Result<PathBuf> {
+    let file_path = dst.as_ref();
+    let file_name = file_path.file_name()
+        .ok_or_else(|| FsError::NoFileName(file_path.to_path_buf()))?
+        .to_string_lossy()
+        .to_string();
+    let part_path = part_file_path(file_path);
+    let validator_path = validator_file_path(&part_path);
+
     info!("Downloading {0}...", &file_name);
 
-    let mut retries_left = 5;
+    let download_progress = ctx.progress(Task::Download { file: file_name.clone() });
 
-    let mut result = reqwest::get(url)
-        .await;
+    let mut attempt = 1;
+    while let Err(err) = download_to_part(ctx, url, &part_path, &validator_path, &file_name, download_progress.as_ref()).await {
+        let retry_after = match err.downcast_ref::<DownloadError>() {
+            Some(download_err) if !download_err.is_retryable() => return Err(err),
+            Some(DownloadError::Status { retry_after, .. }) => *retry_after,
+            _ => None,
+        };
 
-    while let Err(err) = result  {
-        retries_left -= 1;
-        if retries_left <= 0 {
-            return Err(err.into());
+        if attempt >= DOWNLOAD_ATTEMPTS {
+            return Err(err);
         }
-        
-        info!("Download failed: {0}\n {1} attempts left", &err, retries_left);
-        
-        result = reqwest::get(url)
+
+        let backoff = retry_after.unwrap_or(INITIAL_BACKOFF * 2u32.pow(attempt - 1));
+        warn!("Download failed: {0}\n Retrying in {1}s, {2} attempts left", &err, backoff.as_secs(), DOWNLOAD_ATTEMPTS - attempt);
+        tokio::time::sleep(backoff)
             .await;
+
+        attempt += 1;
     }
-    
-    let resp = result?;
 
-    let total_bytes = resp.content_length().unwrap();
-    let download_bar = ProgressBar::new(total_bytes)
-        .with_style(cli::download_progress_style())
-        .with_message(file_name.clone());
+    download_progress.finish();
 
-    let mut stream = resp.bytes_stream();
-    while let Some(chunk_result) = stream.next().await {
-        let chunk = chunk_result?;
-        let chunk_len = chunk.len() as u64;
+    // Only replace the destination once the download is complete
+    if file_path.is_file() {
+        std::fs::remove_file(file_path)?;
+    }
+    tokio::fs::rename(&part_path, file_path)
+        .await?;
+    if validator_path.is_file() {
+        std::fs::remove_file(validator_path)?;
+    }
 
-        file.write_all(&chunk).await?;
+    Ok(file_path.to_path_buf())
+}
 
-        download_bar.inc(chunk_len);
-    }
+// Reads a `Retry-After` header given in seconds, capped at `MAX_RETRY_AFTER`.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers.get(RETRY_AFTER)?
+        .to_str().ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+        .map(|retry_after| retry_after.min(MAX_RETRY_AFTER))
+}
 
-    file.flush().await?;
+// What identifies the version of the remote file, a strong `ETag` or else `Last-Modified`.
+fn validator(headers: &HeaderMap) -> Option<String> {
+    let etag = headers.get(ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .filter(|etag| !etag.starts_with("W/"));
 
-    download_bar.finish();
+    etag.or_else(|| headers.get(LAST_MODIFIED)?.to_str().ok())
+        .map(str::to_string)
+}
 
-    Ok(file_path.to_path_buf())
+// Whether a `206` response continues the part file instead of starting somewhere else.
+fn continues_at(headers: &HeaderMap, offset: u64) -> bool {
+    headers.get(CONTENT_RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| range.strip_prefix("bytes "))
+        .and_then(|range| range.split_once('-'))
+        .is_some_and(|(start, _)| start.trim().parse() == Ok(offset))
 }
 
-#[cfg(test)]
-pub async fn download_file<T: AsRef<Path>>(url: &str, dst: T) -> color_eyre::Result<PathBuf> {
-    use base64::Engine;
+fn part_file_path(file_path: &Path) -> PathBuf {
+    let mut file_name = file_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".part");
 
-    let file_name = dst.as_ref().file_name().unwrap().to_str().unwrap().to_string();
-    let file_path = dst.as_ref();
+    file_path.with_file_name(file_name)
+}
 
-    if file_path.is_file() {
-        std::fs::remove_file(file_path)?;
+fn validator_file_path(part_path: &Path) -> PathBuf {
+    let mut file_name = part_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".validator");
+
+    part_path.with_file_name(file_name)
+}
+
+// Downloads into the `.part` file, resuming from wherever a previous attempt stopped if the server supports it.
+// A part file is only resumed with `If-Range`, so the server sends the whole file again if it changed since.
+async fn download_to_part(
+    ctx: &Context,
+    url: &str,
+    part_path: &Path,
+    validator_path: &Path,
+    file_name: &str,
+    download_progress: &dyn Progress,
+) -> color_eyre::Result<()> {
+    let part_validator = tokio::fs::read_to_string(validator_path)
+        .await
+        .ok();
+    let offset = match (tokio::fs::metadata(part_path).await, &part_validator) {
+        (Ok(metadata), Some(_)) => metadata.len(),
+        _ => 0,
+    };
+
+    let mut request = ctx.client().get(url);
+    if let Some(part_validator) = part_validator.as_deref().filter(|_| offset > 0) {
+        request = request.header(RANGE, format!("bytes={offset}-"))
+            .header(IF_RANGE, part_validator);
     }
 
-    info!("Downloading {0}...", &file_name);
+    let resp = request.send()
+        .await?;
 
-    let cache_loc = PathBuf::from(".mcsi-test-dir")
-        .join("cache");
+    if offset > 0 && resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        // The part file doesn't line up with the remote file anymore, start over on the next attempt
+        remove_part(part_path, validator_path)
+            .await?;
+        return Err(DownloadError::RangeNotSatisfiable(url.to_string()))?;
+    }
 
-    if !cache_loc.is_dir() {
-        std::fs::create_dir_all(&cache_loc)?;
+    if !resp.status().is_success() {
+        return Err(DownloadError::Status {
+            url: url.to_string(),
+            status: resp.status(),
+            retry_after: retry_after(resp.headers()),
+        })?;
     }
 
-    let cached_file = cache_loc.join(base64::engine::general_purpose::STANDARD_NO_PAD.encode(&url));
-    if cached_file.is_file() {
-        std::fs::copy(&cached_file, &file_path)?;
-        return Ok(file_path.to_path_buf());
+    let remote_validator = validator(resp.headers());
+    let resumed = offset > 0 && resp.status() == StatusCode::PARTIAL_CONTENT;
+    if resumed && (!continues_at(resp.headers(), offset) || remote_validator.as_ref().is_some_and(|remote| Some(remote) != part_validator.as_ref())) {
+        remove_part(part_path, validator_path)
+            .await?;
+        return Err(DownloadError::ResumeMismatch(url.to_string()))?;
     }
 
-    let mut file = tokio::fs::File::create(&file_path).await?;
+    let mut file = if resumed {
+        info!("Resuming download at {}", HumanBytes(offset));
+        OpenOptions::new()
+            .append(true)
+            .open(part_path)
+            .await?
+    } else {
+        if offset > 0 {
+            info!("{file_name} changed since the download started, starting over");
+        }
 
-    let resp = reqwest::get(url)
-        .await?;
+        // Without a validator a later attempt can't tell whether the part file is still current
+        match &remote_validator {
+            Some(remote_validator) => write(validator_path, remote_validator).await?,
+            None if validator_path.is_file() => remove_file(validator_path).await?,
+            None => {}
+        }
+
+        tokio::fs::File::create(part_path)
+            .await?
+    };
 
-    let total_bytes = resp.content_length().unwrap();
-    let download_bar = ProgressBar::new(total_bytes)
-        .with_style(cli::download_progress_style())
-        .with_message(file_name.clone());
+    let start = if resumed { offset } else { 0 };
+    let total = resp.content_length()
+        .map(|len| start + len);
+    download_progress.set_length(total);
+    download_progress.set_position(start);
 
+    let mut downloaded = start;
+    let mut last_progress = Instant::now();
     let mut stream = resp.bytes_stream();
     while let Some(chunk_result) = stream.next().await {
-        let chunk = chunk_result?;
+        let chunk = match chunk_result {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                // Keep what we have so far for the next attempt
+                file.flush().await?;
+                return Err(err.into());
+            }
+        };
         let chunk_len = chunk.len() as u64;
 
         file.write_all(&chunk).await?;
 
-        download_bar.inc(chunk_len);
+        download_progress.inc(chunk_len);
+        downloaded += chunk_len;
+        if ctx.events.has_listeners() && last_progress.elapsed() >= PROGRESS_INTERVAL {
+            emit_progress(ctx, file_name, downloaded, total);
+            last_progress = Instant::now();
+        }
+    }
+
+    if ctx.events.has_listeners() {
+        emit_progress(ctx, file_name, downloaded, total);
     }
 
     file.flush().await?;
 
-    download_bar.finish();
+    Ok(())
+}
 
-    std::fs::copy(&file_path, &cached_file)?;
+async fn remove_part(part_path: &Path, validator_path: &Path) -> color_eyre::Result<()> {
+    for path in [part_path, validator_path] {
+        if path.is_file() {
+            remove_file(path)
+                .await?;
+        }
+    }
 
-    Ok(file_path.to_path_buf())
+    Ok(())
+}
+
+fn emit_progress(ctx: &Context, file_name: &str, downloaded: u64, total: Option<u64>) {
+    ctx.events.emit(Event::DownloadProgress {
+        file: file_name.to_string(),
+        downloaded,
+        total,
+    });
 }
 
 struct DirDepthEntry {
@@ -266,7 +557,7 @@ struct DirDepthEntry {
     path: PathBuf,
 }
 
-pub async fn get_closest_common_parent<T: AsRef<Path>>(dir: T) -> color_eyre::Result<PathBuf> {
+pub fn get_closest_common_parent<T: AsRef<Path>>(dir: T) -> color_eyre::Result<PathBuf> {
     let dir = dir.as_ref();
     let mut items: Vec<DirDepthEntry> = Vec::new();
     let mut siblings: HashMap<usize, usize> = HashMap::new();
@@ -289,21 +580,33 @@ pub async fn get_closest_common_parent<T: AsRef<Path>>(dir: T) -> color_eyre::Re
             .or_insert(0);
     }
 
-    let common = items.iter_mut()
-        .map(|item| {
-            item.siblings = *siblings.get(&item.depth).unwrap();
-            item
-        })
+    for item in items.iter_mut() {
+        item.siblings = siblings.get(&item.depth).copied().unwrap_or(0);
+    }
+
+    let common = items.iter()
         .filter(|item| item.siblings != 0)
         .min_by_key(|item| item.depth)
-        .unwrap()
-        .path
-        .parent()
-        .unwrap();
+        .and_then(|item| item.path.parent());
+
+    match common {
+        Some(common) => Ok(common.to_path_buf()),
+        // No dir has siblings, the files are either at the top or wrapped in a chain of single dirs
+        None => innermost_single_dir(dir),
+    }
+}
 
-    let common_dir = PathBuf::from(common);
+fn innermost_single_dir(dir: &Path) -> color_eyre::Result<PathBuf> {
+    let mut dir = dir.to_path_buf();
+    loop {
+        let entries = std::fs::read_dir(&dir)?
+            .collect::<Result<Vec<_>, _>>()?;
 
-    Ok(common_dir)
+        match entries.as_slice() {
+            [entry] if entry.path().is_dir() => dir = entry.path(),
+            _ => return Ok(dir),
+        }
+    }
 }
 
 pub fn ensure_dir<T: AsRef<Path>>(dir: T) -> color_eyre::Result<()> {
@@ -319,11 +622,10 @@ pub fn ensure_dir<T: AsRef<Path>>(dir: T) -> color_eyre::Result<()> {
 pub async fn ensure_parent<T: AsRef<Path>>(path: T) -> color_eyre::Result<()> {
     let path = path.as_ref();
 
-    if let Some(parent) = path.parent() {
-        if !parent.is_dir() {
-            create_dir_all(parent)
-                .await?;
-        }
+    if let Some(parent) = path.parent()
+        && !parent.is_dir() {
+        create_dir_all(parent)
+            .await?;
     }
 
     Ok(())
@@ -333,10 +635,6 @@ pub fn mcsi_dir() -> PathBuf {
     PathBuf::from("./.mcsi")
 }
 
-pub fn work_dir() -> PathBuf {
-    mcsi_dir().join("work_dir")
-}
-
 pub fn logs_dir() -> PathBuf {
     mcsi_dir().join("logs")
 }
@@ -356,24 +654,6 @@ pub fn get_log_file() -> color_eyre::Result<File> {
     Ok(file)
 }
 
-pub fn get_server_start_script<T: AsRef<Path>>(dir: T) -> Option<PathBuf> {
-    let dir = dir.as_ref();
-    let re = Regex::new(r"(?:(?:S|start)|(?:R|run))?.*?(?:S|server)|(?:S|server)|(?:(?:S|start)|(?:R|run))\.sh$").unwrap();
-
-    for entry in WalkDir::new(dir).max_depth(1) {
-        let entry = entry.unwrap();
-        if !entry.path().is_dir() {
-            continue;
-        }
-
-        if re.is_match(entry.file_name().to_str().unwrap()) {
-            return Some(entry.path().to_path_buf());
-        }
-    }
-
-    None
-}
-
 #[cfg(target_os = "linux")]
 pub fn set_as_executable<T: AsRef<Path>>(file: T) -> color_eyre::Result<()> {
     let file = file.as_ref();
@@ -393,4 +673,195 @@ pub fn set_as_executable<T: AsRef<Path>>(file: T) -> color_eyre::Result<()> {
 pub enum FsError {
     #[error("`{0}` does not exist")]
     FileDoesntExist(PathBuf),
-}
\ No newline at end of file
+    #[error("`{0}` has no file name")]
+    NoFileName(PathBuf),
+    #[error("`{0}` is not inside `{1}`")]
+    NotInDir(PathBuf, PathBuf),
+}
+
+#[derive(Error, Clone, Debug)]
+pub enum DownloadError {
+    #[error("Server rejected resuming the download of `{0}`")]
+    RangeNotSatisfiable(String),
+    #[error("Server didn't continue the interrupted download of `{0}`")]
+    ResumeMismatch(String),
+    #[error("`{url}` has sha1 {actual} but {expected} was expected")]
+    HashMismatch {
+        url: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("Downloading `{url}` failed with status {status}")]
+    Status {
+        url: String,
+        status: StatusCode,
+        retry_after: Option<Duration>,
+    },
+}
+
+impl DownloadError {
+    // Whether trying again later could succeed, client errors other than 429 won't.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            DownloadError::RangeNotSatisfiable(_) | DownloadError::ResumeMismatch(_) => true,
+            DownloadError::HashMismatch { .. } => false,
+            DownloadError::Status { status, .. } => *status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use reqwest::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+    use reqwest::StatusCode;
+    use crate::context::Context;
+    use crate::fs_utils::{download_file, get_closest_common_parent, DownloadError, retry_after, MAX_RETRY_AFTER};
+    use crate::http::tests::stand_in_server;
+
+    fn download_dst(name: &str) -> PathBuf {
+        let dir = PathBuf::from("./.mcsi-test-dir")
+            .join("tests")
+            .join("download");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        dir.join(name)
+    }
+
+    #[tokio::test]
+    async fn fails_on_error_status_without_retrying() {
+        let (base_url, requests) = stand_in_server(vec![
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 9\r\nConnection: close\r\n\r\nNot Found",
+        ]);
+
+        let err = download_file(&Context::builder().finish().unwrap(), &format!("{base_url}/server.jar"), download_dst("missing.jar"))
+            .await
+            .unwrap_err();
+
+        match err.downcast_ref::<DownloadError>() {
+            Some(DownloadError::Status { status, .. }) => assert_eq!(*status, StatusCode::NOT_FOUND),
+            _ => panic!("unexpected error {err:?}"),
+        }
+        assert_eq!(requests.iter().count(), 1);
+        assert!(!download_dst("missing.jar").exists());
+    }
+
+    #[tokio::test]
+    async fn resumes_interrupted_download() {
+        let (base_url, requests) = stand_in_server(vec![
+            b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\nETag: \"v1\"\r\nConnection: close\r\n\r\nhello",
+            b"HTTP/1.1 206 Partial Content\r\nContent-Length: 5\r\nContent-Range: bytes 5-9/10\r\nETag: \"v1\"\r\nConnection: close\r\n\r\nworld",
+        ]);
+
+        let dst = download_file(&Context::builder().finish().unwrap(), &format!("{base_url}/pack.zip"), download_dst("resumed.zip"))
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read_to_string(&dst).unwrap(), "helloworld");
+        assert!(!download_dst("resumed.zip.part.validator").exists());
+
+        let requests: Vec<String> = requests.iter().map(|request| request.to_lowercase()).collect();
+        assert!(!requests[0].contains("range:"));
+        assert!(requests[1].contains("range: bytes=5-"));
+        assert!(requests[1].contains("if-range: \"v1\""));
+    }
+
+    #[tokio::test]
+    async fn starts_over_when_the_file_changed() {
+        let (base_url, requests) = stand_in_server(vec![
+            b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\nLast-Modified: Wed, 21 Oct 2015 07:28:00 GMT\r\nConnection: close\r\n\r\nhello",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\nLast-Modified: Thu, 22 Oct 2015 07:28:00 GMT\r\nConnection: close\r\n\r\nHELLOWORLD",
+        ]);
+
+        let dst = download_file(&Context::builder().finish().unwrap(), &format!("{base_url}/pack.zip"), download_dst("changed.zip"))
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read_to_string(dst).unwrap(), "HELLOWORLD");
+
+        let requests: Vec<String> = requests.iter().map(|request| request.to_lowercase()).collect();
+        assert!(requests[1].contains("if-range: wed, 21 oct 2015 07:28:00 gmt"));
+    }
+
+    #[tokio::test]
+    async fn starts_over_without_a_validator() {
+        let (base_url, requests) = stand_in_server(vec![
+            b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\nETag: W/\"weak\"\r\nConnection: close\r\n\r\nhello",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\nConnection: close\r\n\r\nhelloworld",
+        ]);
+
+        let dst = download_file(&Context::builder().finish().unwrap(), &format!("{base_url}/pack.zip"), download_dst("unvalidated.zip"))
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read_to_string(dst).unwrap(), "helloworld");
+
+        let requests: Vec<String> = requests.iter().map(|request| request.to_lowercase()).collect();
+        assert!(!requests[1].contains("range:"));
+    }
+
+    #[tokio::test]
+    async fn downloads_without_content_length() {
+        let (base_url, _) = stand_in_server(vec![
+            b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nno length",
+        ]);
+
+        let dst = download_file(&Context::builder().finish().unwrap(), &format!("{base_url}/installer.jar"), download_dst("no-length.jar"))
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read_to_string(dst).unwrap(), "no length");
+    }
+
+    fn status_error(status: StatusCode) -> DownloadError {
+        DownloadError::Status {
+            url: String::from("https://example.com/server.jar"),
+            status,
+            retry_after: None,
+        }
+    }
+
+    #[test]
+    fn only_retries_retryable_statuses() {
+        assert!(status_error(StatusCode::TOO_MANY_REQUESTS).is_retryable());
+        assert!(status_error(StatusCode::BAD_GATEWAY).is_retryable());
+        assert!(!status_error(StatusCode::NOT_FOUND).is_retryable());
+        assert!(!status_error(StatusCode::FORBIDDEN).is_retryable());
+    }
+
+    #[test]
+    fn parses_and_caps_retry_after_seconds() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("30"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(30)));
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("3600"));
+        assert_eq!(retry_after(&headers), Some(MAX_RETRY_AFTER));
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert_eq!(retry_after(&headers), None);
+    }
+
+    #[test]
+    fn finds_server_files_in_flat_and_wrapped_packs() {
+        let dir = PathBuf::from("./.mcsi-test-dir")
+            .join("tests")
+            .join("common-parent");
+        if dir.is_dir() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        let flat = dir.join("flat");
+        std::fs::create_dir_all(flat.join("mods")).unwrap();
+        std::fs::write(flat.join("server.jar"), "").unwrap();
+        assert_eq!(get_closest_common_parent(&flat).unwrap(), flat);
+
+        let wrapped = dir.join("wrapped");
+        std::fs::create_dir_all(wrapped.join("Pack Server").join("mods")).unwrap();
+        std::fs::create_dir_all(wrapped.join("Pack Server").join("config")).unwrap();
+        assert_eq!(get_closest_common_parent(&wrapped).unwrap(), wrapped.join("Pack Server"));
+    }
+}
@@ -0,0 +1,170 @@
+use std::path::PathBuf;
+use std::time::Duration;
+use reqwest::{Certificate, Client, Proxy};
+
+pub const USER_AGENT: &str = concat!(
+    "mc-server-installer/",
+    env!("CARGO_PKG_VERSION"),
+    " (+https://github.com/Rune580/mc-server-installer)",
+);
+
+#[derive(Clone, Debug)]
+pub struct HttpConfig {
+    // Routes every request through this proxy instead of the one from the environment
+    pub proxy: Option<String>,
+    pub connect_timeout: Duration,
+    // How long a request may go without receiving any data
+    pub read_timeout: Duration,
+    pub ca_cert: Option<PathBuf>,
+    pub user_agent: String,
+    pub endpoints: Endpoints,
+}
+
+// Base urls of every service we talk to, so they can point at a mirror instead.
+#[derive(Clone, Debug)]
+pub struct Endpoints {
+    pub curseforge_api: String,
+    pub ftb_api: String,
+    pub forge_maven: String,
+    pub neoforge_maven: String,
+    pub fabric_meta: String,
+    pub mojang_api: String,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            proxy: None,
+            connect_timeout: Duration::from_secs(30),
+            read_timeout: Duration::from_secs(60),
+            ca_cert: None,
+            user_agent: USER_AGENT.to_string(),
+            endpoints: Endpoints::default(),
+        }
+    }
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Endpoints {
+            curseforge_api: String::from("https://api.curseforge.com"),
+            ftb_api: String::from("https://api.modpacks.ch"),
+            forge_maven: String::from("https://maven.minecraftforge.net"),
+            neoforge_maven: String::from("https://maven.neoforged.net/releases"),
+            fabric_meta: String::from("https://meta.fabricmc.net"),
+            mojang_api: String::from("https://api.minecraftservices.com"),
+        }
+    }
+}
+
+impl Endpoints {
+    fn trimmed(self) -> Self {
+        let trim = |url: String| url.trim_end_matches('/').to_string();
+
+        Endpoints {
+            curseforge_api: trim(self.curseforge_api),
+            ftb_api: trim(self.ftb_api),
+            forge_maven: trim(self.forge_maven),
+            neoforge_maven: trim(self.neoforge_maven),
+            fabric_meta: trim(self.fabric_meta),
+            mojang_api: trim(self.mojang_api),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Http {
+    pub(crate) client: Client,
+    pub(crate) endpoints: Endpoints,
+}
+
+impl Http {
+    pub(crate) fn new(config: HttpConfig) -> color_eyre::Result<Self> {
+        let mut builder = Client::builder()
+            .user_agent(config.user_agent)
+            .connect_timeout(config.connect_timeout)
+            .read_timeout(config.read_timeout);
+
+        if let Some(proxy) = config.proxy {
+            builder = builder.proxy(Proxy::all(proxy)?);
+        }
+
+        if let Some(ca_cert) = config.ca_cert {
+            let pem = std::fs::read(ca_cert)?;
+            for cert in Certificate::from_pem_bundle(&pem)? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+
+        Ok(Http {
+            client: builder.build()?,
+            endpoints: config.endpoints.trimmed(),
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+    use crate::context::Context;
+    use crate::http::{Endpoints, HttpConfig};
+
+    // Serves the given raw responses to consecutive connections, returning the base url and the received requests.
+    pub(crate) fn stand_in_server(responses: Vec<&'static [u8]>) -> (String, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, requests) = channel();
+
+        std::thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+                let mut request = String::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" || line.is_empty() {
+                        break;
+                    }
+                    request += &line;
+                }
+                let _ = sender.send(request);
+
+                stream.write_all(response).unwrap();
+            }
+        });
+
+        (base_url, requests)
+    }
+
+    #[tokio::test]
+    async fn sends_user_agent_to_configured_endpoint() {
+        let (base_url, requests) = stand_in_server(vec![
+            b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}",
+        ]);
+
+        let ctx = Context::builder()
+            .with_http(HttpConfig {
+                user_agent: String::from("test-agent/1.0"),
+                endpoints: Endpoints {
+                    ftb_api: format!("{base_url}/mirror/"),
+                    ..Endpoints::default()
+                },
+                ..HttpConfig::default()
+            })
+            .finish()
+            .unwrap();
+
+        ctx.client().get(format!("{}/public/modpack/1", ctx.endpoints().ftb_api))
+            .send()
+            .await
+            .unwrap();
+
+        let request = requests.recv().unwrap().to_lowercase();
+        assert!(request.starts_with("get /mirror/public/modpack/1 "));
+        assert!(request.contains("user-agent: test-agent/1.0"));
+    }
+}
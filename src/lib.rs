@@ -0,0 +1,19 @@
+//! Installs modded Minecraft servers from CurseForge and FTB packs or plain mod loaders.
+//!
+//! Packs are installed through [`modpack::pipeline`], either with one of the bundled
+//! [`modpack::pipeline::ModpackSource`]s or your own. Every call takes a [`context::Context`],
+//! which carries the http client, the download cache, the progress reporter and the event listeners.
+
+pub mod cache;
+pub mod context;
+pub mod events;
+pub(crate) mod fs_utils;
+pub mod http;
+pub mod lock;
+pub mod modloader;
+pub mod modpack;
+pub mod plan;
+pub mod progress;
+pub mod version;
+
+pub use fs_utils::{get_log_file, DownloadError, FsError};
@@ -0,0 +1,241 @@
+use std::collections::BTreeMap;
+use std::fs::{read, write};
+use std::path::Path;
+use std::sync::Mutex;
+use log::info;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use crate::modloader::ModLoader;
+use crate::modpack::PackSource;
+use crate::context::Context;
+
+pub const LOCK_FILE: &str = "mcsi.lock";
+
+// Everything an install resolved, enough to reproduce the exact same install later.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct PackLock {
+    pub source: PackSource,
+    #[serde(default)]
+    pub mod_loader: Option<ModLoader>,
+    // Sha1 of every downloaded file, keyed by the url it was downloaded from.
+    pub downloads: BTreeMap<String, String>,
+}
+
+#[derive(Default, Debug)]
+struct Resolved {
+    mod_loader: Option<ModLoader>,
+    downloads: BTreeMap<String, String>,
+}
+
+impl PackLock {
+    pub fn save_to<T: AsRef<Path>>(&self, ctx: &Context, target_dir: T) -> color_eyre::Result<()> {
+        if ctx.is_dry_run() {
+            ctx.plan.change(format!("Write {LOCK_FILE}"));
+            return Ok(());
+        }
+
+        let bytes = serde_json::to_vec_pretty(&self)?;
+        write(target_dir.as_ref().join(LOCK_FILE), bytes)?;
+
+        Ok(())
+    }
+
+    pub fn load_from<T: AsRef<Path>>(target_dir: T) -> color_eyre::Result<Self> {
+        let file_path = target_dir.as_ref()
+            .join(LOCK_FILE);
+        if !file_path.is_file() {
+            return Err(LockError::LockNotFound)?;
+        }
+
+        let bytes = read(file_path)?;
+        let lock = serde_json::from_slice(&bytes)?;
+
+        Ok(lock)
+    }
+}
+
+#[derive(Default, Debug)]
+pub(crate) struct LockRecorder {
+    locked: Mutex<Option<PackLock>>,
+    resolved: Mutex<Resolved>,
+}
+
+impl LockRecorder {
+    // Makes everything resolved match `lock` until the returned guard is dropped.
+    pub(crate) fn enforce(&self, lock: Option<PackLock>) -> Result<Enforced<'_>, LockError> {
+        let mut locked = self.locked.lock().unwrap();
+        if locked.is_some() {
+            return Err(LockError::AlreadyEnforced);
+        }
+
+        if let Some(lock) = &lock {
+            info!("Installing from {LOCK_FILE}, {} locked downloads", lock.downloads.len());
+        }
+        *locked = lock;
+
+        Ok(Enforced(self))
+    }
+
+    // The sha1 a download must have when a lock is enforced.
+    pub(crate) fn locked_sha1(&self, url: &str) -> Result<Option<String>, LockError> {
+        let locked = self.locked.lock().unwrap();
+        let Some(lock) = locked.as_ref() else { return Ok(None) };
+
+        match lock.downloads.get(url) {
+            Some(sha1) => Ok(Some(sha1.clone())),
+            None => Err(LockError::NotLocked(url.to_string())),
+        }
+    }
+
+    // Records a file something else downloads, which has to match the enforced lock.
+    pub(crate) fn record_file(&self, url: &str, sha1: &str) -> Result<(), LockError> {
+        if let Some(locked) = self.locked_sha1(url)?
+            && !locked.eq_ignore_ascii_case(sha1) {
+            return Err(LockError::FileChanged(url.to_string()));
+        }
+
+        self.record_download(url, sha1.to_string());
+        Ok(())
+    }
+
+    pub(crate) fn record_download(&self, url: &str, sha1: String) {
+        self.resolved.lock()
+            .unwrap()
+            .downloads
+            .insert(url.to_string(), sha1);
+    }
+
+    pub(crate) fn record_mod_loader(&self, mod_loader: &ModLoader) -> Result<(), LockError> {
+        if let Some(lock) = self.locked.lock().unwrap().as_ref()
+            && lock.mod_loader.as_ref() != Some(mod_loader) {
+            return Err(LockError::ModLoaderChanged {
+                locked: lock.mod_loader.clone(),
+                resolved: mod_loader.clone(),
+            });
+        }
+
+        self.resolved.lock()
+            .unwrap()
+            .mod_loader = Some(mod_loader.clone());
+
+        Ok(())
+    }
+
+    // Builds the lock from everything resolved since the last call, checking it against the enforced lock.
+    pub(crate) fn finish(&self, source: PackSource, dry_run: bool) -> Result<PackLock, LockError> {
+        let resolved = std::mem::take(&mut *self.resolved.lock().unwrap());
+        let lock = PackLock {
+            source,
+            mod_loader: resolved.mod_loader,
+            downloads: resolved.downloads,
+        };
+
+        // Nothing is downloaded during a dry run, so there is nothing to compare
+        if let Some(locked) = self.locked.lock().unwrap().as_ref() && !dry_run {
+            if locked.source != lock.source {
+                return Err(LockError::SourceChanged);
+            }
+
+            if let Some(url) = locked.downloads.keys().find(|url| !lock.downloads.contains_key(*url)) {
+                return Err(LockError::Unresolved(url.clone()));
+            }
+        }
+
+        Ok(lock)
+    }
+}
+
+pub(crate) struct Enforced<'a>(&'a LockRecorder);
+
+impl Drop for Enforced<'_> {
+    fn drop(&mut self) {
+        self.0.locked.lock().unwrap().take();
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum LockError {
+    #[error("No {LOCK_FILE} found in the target dir, install without --locked first")]
+    LockNotFound,
+    #[error("{LOCK_FILE} was created for a different pack source")]
+    WrongSource,
+    #[error("The pack no longer resolves to the locked source")]
+    SourceChanged,
+    #[error("`{0}` is not part of {LOCK_FILE}")]
+    NotLocked(String),
+    #[error("`{0}` from {LOCK_FILE} was never downloaded")]
+    Unresolved(String),
+    #[error("`{0}` changed since {LOCK_FILE} was written")]
+    FileChanged(String),
+    #[error("Another install is already enforcing a lock")]
+    AlreadyEnforced,
+    #[error("Resolved mod loader {resolved} but {LOCK_FILE} has {locked:?}")]
+    ModLoaderChanged {
+        locked: Option<ModLoader>,
+        resolved: ModLoader,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::path::PathBuf;
+    use crate::context::Context;
+    use crate::lock::{LockError, LockRecorder, PackLock};
+    use crate::modloader::ModLoader;
+    use crate::modpack::PackSource;
+
+    #[test]
+    fn lock_round_trip() {
+        let target_dir = PathBuf::from("./.mcsi-test-dir")
+            .join("tests")
+            .join("lock");
+        if target_dir.is_dir() {
+            remove_dir_all(&target_dir).unwrap();
+        }
+        create_dir_all(&target_dir).unwrap();
+
+        let lock = PackLock {
+            source: PackSource::Flame {
+                project_id: 1,
+                file_id: 2,
+                skip_server_pack: false,
+            },
+            mod_loader: Some(ModLoader::Forge { version: String::from("47.2.0") }),
+            downloads: BTreeMap::from([
+                (String::from("https://example.com/a.jar"), String::from("da39a3ee5e6b4b0d3255bfef95601890afd80709")),
+            ]),
+        };
+        lock.save_to(&Context::builder().finish().unwrap(), &target_dir).unwrap();
+
+        assert_eq!(PackLock::load_from(&target_dir).unwrap(), lock);
+    }
+
+    #[test]
+    fn enforces_files_until_released() {
+        let source = PackSource::Ftb {
+            pack_id: 1,
+            version_id: 2,
+        };
+        let lock = PackLock {
+            source: source.clone(),
+            mod_loader: None,
+            downloads: BTreeMap::from([
+                (String::from("https://example.com/a.jar"), String::from("aaaa")),
+            ]),
+        };
+        let recorder = LockRecorder::default();
+
+        let enforced = recorder.enforce(Some(lock.clone())).unwrap();
+        assert!(matches!(recorder.enforce(None), Err(LockError::AlreadyEnforced)));
+        assert!(matches!(recorder.record_file("https://example.com/a.jar", "bbbb"), Err(LockError::FileChanged(_))));
+        assert!(matches!(recorder.record_file("https://example.com/b.jar", "bbbb"), Err(LockError::NotLocked(_))));
+        recorder.record_file("https://example.com/a.jar", "aaaa").unwrap();
+        assert_eq!(recorder.finish(source.clone(), false).unwrap().downloads, lock.downloads);
+        drop(enforced);
+
+        recorder.record_file("https://example.com/b.jar", "bbbb").unwrap();
+        recorder.enforce(None).unwrap();
+    }
+}
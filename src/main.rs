@@ -1,67 +1,120 @@
-use std::str::FromStr;
 use clap::Parser;
+use color_eyre::eyre::OptionExt;
 use dotenvy::dotenv;
-use simplelog::{ColorChoice, CombinedLogger, TerminalMode, TermLogger, WriteLogger};
-use cli::Cli;
-use crate::fs_utils::{ensure_dir, get_log_file};
-use crate::modloader::fabric::install_fabric;
-use crate::modloader::forge::install_forge;
-use crate::modloader::neoforge::install_neoforge;
-use crate::modpack::ftb::IdOrSearch;
-use crate::version::McVersion;
+use simplelog::{ColorChoice, CombinedLogger, SharedLogger, TerminalMode, TermLogger, WriteLogger};
+use cli::{Cli, OutputFormat};
+use mc_server_installer::{cache, events, get_log_file, modpack, plan};
+use mc_server_installer::context::Context;
+use mc_server_installer::events::EventLogger;
+use mc_server_installer::modloader::{install_mod_loader, ModLoader};
+use mc_server_installer::modpack::container::ContainerPack;
+use mc_server_installer::modpack::ftb::IdOrSearch;
+use mc_server_installer::modpack::backup::BackupFormat;
+use mc_server_installer::modpack::{service, InstallOptions};
+use mc_server_installer::progress::NoopReporter;
+use mc_server_installer::version::McVersionRange;
 
 mod cli;
-mod modpack;
-mod modloader;
-mod version;
-pub mod fs_utils;
 
 #[tokio::main]
 async fn main() -> color_eyre::Result<()> {
     dotenv().ok();
 
     let cli = Cli::parse();
+    let json = cli.output == OutputFormat::Json;
+
+    // Stdout belongs to the events when they are printed as json
+    let terminal_logger: Box<dyn SharedLogger> = if json {
+        Box::new(EventLogger)
+    } else {
+        TermLogger::new(cli.rust_log.into(), simplelog::Config::default(), TerminalMode::Mixed, ColorChoice::Auto)
+    };
 
     CombinedLogger::init(
         vec![
-            TermLogger::new(cli.rust_log.into(), simplelog::Config::default(), TerminalMode::Mixed, ColorChoice::Auto),
-            WriteLogger::new(cli.rust_log.into(), simplelog::Config::default(), get_log_file().unwrap()),
+            terminal_logger,
+            WriteLogger::new(cli.rust_log.into(), simplelog::Config::default(), get_log_file()?),
         ]
     )?;
 
-    match cli.sub_command {
+    let mut builder = Context::builder()
+        .with_http(cli.http.config())
+        .with_cache((cli.cache || cli.cache_dir.is_some()).then(|| cli.download_cache()))
+        .with_dry_run(cli.dry_run);
+    if json {
+        builder = builder.with_reporter(NoopReporter)
+            .with_listener(events::print_json);
+    }
+    let ctx = builder.finish()?;
+
+    let result = run(&ctx, &cli).await;
+
+    let plan = ctx.finish(&result);
+    if let (false, Ok(()), Some(plan)) = (json, &result, &plan) {
+        plan::print(plan, &ctx.work_dir());
+    }
+
+    result
+}
+
+async fn run(ctx: &Context, cli: &Cli) -> color_eyre::Result<()> {
+    match cli.sub_command.clone() {
         cli::CliSubCommand::Flame {
             api_key,
             project_id,
             version,
             target_dir,
             skip_server_pack,
-        } => modpack::flame::handle_flame(api_key, project_id, version, target_dir, skip_server_pack).await?,
+            locked,
+            offline,
+            no_backup,
+            backup_format,
+        } => {
+            let options = install_options(cli, backup_format.unless(no_backup));
+
+            if let Some(bundle) = offline {
+                modpack::bundle::install_bundle(ctx, bundle, target_dir, locked, &options)
+                    .await?;
+            } else if locked {
+                modpack::flame::handle_flame_locked(ctx, required_api_key(api_key)?, target_dir, &options)
+                    .await?;
+            } else {
+                modpack::flame::handle_flame(ctx, required_api_key(api_key)?, project_id.ok_or_eyre("--project-id is required")?, version.ok_or_eyre("--version is required")?, target_dir, skip_server_pack, &options)
+                    .await?;
+            }
+        }
         cli::CliSubCommand::Ftb {
             search_terms,
             mc_version,
             id,
             version,
             target_dir,
+            locked,
+            offline,
+            no_backup,
+            backup_format,
         } => {
-            let args = if id.is_some() {
-                IdOrSearch::Id(id.unwrap())
+            let options = install_options(cli, backup_format.unless(no_backup));
+
+            if let Some(bundle) = offline {
+                modpack::bundle::install_bundle(ctx, bundle, target_dir, locked, &options)
+                    .await?;
+            } else if locked {
+                modpack::ftb::handle_ftb_locked(ctx, target_dir, &options)
+                    .await?;
             } else {
-                IdOrSearch::Search {
-                    terms: search_terms.unwrap(),
-                    mc_version,
-                }
-            };
+                let args = ftb_args(id, search_terms, mc_version)?;
 
-            modpack::ftb::handle_ftb(args, version, target_dir)
-                .await?;
+                modpack::ftb::handle_ftb(ctx, args, version.ok_or_eyre("--version is required")?, target_dir, &options)
+                    .await?;
+            }
         }
         cli::CliSubCommand::NeoForge {
             version,
             target_dir,
         } => {
-            ensure_dir(&target_dir)?;
-            install_neoforge(&version, &target_dir)
+            std::fs::create_dir_all(&target_dir)?;
+            install_mod_loader(ctx, &ModLoader::from_parts("neoforge", &version)?, None, cli.jvm.config().java(), &target_dir)
                 .await?;
         }
         cli::CliSubCommand::Forge {
@@ -69,9 +122,8 @@ async fn main() -> color_eyre::Result<()> {
             version,
             target_dir,
         } => {
-            let mc_version = McVersion::from_str(&mc_version)?;
-            ensure_dir(&target_dir)?;
-            install_forge(mc_version, &version, &target_dir)
+            std::fs::create_dir_all(&target_dir)?;
+            install_mod_loader(ctx, &ModLoader::from_parts("forge", &version)?, Some(mc_version), cli.jvm.config().java(), &target_dir)
                 .await?;
         }
         cli::CliSubCommand::Fabric {
@@ -79,12 +131,151 @@ async fn main() -> color_eyre::Result<()> {
             version,
             target_dir,
         } => {
-            let mc_version = McVersion::from_str(&mc_version)?;
-            ensure_dir(&target_dir)?;
-            install_fabric(mc_version, &version, &target_dir)
+            std::fs::create_dir_all(&target_dir)?;
+            install_mod_loader(ctx, &ModLoader::from_parts("fabric", &version)?, Some(mc_version), cli.jvm.config().java(), &target_dir)
+                .await?;
+        }
+        cli::CliSubCommand::Apply {
+            target_dir,
+            config,
+            api_key,
+            no_backup,
+            backup_format,
+        } => {
+            modpack::config::handle_apply(ctx, target_dir, config, api_key, &install_options(cli, backup_format.unless(no_backup)))
+                .await?;
+        }
+        cli::CliSubCommand::ExportBundle {
+            output,
+            source,
+        } => {
+            let staged = match source {
+                cli::BundleSource::Flame {
+                    api_key,
+                    project_id,
+                    version,
+                    skip_server_pack,
+                } => modpack::flame::stage_flame(ctx, api_key, project_id, version, skip_server_pack, &cli.jvm.config()).await?,
+                cli::BundleSource::Ftb {
+                    search_terms,
+                    id,
+                    mc_version,
+                    version,
+                } => modpack::ftb::stage_ftb(ctx, ftb_args(id, search_terms, mc_version)?, version, &cli.jvm.config()).await?,
+            };
+
+            modpack::bundle::export_bundle(ctx, staged, output)
                 .await?;
         }
+        cli::CliSubCommand::Verify {
+            target_dir,
+            repair,
+            api_key,
+        } => {
+            modpack::verify::handle_verify(ctx, target_dir, repair, api_key, &cli.jvm.config())
+                .await?;
+        }
+        cli::CliSubCommand::Uninstall {
+            target_dir,
+            backup,
+        } => {
+            modpack::uninstall(ctx, target_dir, backup.map(Into::into))
+                .await?;
+        }
+        cli::CliSubCommand::ContainerEntrypoint {
+            target_dir,
+            pack,
+            api_key,
+            project_id,
+            id,
+            version,
+            skip_server_pack,
+            no_backup,
+            backup_format,
+        } => {
+            let pack = match pack {
+                Some(cli::ContainerPack::Flame) => Some(ContainerPack::Flame {
+                    api_key: api_key.ok_or_eyre("--api-key is required for flame packs")?,
+                    project_id: project_id.ok_or_eyre("--project-id is required for flame packs")?,
+                    version,
+                    skip_server_pack,
+                }),
+                Some(cli::ContainerPack::Ftb) => Some(ContainerPack::Ftb {
+                    id: id.ok_or_eyre("--id is required for ftb packs")?,
+                    version,
+                }),
+                None => None,
+            };
+
+            modpack::container::handle_container_entrypoint(ctx, target_dir, pack, &install_options(cli, backup_format.unless(no_backup)))
+                .await?;
+        }
+        cli::CliSubCommand::InstallService {
+            target_dir,
+            unit_dir,
+            start,
+        } => {
+            service::handle_install_service(ctx, target_dir, unit_dir, start, &cli.service.config())
+                .await?;
+        }
+        cli::CliSubCommand::Cache { command } => match command {
+            cli::CacheSubCommand::Clean { max_size } => {
+                cache::handle_clean(ctx, cli.download_cache(), max_size)?;
+            }
+        },
+        cli::CliSubCommand::Backup { command } => match command {
+            cli::BackupSubCommand::List { target_dir } => {
+                let backups = modpack::backup::handle_list(ctx, target_dir)?;
+                if cli.output == OutputFormat::Text {
+                    modpack::backup::print_list(&backups);
+                }
+            }
+            cli::BackupSubCommand::Restore {
+                name,
+                target_dir,
+                no_backup,
+                backup_format,
+            } => {
+                modpack::backup::handle_restore(ctx, target_dir, name, backup_format.unless(no_backup))
+                    .await?;
+            }
+            cli::BackupSubCommand::Prune {
+                target_dir,
+                keep,
+                older_than,
+            } => {
+                modpack::backup::handle_prune(ctx, target_dir, keep, older_than)
+                    .await?;
+            }
+        },
     }
 
     Ok(())
 }
+
+fn required_api_key(api_key: Option<String>) -> color_eyre::Result<String> {
+    api_key.ok_or_eyre("--api-key is required unless installing --offline")
+}
+
+fn install_options(cli: &Cli, backup: Option<BackupFormat>) -> InstallOptions {
+    InstallOptions {
+        backup,
+        locked: None,
+        jvm: cli.jvm.config(),
+        server: cli.server.settings(),
+        service: cli.service.config(),
+    }
+}
+
+fn ftb_args(id: Option<String>, search_terms: Option<Vec<String>>, mc_version: Option<McVersionRange>) -> color_eyre::Result<IdOrSearch> {
+    let args = if let Some(id) = id {
+        IdOrSearch::Id(id)
+    } else {
+        IdOrSearch::Search {
+            terms: search_terms.ok_or_eyre("Either --id or --search-terms is required")?,
+            mc_version,
+        }
+    };
+
+    Ok(args)
+}
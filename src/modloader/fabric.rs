@@ -1,28 +1,62 @@
 use std::path::Path;
 use crate::fs_utils::download_file;
+use crate::context::Context;
+use crate::modloader::{created_files, files_in, LoaderInstall, ModLoader, ModLoaderError, ModLoaderInstaller};
 use crate::version::McVersion;
 
-pub async fn install_fabric<P: AsRef<Path>>(mc_version: McVersion, loader_version: &str, work_dir: P) -> color_eyre::Result<()> {
-    let installer_version = latest_fabric_installer_version()
-        .await?;
+pub struct FabricInstaller {
+    mc_version: McVersion,
+    loader_version: String,
+}
 
-    let url = format!("https://meta.fabricmc.net/v2/versions/loader/{0}/{1}/{2}/server/jar", mc_version.as_str(), loader_version, installer_version);
-    let dst = work_dir.as_ref().join("server.jar");
+impl FabricInstaller {
+    pub fn new(mc_version: McVersion, loader_version: &str) -> Self {
+        FabricInstaller {
+            mc_version,
+            loader_version: loader_version.to_string(),
+        }
+    }
+}
 
-    download_file(&url, dst)
-        .await?;
+impl ModLoaderInstaller for FabricInstaller {
+    async fn install(&self, ctx: &Context, work_dir: &Path) -> color_eyre::Result<LoaderInstall> {
+        let before = files_in(work_dir);
+        let installer_version = latest_fabric_installer_version(ctx)
+            .await?;
 
-    Ok(())
+        let url = format!("{0}/v2/versions/loader/{1}/{2}/{3}/server/jar", ctx.endpoints().fabric_meta, self.mc_version, self.loader_version, installer_version);
+        let dst = work_dir.join("server.jar");
+
+        download_file(ctx, &url, dst)
+            .await?;
+
+        // The server jar is a launcher that fetches the rest of the loader on its first start
+        Ok(LoaderInstall {
+            mod_loader: ModLoader::Fabric {
+                version: self.loader_version.clone(),
+            },
+            launch_args: vec![String::from("-jar"), String::from("server.jar"), String::from("nogui")],
+            files: created_files(&before, work_dir),
+            java_version: self.mc_version.java_version(),
+            installer_log: String::new(),
+        })
+    }
 }
 
-async fn latest_fabric_installer_version() -> color_eyre::Result<String> {
-    let resp = reqwest::get("https://meta.fabricmc.net/v2/versions/installer")
+async fn latest_fabric_installer_version(ctx: &Context) -> color_eyre::Result<String> {
+    let url = format!("{0}/v2/versions/installer", ctx.endpoints().fabric_meta);
+    let resp = ctx.client().get(url)
+        .send()
         .await?
+        .error_for_status()?
         .text()
         .await?;
 
     let json: serde_json::Value = serde_json::from_str(&resp)?;
-    let version = json.get(0).unwrap().get("version").unwrap().as_str().unwrap();
+    let version = json.get(0)
+        .and_then(|installer| installer.get("version"))
+        .and_then(|version| version.as_str())
+        .ok_or(ModLoaderError::NoFabricInstaller)?;
 
     Ok(version.to_string())
-}
\ No newline at end of file
+}
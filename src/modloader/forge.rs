@@ -1,44 +1,71 @@
 use std::fs::remove_file;
 use std::path::Path;
-use std::process::Command;
-use log::{error, info};
+use log::info;
 use crate::fs_utils::download_file;
+use crate::context::Context;
+use crate::modloader::{args_file_launch, created_files, files_in, run_installer_jar, LoaderInstall, ModLoader, ModLoaderInstaller};
 use crate::version::McVersion;
 
-pub async fn install_forge<P: AsRef<Path>>(mc_version: McVersion, forge_version: &str, work_dir: P) -> color_eyre::Result<()> {
-    info!("Downloading forge...");
+pub struct ForgeInstaller {
+    mc_version: McVersion,
+    forge_version: String,
+    java: String,
+}
 
-    let long_version = format!("{mc_version}-{forge_version}", mc_version = mc_version.as_str());
-    let installer = format!("forge-{long_version}-installer.jar");
-    let universal = format!("forge-{long_version}-universal.jar");
+impl ForgeInstaller {
+    pub fn new(mc_version: McVersion, forge_version: &str) -> Self {
+        ForgeInstaller {
+            mc_version,
+            forge_version: forge_version.to_string(),
+            java: String::from("java"),
+        }
+    }
 
-    let installer_url = format!("https://maven.minecraftforge.net/net/minecraftforge/forge/{long_version}/{installer}");
-    let universal_url = format!("https://maven.minecraftforge.net/net/minecraftforge/forge/{long_version}/{universal}");
+    pub fn with_java(mut self, java: &str) -> Self {
+        self.java = java.to_string();
+        self
+    }
+}
 
-    let installer_dst = work_dir.as_ref().join("installer.jar");
-    let universal_dst = work_dir.as_ref().join("server.jar");
-    
-    download_file(&installer_url, &installer_dst)
-        .await?;
-    download_file(&universal_url, &universal_dst)
-        .await?;
+impl ModLoaderInstaller for ForgeInstaller {
+    async fn install(&self, ctx: &Context, work_dir: &Path) -> color_eyre::Result<LoaderInstall> {
+        info!("Downloading forge...");
 
-    info!("Installing forge...");
-    let output = Command::new("java")
-        .current_dir(&work_dir)
-        .args(["-jar", "installer.jar", "--installServer"])
-        .output()?;
+        let before = files_in(work_dir);
+        let long_version = format!("{mc_version}-{forge_version}", mc_version = self.mc_version, forge_version = self.forge_version);
+        let installer = format!("forge-{long_version}-installer.jar");
+        let universal = format!("forge-{long_version}-universal.jar");
 
-    if !output.status.success() {
-        error!("Forge install failed\nCode:\t{:#?}\nOutput:\n{:#?}", output.status.code(), output)
+        let maven = &ctx.endpoints().forge_maven;
+        let installer_url = format!("{maven}/net/minecraftforge/forge/{long_version}/{installer}");
+        let universal_url = format!("{maven}/net/minecraftforge/forge/{long_version}/{universal}");
 
-    } else {
-        info!("Forge installed successfully!")
-    }
+        let installer_dst = work_dir.join("installer.jar");
+        let universal_dst = work_dir.join("server.jar");
 
-    remove_file(installer_dst)?;
+        download_file(ctx, &installer_url, &installer_dst)
+            .await?;
+        download_file(ctx, &universal_url, &universal_dst)
+            .await?;
 
-    Ok(())
+        let mut installer_log = String::new();
+        if ctx.is_dry_run() {
+            ctx.plan.command(format!("{} -jar installer.jar --installServer (in {:?})", self.java, work_dir));
+        } else {
+            installer_log = run_installer_jar("Forge", &self.java, work_dir)?;
+            remove_file(installer_dst)?;
+        }
+
+        Ok(LoaderInstall {
+            mod_loader: ModLoader::Forge {
+                version: self.forge_version.clone(),
+            },
+            launch_args: args_file_launch(work_dir, &format!("libraries/net/minecraftforge/forge/{long_version}/unix_args.txt")),
+            files: created_files(&before, work_dir),
+            java_version: self.mc_version.java_version(),
+            installer_log,
+        })
+    }
 }
 
 #[cfg(test)]
@@ -46,7 +73,9 @@ mod tests {
     use std::fs::create_dir;
     use std::path::PathBuf;
     use std::str::FromStr;
-    use crate::modloader::forge::install_forge;
+    use crate::modloader::forge::ForgeInstaller;
+    use crate::context::Context;
+    use crate::modloader::ModLoaderInstaller;
     use crate::version::McVersion;
 
     #[tokio::test]
@@ -59,8 +88,12 @@ mod tests {
             create_dir(&work_dir).unwrap();
         }
 
-        install_forge(mc_version, forge_version, &work_dir)
+        let install = ForgeInstaller::new(mc_version, forge_version)
+            .install(&Context::builder().finish().unwrap(), &work_dir)
             .await
             .unwrap();
+
+        assert_eq!(install.java_version, 17);
+        assert!(!install.files.is_empty());
     }
-}
\ No newline at end of file
+}
@@ -1,6 +1,21 @@
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+use std::fs::write;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process::Command;
 use std::str::FromStr;
+use log::info;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use walkdir::WalkDir;
+use crate::fs_utils::{ensure_dir, file_path_relative_to, logs_dir};
+use crate::modloader::fabric::FabricInstaller;
+use crate::modloader::forge::ForgeInstaller;
+use crate::modloader::neoforge::NeoForgeInstaller;
+use crate::context::Context;
+use crate::events::Stage;
+use crate::version::McVersion;
 
 pub mod fabric;
 pub mod neoforge;
@@ -22,48 +37,351 @@ pub enum ModLoader {
     },
 }
 
+// Names each loader goes by in CurseForge ids, Modrinth dependencies and FTB targets, longest first so
+// `fabric-loader-0.15.11` isn't read as loader `fabric` with version `loader-0.15.11`.
+const LOADER_NAMES: [(&str, LoaderKind); 8] = [
+    ("minecraftforge", LoaderKind::Forge),
+    ("fabric-loader", LoaderKind::Fabric),
+    ("quilt-loader", LoaderKind::Quilt),
+    ("neoforged", LoaderKind::NeoForge),
+    ("neoforge", LoaderKind::NeoForge),
+    ("fabric", LoaderKind::Fabric),
+    ("forge", LoaderKind::Forge),
+    ("quilt", LoaderKind::Quilt),
+];
+
+// NeoForge for 1.20.1 forked Forge 47.1 and kept publishing it as `net.neoforged:forge`.
+const LEGACY_NEOFORGE_MC_VERSION: &str = "1.20.1";
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+enum LoaderKind {
+    NeoForge,
+    Forge,
+    Fabric,
+    Quilt,
+}
+
+impl ModLoader {
+    // A loader from its name and version, as listed by FTB version targets.
+    pub fn from_parts(name: &str, version: &str) -> Result<Self, ModLoaderParseError> {
+        let name = name.to_ascii_lowercase();
+        let (_, kind) = LOADER_NAMES.iter()
+            .find(|(loader_name, _)| *loader_name == name)
+            .ok_or_else(|| ModLoaderParseError::UnknownLoader(name.clone()))?;
+
+        Self::from_kind(*kind, version)
+    }
+
+    // A loader from a Modrinth `modrinth.index.json` dependency, `None` for the `minecraft` entry.
+    pub fn from_modrinth(dependency: &str, version: &str) -> Result<Option<Self>, ModLoaderParseError> {
+        if dependency == "minecraft" {
+            return Ok(None);
+        }
+
+        Self::from_parts(dependency, version)
+            .map(Some)
+    }
+
+    fn from_kind(kind: LoaderKind, version: &str) -> Result<Self, ModLoaderParseError> {
+        let version = version.trim();
+        let valid = !version.is_empty() && version.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '+' | '_'));
+        if !valid {
+            return Err(ModLoaderParseError::InvalidVersion(version.to_string()));
+        }
+
+        // Forge versions are sometimes qualified with the minecraft version, like `1.20.1-47.2.0`
+        let (mc_version, unqualified) = match version.split_once('-') {
+            Some((mc_version, unqualified)) if is_mc_version(mc_version) && unqualified.starts_with(|c: char| c.is_ascii_digit()) => (Some(mc_version), unqualified),
+            _ => (None, version),
+        };
+
+        let version = match kind {
+            LoaderKind::Forge => unqualified.to_string(),
+            LoaderKind::NeoForge if is_legacy_neoforge(unqualified) => {
+                let mc_version = mc_version.unwrap_or(LEGACY_NEOFORGE_MC_VERSION);
+                format!("{mc_version}-{unqualified}")
+            }
+            // Fabric and Quilt version ids put the minecraft version last, like `0.15.11-1.20.1`
+            LoaderKind::Fabric | LoaderKind::Quilt => match version.rsplit_once('-') {
+                Some((version, mc_version)) if is_mc_version(mc_version) => version.to_string(),
+                _ => version.to_string(),
+            },
+            LoaderKind::NeoForge => version.to_string(),
+        };
+
+        Ok(match kind {
+            LoaderKind::NeoForge => ModLoader::NeoForge { version },
+            LoaderKind::Forge => ModLoader::Forge { version },
+            LoaderKind::Fabric => ModLoader::Fabric { version },
+            LoaderKind::Quilt => ModLoader::Quilt { version },
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ModLoader::NeoForge { .. } => "neoforge",
+            ModLoader::Forge { .. } => "forge",
+            ModLoader::Fabric { .. } => "fabric",
+            ModLoader::Quilt { .. } => "quilt",
+        }
+    }
+
+    pub fn version(&self) -> &str {
+        match self {
+            ModLoader::NeoForge { version }
+            | ModLoader::Forge { version }
+            | ModLoader::Fabric { version }
+            | ModLoader::Quilt { version } => version,
+        }
+    }
+}
+
+fn is_mc_version(s: &str) -> bool {
+    McVersion::from_str(s).is_ok()
+}
+
+// Forge style version of the NeoForge fork for 1.20.1, like `47.1.106`, instead of `20.4.237`.
+fn is_legacy_neoforge(version: &str) -> bool {
+    version.split('.')
+        .next()
+        .and_then(|major| major.parse::<u32>().ok())
+        .is_some_and(|major| major == 47)
+}
+
+// Parses loader ids from CurseForge manifests and mcsi.toml, like `forge-47.2.0`, `fabric-0.15.11`
+// or `neoforge-1.20.1-47.1.106`. Version json ids like `1.20.1-forge-47.2.0` are understood too.
 impl FromStr for ModLoader {
     type Err = ModLoaderParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.contains("neoforge") {
-            let neoforge_version = s
-                .split("-")
-                .last()
-                .ok_or(ModLoaderParseError::InvalidInput)?;
-
-            Ok(ModLoader::NeoForge {
-                version: neoforge_version.to_string(),
-            })
+        let id = s.trim().to_ascii_lowercase();
+
+        // Version json ids put the minecraft version first
+        let (mc_version, id) = match id.split_once('-') {
+            Some((mc_version, rest)) if is_mc_version(mc_version) => (Some(mc_version), rest),
+            _ => (None, id.as_str()),
+        };
+
+        let (kind, version) = LOADER_NAMES.iter()
+            .find_map(|(name, kind)| id.strip_prefix(name)
+                .and_then(|rest| rest.strip_prefix('-'))
+                .map(|version| (*kind, version)))
+            .ok_or_else(|| ModLoaderParseError::UnknownLoader(s.to_string()))?;
+
+        match mc_version {
+            Some(mc_version) => Self::from_kind(kind, &format!("{mc_version}-{version}")),
+            None => Self::from_kind(kind, version),
+        }
+    }
+}
+
+impl Display for ModLoader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.name(), self.version())
+    }
+}
+
+const INSTALLER_LOG: &str = "mod-loader-installer.log";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LoaderInstall {
+    pub mod_loader: ModLoader,
+    // Arguments passed to `java` to start the server, relative to the server dir
+    pub launch_args: Vec<String>,
+    pub files: Vec<String>,
+    pub java_version: u8,
+    #[serde(skip)]
+    pub installer_log: String,
+}
+
+#[allow(async_fn_in_trait)]
+pub trait ModLoaderInstaller {
+    async fn install(&self, ctx: &Context, work_dir: &Path) -> color_eyre::Result<LoaderInstall>;
+}
+
+pub async fn install_mod_loader<P: AsRef<Path>>(
+    ctx: &Context,
+    mod_loader: &ModLoader,
+    mc_version: Option<McVersion>,
+    java: &str,
+    work_dir: P,
+) -> color_eyre::Result<LoaderInstall> {
+    ctx.events.record_outcome(|outcome| outcome.mod_loader = Some(mod_loader.clone()));
+
+    let work_dir = work_dir.as_ref();
+    let install = ctx.events.stage(Stage::InstallLoader, async move {
+        install(ctx, mod_loader, mc_version, java, work_dir)
+            .await
+    }).await?;
+
+    if !install.installer_log.is_empty() {
+        ensure_dir(logs_dir())?;
+        write(logs_dir().join(INSTALLER_LOG), &install.installer_log)?;
+    }
+
+    Ok(install)
+}
+
+async fn install(
+    ctx: &Context,
+    mod_loader: &ModLoader,
+    mc_version: Option<McVersion>,
+    java: &str,
+    work_dir: &Path,
+) -> color_eyre::Result<LoaderInstall> {
+    match mod_loader {
+        ModLoader::NeoForge { version } => {
+            info!("Detected mod loader: NeoForge, Version: {}", version);
+
+            NeoForgeInstaller::new(version)
+                .with_java(java)
+                .install(ctx, work_dir)
+                .await
         }
-        else if s.contains("forge") {
-            let forge_version = s
-                .split("-")
-                .last()
-                .ok_or(ModLoaderParseError::InvalidInput)?;
-
-            Ok(ModLoader::Forge {
-                version: forge_version.to_string()
-            })
-        } else if s.contains("fabric") {
-            let fabric_version = s
-                .split("-")
-                .last()
-                .ok_or(ModLoaderParseError::InvalidInput)?;
-
-            Ok(ModLoader::Fabric {
-                version: fabric_version.to_string()
-            })
-        } else if s.contains("quilt") {
-            Ok(ModLoader::Quilt { version: s.to_string() })
-        } else {
-            Err(ModLoaderParseError::InvalidInput)
+        ModLoader::Forge { version } => {
+            info!("Detected mod loader: Forge, Version: {}", version);
+
+            let mc_version = mc_version.ok_or(ModLoaderError::MissingMcVersion)?;
+            ForgeInstaller::new(mc_version, version)
+                .with_java(java)
+                .install(ctx, work_dir)
+                .await
+        }
+        ModLoader::Fabric { version } => {
+            info!("Detected mod loader: Fabric, Version: {}", version);
+
+            let mc_version = mc_version.ok_or(ModLoaderError::MissingMcVersion)?;
+            FabricInstaller::new(mc_version, version)
+                .install(ctx, work_dir)
+                .await
         }
+        ModLoader::Quilt { .. } => Err(ModLoaderError::Unsupported(mod_loader.clone()))?,
+    }
+}
+
+// Runs the `installer.jar` of forge based loaders in `work_dir` and returns its output.
+fn run_installer_jar(name: &str, java: &str, work_dir: &Path) -> color_eyre::Result<String> {
+    info!("Installing {name}...");
+    let output = Command::new(java)
+        .current_dir(work_dir)
+        .args(["-jar", "installer.jar", "--installServer"])
+        .output()
+        .map_err(|err| match err.kind() {
+            ErrorKind::NotFound => ModLoaderError::JavaNotFound(java.to_string()).into(),
+            _ => color_eyre::Report::new(err),
+        })?;
+
+    let log = format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+    if !output.status.success() {
+        ensure_dir(logs_dir())?;
+        write(logs_dir().join(INSTALLER_LOG), &log)?;
+
+        Err(ModLoaderError::InstallerFailed {
+            name: name.to_string(),
+            code: output.status.code(),
+        })?;
+    }
+
+    info!("{name} installed successfully!");
+    Ok(log)
+}
+
+fn files_in(dir: &Path) -> BTreeSet<String> {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| file_path_relative_to(entry.path(), dir).ok())
+        .map(|relative| relative.to_string_lossy().to_string())
+        .collect()
+}
+
+fn created_files(before: &BTreeSet<String>, dir: &Path) -> Vec<String> {
+    files_in(dir)
+        .into_iter()
+        .filter(|file| !before.contains(file))
+        .collect()
+}
+
+// Starts the server through the args file written by the installer, falling back to the server jar
+// for versions that predate args files.
+fn args_file_launch(work_dir: &Path, args_file: &str) -> Vec<String> {
+    if !work_dir.join(args_file).is_file() {
+        return vec![String::from("-jar"), String::from("server.jar"), String::from("nogui")];
     }
+
+    let mut args = Vec::new();
+    if work_dir.join("user_jvm_args.txt").is_file() {
+        args.push(String::from("@user_jvm_args.txt"));
+    }
+    args.push(format!("@{args_file}"));
+    args.push(String::from("nogui"));
+
+    args
+}
+
+#[derive(Error, Clone, Debug)]
+pub enum ModLoaderError {
+    #[error("A minecraft version is required to install this mod loader")]
+    MissingMcVersion,
+    #[error("Installing {0} isn't supported")]
+    Unsupported(ModLoader),
+    #[error("Fabric meta didn't list any installer versions")]
+    NoFabricInstaller,
+    #[error("`{0}` was not found, install java or point --java-path at it")]
+    JavaNotFound(String),
+    #[error("{name} installer exited with code {code:?}, its output is in .mcsi/logs/{INSTALLER_LOG}")]
+    InstallerFailed {
+        name: String,
+        code: Option<i32>,
+    },
 }
 
 #[derive(Error, Clone, Debug)]
 pub enum ModLoaderParseError {
-    #[error("Failed to parse mod loader")]
-    InvalidInput,
-}
\ No newline at end of file
+    #[error("`{0}` is not a mod loader id like forge-47.2.0, neoforge-20.4.237 or fabric-0.15.11")]
+    UnknownLoader(String),
+    #[error("`{0}` is not a mod loader version")]
+    InvalidVersion(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use crate::modloader::{ModLoader, LOADER_NAMES};
+
+    fn parse(id: &str) -> ModLoader {
+        ModLoader::from_str(id).unwrap()
+    }
+
+    #[test]
+    fn loader_ids_round_trip() {
+        for id in ["forge-47.2.0", "neoforge-20.4.237", "neoforge-21.1.77", "neoforge-1.20.1-47.1.106", "fabric-0.15.11", "quilt-0.26.0", "fabric-0.16.9+build.1"] {
+            assert_eq!(parse(id).to_string(), id);
+        }
+    }
+
+    #[test]
+    fn loader_names_are_longest_first() {
+        assert!(LOADER_NAMES.windows(2).all(|pair| pair[0].0.len() >= pair[1].0.len()));
+        assert!(matches!(parse("minecraftforge-47.2.0"), ModLoader::Forge { .. }));
+    }
+
+    #[test]
+    fn parses_every_id_format() {
+        assert_eq!(parse("forge-1.20.1-47.2.0"), ModLoader::Forge { version: String::from("47.2.0") });
+        assert_eq!(parse("1.20.1-forge-47.2.0"), ModLoader::Forge { version: String::from("47.2.0") });
+        assert_eq!(parse("neoforge-47.1.106"), ModLoader::NeoForge { version: String::from("1.20.1-47.1.106") });
+        assert_eq!(parse("NeoForge-20.4.237"), ModLoader::NeoForge { version: String::from("20.4.237") });
+        assert_eq!(parse("fabric-loader-0.15.11-1.20.1"), ModLoader::Fabric { version: String::from("0.15.11") });
+        assert_eq!(parse("quilt-0.26.0"), ModLoader::Quilt { version: String::from("0.26.0") });
+
+        assert_eq!(ModLoader::from_modrinth("fabric-loader", "0.15.11").unwrap(), Some(ModLoader::Fabric { version: String::from("0.15.11") }));
+        assert_eq!(ModLoader::from_modrinth("minecraft", "1.20.1").unwrap(), None);
+        assert_eq!(ModLoader::from_parts("neoforge", "47.1.106").unwrap().to_string(), "neoforge-1.20.1-47.1.106");
+
+        for id in ["", "forge", "forge-", "optifine-1.0", "forgery-1.0", "forge-4 7"] {
+            assert!(ModLoader::from_str(id).is_err(), "{id}");
+        }
+    }
+}
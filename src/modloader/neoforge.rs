@@ -1,40 +1,103 @@
 use std::fs::remove_file;
 use std::path::Path;
-use std::process::Command;
-use log::{error, info};
+use std::str::FromStr;
+use log::info;
 use crate::fs_utils::download_file;
+use crate::context::Context;
+use crate::modloader::{args_file_launch, created_files, files_in, run_installer_jar, LoaderInstall, ModLoader, ModLoaderInstaller};
+use crate::version::McVersion;
 
-pub async fn install_neoforge<P: AsRef<Path>>(neoforge_version: &str, work_dir: P) -> color_eyre::Result<()> {
-    info!("Downloading neoforge...");
-    
-    let installer = format!("neoforge-{neoforge_version}-installer.jar");
-    let universal = format!("neoforge-{neoforge_version}-universal.jar");
+pub struct NeoForgeInstaller {
+    neoforge_version: String,
+    java: String,
+}
 
-    let installer_url = format!("https://maven.neoforged.net/releases/net/neoforged/neoforge/{neoforge_version}/{installer}");
-    let universal_url = format!("https://maven.neoforged.net/releases/net/neoforged/neoforge/{neoforge_version}/{universal}");
+impl NeoForgeInstaller {
+    pub fn new(neoforge_version: &str) -> Self {
+        NeoForgeInstaller {
+            neoforge_version: neoforge_version.to_string(),
+            java: String::from("java"),
+        }
+    }
 
-    let installer_dst = work_dir.as_ref().join("installer.jar");
-    let universal_dst = work_dir.as_ref().join("server.jar");
+    pub fn with_java(mut self, java: &str) -> Self {
+        self.java = java.to_string();
+        self
+    }
 
-    download_file(&installer_url, &installer_dst)
-        .await?;
-    download_file(&universal_url, &universal_dst)
-        .await?;
+    // The 1.20.1 fork is qualified with its minecraft version, like `1.20.1-47.1.106`.
+    fn legacy_mc_version(&self) -> Option<McVersion> {
+        let (mc_version, _) = self.neoforge_version.split_once('-')?;
 
-    info!("Installing neoforge...");
-    let output = Command::new("java")
-        .current_dir(&work_dir)
-        .args(["-jar", "installer.jar", "--installServer"])
-        .output()?;
+        McVersion::from_str(mc_version).ok()
+    }
 
-    if !output.status.success() {
-        error!("NeoForge install failed\nCode:\t{:#?}\nOutput:\n{:#?}", output.status.code(), output)
+    // Maven artifact of the version, the 1.20.1 fork kept the name `forge`.
+    fn artifact(&self) -> &'static str {
+        match self.legacy_mc_version() {
+            Some(_) => "forge",
+            None => "neoforge",
+        }
+    }
 
-    } else {
-        info!("NeoForge installed successfully!")
+    // NeoForge versions start with the minor and patch of the minecraft version, 20.4.237 is for 1.20.4.
+    fn mc_version(&self) -> Option<McVersion> {
+        if let Some(mc_version) = self.legacy_mc_version() {
+            return Some(mc_version);
+        }
+
+        let mut nums = self.neoforge_version.split('.');
+        let minor = nums.next()?;
+        let patch = nums.next()?;
+
+        McVersion::from_str(&format!("1.{minor}.{patch}")).ok()
     }
+}
+
+impl ModLoaderInstaller for NeoForgeInstaller {
+    async fn install(&self, ctx: &Context, work_dir: &Path) -> color_eyre::Result<LoaderInstall> {
+        info!("Downloading neoforge...");
 
-    remove_file(installer_dst)?;
+        let neoforge_version = &self.neoforge_version;
+        let artifact = self.artifact();
+        let before = files_in(work_dir);
+        let installer = format!("{artifact}-{neoforge_version}-installer.jar");
+        let universal = format!("{artifact}-{neoforge_version}-universal.jar");
 
-    Ok(())
-}
\ No newline at end of file
+        let maven = &ctx.endpoints().neoforge_maven;
+        let installer_url = format!("{maven}/net/neoforged/{artifact}/{neoforge_version}/{installer}");
+        let universal_url = format!("{maven}/net/neoforged/{artifact}/{neoforge_version}/{universal}");
+
+        let installer_dst = work_dir.join("installer.jar");
+        let universal_dst = work_dir.join("server.jar");
+
+        download_file(ctx, &installer_url, &installer_dst)
+            .await?;
+        download_file(ctx, &universal_url, &universal_dst)
+            .await?;
+
+        let mut installer_log = String::new();
+        if ctx.is_dry_run() {
+            ctx.plan.command(format!("{} -jar installer.jar --installServer (in {:?})", self.java, work_dir));
+        } else {
+            installer_log = run_installer_jar("NeoForge", &self.java, work_dir)?;
+            remove_file(installer_dst)?;
+        }
+
+        // Every NeoForge version runs on at least 1.20.1, which needs java 17
+        let java_version = self.mc_version()
+            .map(|mc_version| mc_version.java_version())
+            .unwrap_or(17)
+            .max(17);
+
+        Ok(LoaderInstall {
+            mod_loader: ModLoader::NeoForge {
+                version: neoforge_version.clone(),
+            },
+            launch_args: args_file_launch(work_dir, &format!("libraries/net/neoforged/{artifact}/{neoforge_version}/unix_args.txt")),
+            files: created_files(&before, work_dir),
+            java_version,
+            installer_log,
+        })
+    }
+}
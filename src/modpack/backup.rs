@@ -0,0 +1,593 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use indicatif::HumanBytes;
+use log::{info, warn};
+use serde::Serialize;
+use thiserror::Error;
+use tokio::fs::{copy, create_dir_all, remove_dir_all, remove_file};
+use walkdir::WalkDir;
+use crate::fs_utils::{archive_and_remove_files, backup_and_remove_files, dir_size, ensure_parent, recursive_copy_to_dir, remove_files, sha1_file};
+use crate::modpack::{check_manifest, PackManifest};
+use crate::context::Context;
+
+const BACKUP_PREFIX: &str = "backup-";
+// Also parses the names of backups taken before they had milliseconds
+const BACKUP_TIME_FORMAT: &str = "%Y-%m-%d-%H%M%S%.3f";
+const ZIP_EXTENSION: &str = ".zip";
+const INDEX_EXTENSION: &str = ".index.json";
+const MANIFEST_EXTENSION: &str = ".manifest.json";
+
+#[derive(Serialize, Eq, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum BackupFormat {
+    // A plain copy of every file in `backup-<timestamp>/`
+    Dir,
+    // A deflate compressed `backup-<timestamp>.zip`
+    Zip,
+    // A `backup-<timestamp>.index.json` pointing into a content-addressed `objects/` store shared by all backups
+    Dedup,
+}
+
+#[derive(Clone, Debug)]
+pub struct BackupEntry {
+    pub name: String,
+    pub path: PathBuf,
+    pub format: BackupFormat,
+    pub created: DateTime<Utc>,
+}
+
+impl BackupEntry {
+    // The dir holding the backup and, for dedup backups, its objects.
+    fn backups_dir(&self) -> Result<&Path, BackupError> {
+        self.path.parent()
+            .ok_or_else(|| BackupError::NoBackupsDir(self.name.clone()))
+    }
+}
+
+pub fn backups_dir<T: AsRef<Path>>(target_dir: T) -> PathBuf {
+    target_dir.as_ref()
+        .join(".mcsi")
+        .join("backups")
+}
+
+fn objects_dir(backups_dir: &Path) -> PathBuf {
+    backups_dir.join("objects")
+}
+
+fn object_path(backups_dir: &Path, hash: &str) -> PathBuf {
+    objects_dir(backups_dir)
+        .join(&hash[..2])
+        .join(hash)
+}
+
+// The manifest of the pack a backup was taken from, stored next to the backup.
+fn backup_manifest_path(backups_dir: &Path, name: &str) -> PathBuf {
+    backups_dir.join(format!("{name}{MANIFEST_EXTENSION}"))
+}
+
+pub async fn create_backup(ctx: &Context, target_dir: &Path, manifest: PackManifest, format: BackupFormat) -> color_eyre::Result<PathBuf> {
+    let backups_dir = backups_dir(target_dir);
+    let name = backup_name(&backups_dir, Utc::now());
+
+    create_dir_all(&backups_dir)
+        .await?;
+
+    manifest.save_as(backup_manifest_path(&backups_dir, &name))?;
+
+    let backup_path = match format {
+        BackupFormat::Dir => {
+            let backup_dir = backups_dir.join(&name);
+            backup_and_remove_files(ctx, target_dir, &backup_dir, manifest.files)
+                .await?;
+
+            backup_dir
+        }
+        BackupFormat::Zip => {
+            let archive = backups_dir.join(format!("{name}{ZIP_EXTENSION}"));
+            archive_and_remove_files(ctx, target_dir, &archive, manifest.files)
+                .await?;
+
+            archive
+        }
+        BackupFormat::Dedup => {
+            let index = backups_dir.join(format!("{name}{INDEX_EXTENSION}"));
+            dedup_and_remove_files(ctx, target_dir, &backups_dir, &index, manifest)
+                .await?;
+
+            index
+        }
+    };
+
+    Ok(backup_path)
+}
+
+// `backup-<timestamp>`, with a counter appended if a backup was already taken in the same millisecond.
+fn backup_name(backups_dir: &Path, now: DateTime<Utc>) -> String {
+    let name = format!("{BACKUP_PREFIX}{}", now.format(BACKUP_TIME_FORMAT));
+    let taken = |name: &str| backup_manifest_path(backups_dir, name).exists();
+
+    if !taken(&name) {
+        return name;
+    }
+
+    (1..)
+        .map(|counter| format!("{name}-{counter}"))
+        .find(|name| !taken(name))
+        .unwrap_or(name)
+}
+
+// When the backup was taken and its counter, from its name.
+fn parse_backup_name(name: &str) -> Option<(NaiveDateTime, u32)> {
+    let timestamp = name.strip_prefix(BACKUP_PREFIX)?;
+    if let Ok(created) = NaiveDateTime::parse_from_str(timestamp, BACKUP_TIME_FORMAT) {
+        return Some((created, 0));
+    }
+
+    let (timestamp, counter) = timestamp.rsplit_once('-')?;
+    let created = NaiveDateTime::parse_from_str(timestamp, BACKUP_TIME_FORMAT).ok()?;
+
+    Some((created, counter.parse().ok()?))
+}
+
+// Stores every file once under its hash, so unchanged files are shared between backups.
+async fn dedup_and_remove_files(
+    ctx: &Context,
+    target_dir: &Path,
+    backups_dir: &Path,
+    index_path: &Path,
+    manifest: PackManifest,
+) -> color_eyre::Result<()> {
+    info!("Starting backup of files...");
+
+    let mut index = PackManifest {
+        files: Vec::new(),
+        hashes: BTreeMap::new(),
+        source: manifest.source,
+        loader: manifest.loader,
+        config: manifest.config,
+    };
+
+    for rel_file in &manifest.files {
+        let src_file = target_dir.join(rel_file);
+        if !src_file.is_file() {
+            warn!("File {} doesn't exist!", src_file.display());
+            continue;
+        }
+
+        let hash = sha1_file(&src_file)?;
+        let object = object_path(backups_dir, &hash);
+        if !object.is_file() {
+            ensure_parent(&object)
+                .await?;
+            copy(&src_file, &object)
+                .await?;
+        }
+
+        index.files.push(rel_file.clone());
+        index.hashes.insert(rel_file.clone(), hash);
+    }
+
+    index.save_as(index_path)?;
+
+    remove_files(ctx, target_dir, index.files)
+        .await?;
+
+    Ok(())
+}
+
+pub fn list_backups<T: AsRef<Path>>(target_dir: T) -> color_eyre::Result<Vec<BackupEntry>> {
+    let backups_dir = backups_dir(target_dir);
+    if !backups_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut backups = Vec::new();
+    for entry in std::fs::read_dir(backups_dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().to_string();
+
+        let (name, format) = if entry.path().is_dir() {
+            (file_name.as_str(), BackupFormat::Dir)
+        } else if let Some(name) = file_name.strip_suffix(ZIP_EXTENSION) {
+            (name, BackupFormat::Zip)
+        } else if let Some(name) = file_name.strip_suffix(INDEX_EXTENSION) {
+            (name, BackupFormat::Dedup)
+        } else {
+            continue;
+        };
+
+        if !name.starts_with(BACKUP_PREFIX) {
+            continue;
+        }
+        let Some((created, counter)) = parse_backup_name(name) else {
+            warn!("Skipping {file_name}, unrecognized backup name");
+            continue;
+        };
+
+        backups.push((counter, BackupEntry {
+            name: name.to_string(),
+            path: entry.path(),
+            format,
+            created: created.and_utc(),
+        }));
+    }
+
+    backups.sort_by_key(|(counter, entry)| std::cmp::Reverse((entry.created, *counter)));
+
+    Ok(backups.into_iter().map(|(_, entry)| entry).collect())
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct BackupInfo {
+    pub name: String,
+    // RFC 3339
+    pub created: String,
+    pub format: BackupFormat,
+    pub size: u64,
+}
+
+// Lists the backups of `target_dir`, newest first, and records them in the outcome.
+pub fn handle_list<T: AsRef<Path>>(ctx: &Context, target_dir: T) -> color_eyre::Result<Vec<BackupInfo>> {
+    let backups = list_backups(target_dir)?
+        .into_iter()
+        .map(|backup| Ok(BackupInfo {
+            size: backup_size(&backup)?,
+            name: backup.name,
+            created: backup.created.to_rfc3339(),
+            format: backup.format,
+        }))
+        .collect::<color_eyre::Result<Vec<_>>>()?;
+
+    let recorded = backups.clone();
+    ctx.record_outcome(|outcome| outcome.backups = Some(recorded));
+
+    Ok(backups)
+}
+
+// Prints the backups as a table, json output gets them in the result instead.
+pub fn print_list(backups: &[BackupInfo]) {
+    if backups.is_empty() {
+        println!("No backups found");
+        return;
+    }
+
+    for backup in backups {
+        println!("{}\t{}\t{:?}\t{}", backup.name, backup.created, backup.format, HumanBytes(backup.size));
+    }
+}
+
+fn backup_size(backup: &BackupEntry) -> color_eyre::Result<u64> {
+    match backup.format {
+        BackupFormat::Dir | BackupFormat::Zip => dir_size(&backup.path),
+        BackupFormat::Dedup => {
+            let backups_dir = backup.backups_dir()?;
+            let index = PackManifest::load_file(&backup.path)?;
+
+            let mut size = 0;
+            for hash in index.hashes.values() {
+                size += object_path(backups_dir, hash).metadata()?.len();
+            }
+
+            Ok(size)
+        }
+    }
+}
+
+// Unpacks the files of a backup into `staging_dir` unless they are already stored as a plain directory.
+async fn materialize_backup(backup: &BackupEntry, staging_dir: &Path) -> color_eyre::Result<PathBuf> {
+    match backup.format {
+        BackupFormat::Dir => Ok(backup.path.clone()),
+        BackupFormat::Zip => {
+            let mut archive = zip::ZipArchive::new(File::open(&backup.path)?)?;
+            archive.extract(staging_dir)?;
+
+            Ok(staging_dir.to_path_buf())
+        }
+        BackupFormat::Dedup => {
+            let backups_dir = backup.backups_dir()?;
+            let index = PackManifest::load_file(&backup.path)?;
+
+            for (rel_file, hash) in index.hashes {
+                let dst = staging_dir.join(rel_file);
+                ensure_parent(&dst)
+                    .await?;
+                copy(object_path(backups_dir, &hash), dst)
+                    .await?;
+            }
+
+            Ok(staging_dir.to_path_buf())
+        }
+    }
+}
+
+async fn collect_garbage(target_dir: &Path) -> color_eyre::Result<()> {
+    let backups_dir = backups_dir(target_dir);
+    let objects_dir = objects_dir(&backups_dir);
+    if !objects_dir.is_dir() {
+        return Ok(());
+    }
+
+    let mut referenced = HashSet::new();
+    for backup in list_backups(target_dir)? {
+        if backup.format == BackupFormat::Dedup {
+            let index = PackManifest::load_file(&backup.path)?;
+            referenced.extend(index.hashes.into_values());
+        }
+    }
+
+    let mut removed = 0;
+    for entry in WalkDir::new(&objects_dir) {
+        let entry = entry?;
+        if !entry.path().is_file() {
+            continue;
+        }
+
+        if !referenced.contains(entry.file_name().to_string_lossy().as_ref()) {
+            remove_file(entry.path())
+                .await?;
+            removed += 1;
+        }
+    }
+
+    if removed > 0 {
+        info!("Removed {removed} unreferenced objects");
+    }
+
+    Ok(())
+}
+
+pub async fn handle_restore<T: AsRef<Path>>(
+    ctx: &Context,
+    target_dir: T,
+    name: String,
+    backup: Option<BackupFormat>,
+) -> color_eyre::Result<()> {
+    let target_dir = target_dir.as_ref();
+    let backups_dir = backups_dir(target_dir);
+
+    let Some(entry) = list_backups(target_dir)?.into_iter().find(|entry| entry.name == name) else {
+        return Err(BackupError::NotFound(name))?;
+    };
+
+    if ctx.is_dry_run() {
+        check_manifest(ctx, target_dir, backup)
+            .await?;
+        ctx.plan.change(format!("Restore {name} into {:?}", target_dir));
+        return Ok(());
+    }
+
+    let target_mcsi_dir = target_dir
+        .join(".mcsi");
+    let staging_dir = target_mcsi_dir
+        .join("restore");
+    if staging_dir.is_dir() {
+        remove_dir_all(&staging_dir)
+            .await?;
+    }
+
+    let backup_dir = materialize_backup(&entry, &staging_dir)
+        .await?;
+
+    // The files may have changed since they were installed, so hash what is actually in the backup.
+    let mut builder = PackManifest::builder()
+        .with_files_from_dir(&backup_dir)?;
+    if let Ok(PackManifest { source: Some(source), loader, .. }) = PackManifest::load_file(backup_manifest_path(&backups_dir, &name)) {
+        builder = builder.with_source(source)
+            .with_loader(loader);
+    }
+    let manifest = builder.finish();
+
+    check_manifest(ctx, target_dir, backup)
+        .await?;
+
+    info!("Restoring {name}...");
+    recursive_copy_to_dir(ctx, &backup_dir, target_dir)
+        .await?;
+
+    manifest.save_to(&target_mcsi_dir)?;
+
+    if staging_dir.is_dir() {
+        remove_dir_all(&staging_dir)
+            .await?;
+    }
+
+    info!("Restored {name}!");
+    Ok(())
+}
+
+pub async fn handle_prune<T: AsRef<Path>>(
+    ctx: &Context,
+    target_dir: T,
+    keep: Option<usize>,
+    older_than: Option<String>,
+) -> color_eyre::Result<()> {
+    let older_than = older_than
+        .map(|age| parse_age(&age))
+        .transpose()?;
+
+    let target_dir = target_dir.as_ref();
+    let backups_dir = backups_dir(target_dir);
+    let backups = list_backups(target_dir)?;
+    let expired = select_expired(&backups, keep, older_than, Utc::now());
+
+    if ctx.is_dry_run() {
+        for backup in &expired {
+            ctx.plan.change(format!("Delete {}", backup.name));
+        }
+        return Ok(());
+    }
+
+    for backup in &expired {
+        info!("Removing {}...", backup.name);
+        if backup.format == BackupFormat::Dir {
+            remove_dir_all(&backup.path)
+                .await?;
+        } else {
+            remove_file(&backup.path)
+                .await?;
+        }
+
+        let manifest_path = backup_manifest_path(&backups_dir, &backup.name);
+        if manifest_path.is_file() {
+            remove_file(manifest_path)
+                .await?;
+        }
+    }
+
+    collect_garbage(target_dir)
+        .await?;
+
+    info!("Removed {} of {} backups", expired.len(), backups.len());
+    Ok(())
+}
+
+// Picks the backups that fall outside the newest `keep` and are older than `older_than`.
+fn select_expired(
+    backups: &[BackupEntry],
+    keep: Option<usize>,
+    older_than: Option<Duration>,
+    now: DateTime<Utc>,
+) -> Vec<BackupEntry> {
+    backups.iter()
+        .enumerate()
+        .filter(|(i, _)| keep.is_none_or(|keep| *i >= keep))
+        .filter(|(_, backup)| older_than.is_none_or(|age| now - backup.created > age))
+        .map(|(_, backup)| backup.clone())
+        .collect()
+}
+
+// Parses ages like `30m`, `12h`, `7d` or `2w`.
+fn parse_age(age: &str) -> Result<Duration, BackupError> {
+    let invalid = || BackupError::InvalidAge(age.to_string());
+
+    let unit_start = age.find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let (amount, unit) = age.split_at(unit_start);
+    let amount: i64 = amount.parse().map_err(|_| invalid())?;
+
+    match unit {
+        "s" => Ok(Duration::seconds(amount)),
+        "m" => Ok(Duration::minutes(amount)),
+        "h" => Ok(Duration::hours(amount)),
+        "d" => Ok(Duration::days(amount)),
+        "w" => Ok(Duration::weeks(amount)),
+        _ => Err(invalid()),
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum BackupError {
+    #[error("No backup named `{0}` exists")]
+    NotFound(String),
+    #[error("`{0}` is not a valid age, expected something like `30m`, `12h`, `7d` or `2w`")]
+    InvalidAge(String),
+    #[error("Backup `{0}` is not inside a backups dir")]
+    NoBackupsDir(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, read_to_string, remove_dir_all, write};
+    use std::path::PathBuf;
+    use chrono::{Duration, TimeZone, Utc};
+    use walkdir::WalkDir;
+    use crate::modpack::backup::{BackupEntry, BackupFormat, backup_name, backups_dir, create_backup, handle_restore, list_backups, objects_dir, parse_age, parse_backup_name, select_expired};
+    use crate::context::Context;
+    use crate::modpack::PackManifest;
+
+    fn backup(days_ago: i64) -> BackupEntry {
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 0, 0, 0).unwrap();
+
+        BackupEntry {
+            name: format!("backup-{days_ago}"),
+            path: PathBuf::new(),
+            format: BackupFormat::Dir,
+            created: now - Duration::days(days_ago),
+        }
+    }
+
+    #[test]
+    fn parses_ages() {
+        assert_eq!(parse_age("30m").unwrap(), Duration::minutes(30));
+        assert_eq!(parse_age("7d").unwrap(), Duration::days(7));
+        assert_eq!(parse_age("2w").unwrap(), Duration::weeks(2));
+        assert!(parse_age("7").is_err());
+        assert!(parse_age("d").is_err());
+        assert!(parse_age("7y").is_err());
+    }
+
+    #[test]
+    fn selects_expired_backups() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 0, 0, 0).unwrap();
+        let backups = vec![backup(1), backup(5), backup(10), backup(20)];
+
+        let names = |expired: Vec<BackupEntry>| expired.into_iter().map(|entry| entry.name).collect::<Vec<_>>();
+
+        assert_eq!(names(select_expired(&backups, Some(2), None, now)), vec!["backup-10", "backup-20"]);
+        assert_eq!(names(select_expired(&backups, None, Some(Duration::days(7)), now)), vec!["backup-10", "backup-20"]);
+        assert_eq!(names(select_expired(&backups, Some(3), Some(Duration::days(2)), now)), vec!["backup-20"]);
+    }
+
+    #[test]
+    fn names_backups_uniquely() {
+        let backups_dir = PathBuf::from("./.mcsi-test-dir")
+            .join("tests")
+            .join("backup-names");
+        if backups_dir.is_dir() {
+            remove_dir_all(&backups_dir).unwrap();
+        }
+        create_dir_all(&backups_dir).unwrap();
+
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let first = backup_name(&backups_dir, now);
+        assert_eq!(first, "backup-2024-06-01-120000.000");
+        write(backups_dir.join(format!("{first}.manifest.json")), "{}").unwrap();
+        let second = backup_name(&backups_dir, now);
+        assert_eq!(second, "backup-2024-06-01-120000.000-1");
+
+        assert_eq!(parse_backup_name(&first), Some((now.naive_utc(), 0)));
+        assert_eq!(parse_backup_name(&second), Some((now.naive_utc(), 1)));
+        assert_eq!(parse_backup_name("backup-2024-06-01-120000"), Some((now.naive_utc(), 0)));
+        assert_eq!(parse_backup_name("backup-today"), None);
+    }
+
+    #[tokio::test]
+    async fn dedup_backups_share_objects_and_restore() {
+        let target_dir = PathBuf::from("./.mcsi-test-dir")
+            .join("tests")
+            .join("backup-dedup");
+        if target_dir.is_dir() {
+            remove_dir_all(&target_dir).unwrap();
+        }
+        create_dir_all(target_dir.join("mods")).unwrap();
+
+        write(target_dir.join("mods").join("a.jar"), "a").unwrap();
+        write(target_dir.join("mods").join("b.jar"), "b").unwrap();
+        let manifest = PackManifest::builder()
+            .with_files_from_dir(&target_dir)
+            .unwrap()
+            .finish();
+        let ctx = Context::builder().finish().unwrap();
+        create_backup(&ctx, &target_dir, manifest.clone(), BackupFormat::Dedup).await.unwrap();
+        assert!(!target_dir.join("mods").join("a.jar").exists());
+
+        create_dir_all(target_dir.join("mods")).unwrap();
+        write(target_dir.join("mods").join("a.jar"), "a").unwrap();
+        write(target_dir.join("mods").join("b.jar"), "b2").unwrap();
+        create_backup(&ctx, &target_dir, manifest, BackupFormat::Dedup).await.unwrap();
+
+        let objects = WalkDir::new(objects_dir(&backups_dir(&target_dir)))
+            .into_iter()
+            .filter(|entry| entry.as_ref().unwrap().path().is_file())
+            .count();
+        assert_eq!(objects, 3);
+
+        let backups = list_backups(&target_dir).unwrap();
+        assert_eq!(backups.len(), 2);
+
+        handle_restore(&ctx, &target_dir, backups[1].name.clone(), None).await.unwrap();
+        assert_eq!(read_to_string(target_dir.join("mods").join("b.jar")).unwrap(), "b");
+    }
+}
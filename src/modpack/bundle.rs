@@ -0,0 +1,266 @@
+use std::fs::File;
+use std::path::Path;
+use log::info;
+use thiserror::Error;
+use tokio::fs::{remove_dir_all, remove_file};
+use walkdir::WalkDir;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+use zip::write::SimpleFileOptions;
+use crate::fs_utils::{ensure_dir, file_path_relative_to, sha1_file};
+use crate::lock::{LockError, PackLock, LOCK_FILE};
+use crate::modpack::{check_manifest, post_process, resolve_players, InstallOptions, PackManifest};
+use crate::modpack::pipeline::StagedPack;
+use crate::context::Context;
+
+// Sits at the root of a bundle and describes the staged files it contains.
+const BUNDLE_MANIFEST: &str = "mcsi-bundle.json";
+
+// Writes the staged work dir and its lock into a bundle, ready to be installed without network access.
+pub async fn export_bundle<T: AsRef<Path>>(ctx: &Context, staged: StagedPack, output: T) -> color_eyre::Result<()> {
+    let output = output.as_ref();
+    let work_dir = ctx.work_dir();
+    let lock = ctx.lock.finish(staged.source.clone(), ctx.is_dry_run())?;
+
+    if ctx.is_dry_run() {
+        ctx.plan.change(format!("Write bundle {:?}", output));
+        remove_dir_all(&work_dir)
+            .await?;
+        return Ok(());
+    }
+
+    info!("Writing bundle to {:?}...", output);
+    if let Err(err) = write_bundle(&work_dir, staged, &lock, output) {
+        // Don't leave a partial bundle around that could be mistaken for a complete one
+        if output.is_file() {
+            remove_file(output)
+                .await?;
+        }
+        return Err(err);
+    }
+
+    remove_dir_all(&work_dir)
+        .await?;
+
+    info!("Bundle exported!");
+    Ok(())
+}
+
+fn write_bundle(staged_dir: &Path, staged: StagedPack, lock: &PackLock, output: &Path) -> color_eyre::Result<()> {
+    let manifest = PackManifest::builder()
+        .with_files_from_dir(staged_dir)?
+        .with_source(staged.source)
+        .with_loader(staged.loader)
+        .with_config(staged.config)
+        .finish();
+
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated);
+    let mut zip = ZipWriter::new(File::create(output)?);
+
+    zip.start_file(BUNDLE_MANIFEST, options)?;
+    serde_json::to_writer_pretty(&mut zip, &manifest)?;
+    zip.start_file(LOCK_FILE, options)?;
+    serde_json::to_writer_pretty(&mut zip, lock)?;
+
+    for entry in WalkDir::new(staged_dir) {
+        let entry = entry?;
+        if entry.path().is_dir() {
+            continue;
+        }
+
+        let relative = file_path_relative_to(entry.path(), staged_dir)?;
+        let name = relative.to_string_lossy().replace('\\', "/");
+
+        // Keep start scripts executable
+        #[cfg(unix)]
+        let options = {
+            use std::os::unix::fs::PermissionsExt;
+            options.unix_permissions(entry.metadata()?.permissions().mode())
+        };
+
+        zip.start_file(name, options)?;
+        std::io::copy(&mut File::open(entry.path())?, &mut zip)?;
+    }
+
+    zip.finish()?;
+
+    Ok(())
+}
+
+// Extracts the bundle into `dst_dir` and checks every file against the bundle manifest, returns its lock if it has one.
+fn read_bundle(bundle: &Path, dst_dir: &Path) -> color_eyre::Result<(StagedPack, Option<PackLock>)> {
+    let mut archive = ZipArchive::new(File::open(bundle)?)?;
+    archive.extract(dst_dir)?;
+
+    let manifest_path = dst_dir.join(BUNDLE_MANIFEST);
+    if !manifest_path.is_file() {
+        Err(BundleError::NotABundle(bundle.to_string_lossy().to_string()))?;
+    }
+
+    let manifest = PackManifest::load_file(&manifest_path)?;
+    std::fs::remove_file(manifest_path)?;
+
+    // Bundles exported before they carried a lock have none
+    let lock = match dst_dir.join(LOCK_FILE).is_file() {
+        true => {
+            let lock = PackLock::load_from(dst_dir)?;
+            std::fs::remove_file(dst_dir.join(LOCK_FILE))?;
+            Some(lock)
+        }
+        false => None,
+    };
+
+    for rel_file in &manifest.files {
+        let file = dst_dir.join(rel_file);
+        let intact = file.is_file()
+            && manifest.hashes.get(rel_file).is_none_or(|expected| sha1_file(&file).is_ok_and(|actual| actual == *expected));
+
+        if !intact {
+            Err(BundleError::Corrupted(rel_file.clone()))?;
+        }
+    }
+
+    let source = manifest.source
+        .ok_or(BundleError::NoSource)?;
+
+    let staged = StagedPack {
+        source,
+        loader: manifest.loader,
+        config: manifest.config,
+    };
+
+    Ok((staged, lock))
+}
+
+// With `locked`, the bundle has to hold exactly what the mcsi.lock in the target dir records.
+pub async fn install_bundle<TBundle: AsRef<Path>, TDir: AsRef<Path>>(
+    ctx: &Context,
+    bundle: TBundle,
+    target_dir: TDir,
+    locked: bool,
+    options: &InstallOptions,
+) -> color_eyre::Result<()> {
+    let bundle = bundle.as_ref();
+    let target_dir = target_dir.as_ref();
+
+    ensure_dir(ctx.staging_dir())?;
+    let work_dir = ctx.work_dir();
+    if work_dir.is_dir() {
+        remove_dir_all(&work_dir)
+            .await?;
+    }
+
+    let target_lock = match locked {
+        true => Some(PackLock::load_from(target_dir)?),
+        false => None,
+    };
+
+    info!("Extracting bundle {:?}...", bundle);
+    let read = read_bundle(bundle, &work_dir)
+        .and_then(|(staged, lock)| {
+            if let Some(target_lock) = &target_lock {
+                let lock = lock.as_ref()
+                    .ok_or_else(|| BundleError::NoLock(bundle.to_string_lossy().to_string()))?;
+                if lock.source != target_lock.source {
+                    Err(LockError::SourceChanged)?;
+                }
+                if *lock != *target_lock {
+                    Err(BundleError::LockMismatch)?;
+                }
+            }
+
+            Ok((staged, lock))
+        });
+    let (staged, lock) = match read {
+        Ok(read) => read,
+        Err(err) => {
+            remove_dir_all(&work_dir)
+                .await?;
+            return Err(err);
+        }
+    };
+
+    // Only touch the current install once we know the bundle is intact and the players are found
+    let players = resolve_players(ctx, &[target_dir, &work_dir], options)
+        .await?;
+    check_manifest(ctx, target_dir, options.backup)
+        .await?;
+    post_process(ctx, target_dir, staged, &players, options)
+        .await?;
+    if let Some(lock) = lock {
+        lock.save_to(ctx, target_dir)?;
+    }
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum BundleError {
+    #[error("`{0}` is not a bundle, it has no bundle manifest")]
+    NotABundle(String),
+    #[error("Bundle manifest doesn't record where the pack came from")]
+    NoSource,
+    #[error("`{0}` is missing or corrupted in the bundle")]
+    Corrupted(String),
+    #[error("`{0}` has no {LOCK_FILE}, export it again to install it with --locked")]
+    NoLock(String),
+    #[error("The bundle doesn't hold the downloads or mod loader {LOCK_FILE} records")]
+    LockMismatch,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs::{create_dir_all, read_to_string, remove_dir_all, write};
+    use std::path::PathBuf;
+    use crate::lock::PackLock;
+    use crate::modloader::{LoaderInstall, ModLoader};
+    use crate::modpack::bundle::{read_bundle, write_bundle};
+    use crate::modpack::pipeline::StagedPack;
+    use crate::modpack::PackSource;
+
+    #[test]
+    fn bundle_round_trip() {
+        let dir = PathBuf::from("./.mcsi-test-dir")
+            .join("tests")
+            .join("bundle");
+        if dir.is_dir() {
+            remove_dir_all(&dir).unwrap();
+        }
+        let staged = dir.join("staged");
+        create_dir_all(staged.join("mods")).unwrap();
+        create_dir_all(staged.join("libraries").join("net")).unwrap();
+        write(staged.join("mods").join("a.jar"), "a").unwrap();
+        write(staged.join("libraries").join("net").join("lib.jar"), "lib").unwrap();
+        write(staged.join("mc-start.sh"), "#!/usr/bin/env sh").unwrap();
+
+        let source = PackSource::Ftb {
+            pack_id: 1,
+            version_id: 2,
+        };
+        let loader = LoaderInstall {
+            mod_loader: ModLoader::Fabric { version: String::from("0.16.9") },
+            launch_args: vec![String::from("-jar"), String::from("server.jar")],
+            files: vec![String::from("server.jar")],
+            java_version: 21,
+            installer_log: String::new(),
+        };
+        let bundle = dir.join("bundle.zip");
+        let lock = PackLock {
+            source: source.clone(),
+            mod_loader: Some(loader.mod_loader.clone()),
+            downloads: BTreeMap::from([(String::from("https://example.com/a.jar"), String::from("86f7e437faa5a7fce15d1ddcb9eaeaea377667b8"))]),
+        };
+        write_bundle(&staged, StagedPack { source: source.clone(), loader: Some(loader.clone()), config: None }, &lock, &bundle).unwrap();
+
+        let extracted = dir.join("extracted");
+        let (read, read_lock) = read_bundle(&bundle, &extracted).unwrap();
+        assert_eq!(read_lock, Some(lock));
+        assert_eq!(read.source, source);
+        assert_eq!(read.loader.unwrap().launch_args, loader.launch_args);
+        assert_eq!(read_to_string(extracted.join("mods").join("a.jar")).unwrap(), "a");
+        assert_eq!(read_to_string(extracted.join("libraries").join("net").join("lib.jar")).unwrap(), "lib");
+        assert!(!extracted.join("mcsi-bundle.json").exists());
+        assert!(!extracted.join("mcsi.lock").exists());
+    }
+}
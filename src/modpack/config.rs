@@ -0,0 +1,389 @@
+use std::fs::{read_dir, read_to_string, remove_file};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use log::info;
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+use walkdir::WalkDir;
+use crate::fs_utils::{download_file_with_hash, ensure_dir, file_path_relative_to, recursive_copy_to_dir, sha1_file};
+use crate::context::Context;
+use crate::modloader::ModLoader;
+use crate::modpack::flame::FlameSource;
+use crate::modpack::ftb::{FtbSource, IdOrSearch};
+use crate::modpack::{check_manifest, pipeline, post_process, refresh, resolve_players, InstallOptions, PackManifest};
+use crate::modpack::jvm::JvmConfig;
+use crate::modpack::server_files::ServerSettings;
+use crate::modpack::pipeline::ModpackSource;
+use crate::version::{McVersion, McVersionRange};
+
+pub const CONFIG_FILE: &str = "mcsi.toml";
+
+// Declarative description of a server, converged to by `apply`.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    pub source: SourceConfig,
+    // Replaces the mod loader the pack ships with, e.g. `forge-47.2.0`
+    pub loader: Option<String>,
+    // Minecraft version of the loader override, required for Forge and Fabric
+    pub mc_version: Option<String>,
+    #[serde(default)]
+    pub mods: ModsConfig,
+    #[serde(default)]
+    pub jvm: JvmConfig,
+    #[serde(default)]
+    pub server: ServerSettings,
+    // Directory of files copied over the pack, relative to the config file
+    pub overrides: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum SourceConfig {
+    Flame {
+        project_id: u64,
+        // A file id, a file name or `latest` to follow the newest release
+        #[serde(default = "latest")]
+        version: String,
+        #[serde(default)]
+        skip_server_pack: bool,
+    },
+    Ftb {
+        id: Option<String>,
+        #[serde(default)]
+        search_terms: Vec<String>,
+        mc_version: Option<String>,
+        // A version id or `latest` to follow the newest release
+        #[serde(default = "latest")]
+        version: String,
+    },
+}
+
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ModsConfig {
+    #[serde(default)]
+    pub extra: Vec<ExtraMod>,
+    // Mods whose file name contains any of these are removed from the pack
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ExtraMod {
+    pub url: String,
+    pub sha1: Option<String>,
+    // Defaults to the last segment of the url
+    pub file_name: Option<String>,
+}
+
+fn latest() -> String {
+    String::from("latest")
+}
+
+impl ServerConfig {
+    pub fn load_file<T: AsRef<Path>>(file_path: T) -> color_eyre::Result<Self> {
+        let file_path = file_path.as_ref();
+        if !file_path.is_file() {
+            return Err(ConfigError::ConfigNotFound(file_path.to_string_lossy().to_string()))?;
+        }
+
+        let contents = read_to_string(file_path)?;
+        let config = toml::from_str(&contents)?;
+
+        Ok(config)
+    }
+
+    fn mod_loader(&self) -> color_eyre::Result<Option<(ModLoader, Option<McVersion>)>> {
+        let Some(loader) = &self.loader else { return Ok(None) };
+
+        let mod_loader = ModLoader::from_str(loader)?;
+        let mc_version = match &self.mc_version {
+            Some(mc_version) => Some(McVersion::from_str(mc_version)?),
+            None => None,
+        };
+
+        Ok(Some((mod_loader, mc_version)))
+    }
+}
+
+pub async fn handle_apply<TDir: AsRef<Path>, TConfig: AsRef<Path>>(
+    ctx: &Context,
+    target_dir: TDir,
+    config_file: Option<TConfig>,
+    api_key: Option<String>,
+    options: &InstallOptions,
+) -> color_eyre::Result<()> {
+    let target_dir = target_dir.as_ref();
+    let config_file = match config_file {
+        Some(config_file) => config_file.as_ref().to_path_buf(),
+        None => target_dir.join(CONFIG_FILE),
+    };
+
+    let config = ServerConfig::load_file(&config_file)?;
+    info!("Applying {:?}...", config_file);
+
+    // The config wins over the command line
+    let options = InstallOptions {
+        jvm: options.jvm.clone().merge(config.jvm.clone()),
+        server: options.server.clone().merge(config.server.clone()),
+        ..options.clone()
+    };
+
+    let config_dir = config_file.parent()
+        .unwrap_or(Path::new("."));
+    let config_sha1 = config_sha1(&config_file, &config, config_dir)?;
+
+    let config = AppliedConfig { config: &config, dir: config_dir, sha1: config_sha1 };
+    match config.config.source.clone() {
+        SourceConfig::Flame { project_id, version, skip_server_pack } => {
+            let Some(api_key) = api_key else { return Err(ConfigError::MissingApiKey)? };
+
+            let source = FlameSource::new(ctx, &api_key, project_id, version, skip_server_pack)?;
+            converge(ctx, source, target_dir, config, &options)
+                .await
+        }
+        SourceConfig::Ftb { id, search_terms, mc_version, version } => {
+            let args = match id {
+                Some(id) => IdOrSearch::Id(id),
+                None if !search_terms.is_empty() => IdOrSearch::Search {
+                    terms: search_terms,
+                    mc_version: mc_version.as_deref().map(McVersionRange::from_str).transpose()?,
+                },
+                None => return Err(ConfigError::NoFtbPack)?,
+            };
+
+            converge(ctx, FtbSource::new(ctx, args, version), target_dir, config, &options)
+                .await
+        }
+    }
+}
+
+struct AppliedConfig<'a> {
+    config: &'a ServerConfig,
+    // Where paths in the config are relative to
+    dir: &'a Path,
+    sha1: String,
+}
+
+// Reinstalls the pack only when the source resolves to another version or the config changed.
+async fn converge<S: ModpackSource>(
+    ctx: &Context,
+    mut source: S,
+    target_dir: &Path,
+    config: AppliedConfig<'_>,
+    options: &InstallOptions,
+) -> color_eyre::Result<()> {
+    let _enforced = ctx.lock.enforce(options.locked.clone())?;
+
+    pipeline::resolve(ctx, &mut source)
+        .await?;
+    let requested = source.pack_source()?;
+    let installed = PackManifest::load_from(target_dir.join(".mcsi")).ok();
+
+    if let Some(installed) = installed
+        && installed.source.as_ref() == Some(&requested)
+        && installed.config.as_deref() == Some(config.sha1.as_str())
+    {
+        info!("The installed pack and config are up to date");
+        ctx.plan.resolved(format!("Pack: {requested:?} is already installed"));
+        return refresh(ctx, target_dir, options)
+            .await;
+    }
+
+    let mut staged = pipeline::stage_resolved(ctx, &mut source, config.config.mod_loader()?, &options.jvm)
+        .await?;
+    customize(ctx, config.config, config.dir, &ctx.work_dir())
+        .await?;
+    staged.config = Some(config.sha1);
+
+    let lock = ctx.lock.finish(staged.source.clone(), ctx.is_dry_run())?;
+
+    // Only touch the current install once everything is staged, players included
+    let players = resolve_players(ctx, &[target_dir, &ctx.work_dir()], options)
+        .await?;
+    check_manifest(ctx, target_dir, options.backup)
+        .await?;
+    post_process(ctx, target_dir, staged, &players, options)
+        .await?;
+    lock.save_to(ctx, target_dir)?;
+
+    Ok(())
+}
+
+// Hashes the config file together with the overrides it copies, so editing either counts as a change.
+fn config_sha1(config_file: &Path, config: &ServerConfig, config_dir: &Path) -> color_eyre::Result<String> {
+    let mut hasher = Sha1::new();
+    hasher.update(std::fs::read(config_file)?);
+
+    if let Some(overrides) = &config.overrides {
+        let overrides = config_dir.join(overrides);
+        for entry in WalkDir::new(&overrides).sort_by_file_name() {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            let rel_path = file_path_relative_to(entry.path(), &overrides)?;
+            hasher.update(rel_path.to_string_lossy().as_bytes());
+            hasher.update(sha1_file(entry.path())?);
+        }
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+async fn customize(ctx: &Context, config: &ServerConfig, config_dir: &Path, staged_dir: &Path) -> color_eyre::Result<()> {
+    let mods_dir = staged_dir.join("mods");
+    exclude_mods(&mods_dir, &config.mods.exclude)?;
+
+    if !config.mods.extra.is_empty() {
+        ensure_dir(&mods_dir)?;
+    }
+    for extra in &config.mods.extra {
+        let file_name = match &extra.file_name {
+            Some(file_name) => file_name.clone(),
+            None => url_file_name(&extra.url)?,
+        };
+
+        download_file_with_hash(ctx, &extra.url, mods_dir.join(file_name), extra.sha1.as_deref())
+            .await?;
+    }
+
+    if let Some(overrides) = &config.overrides {
+        let overrides = config_dir.join(overrides);
+        if !overrides.is_dir() {
+            return Err(ConfigError::OverridesNotFound(overrides.to_string_lossy().to_string()))?;
+        }
+
+        info!("Copying overrides from {:?}...", overrides);
+        recursive_copy_to_dir(ctx, &overrides, staged_dir)
+            .await?;
+    }
+
+    Ok(())
+}
+
+fn exclude_mods(mods_dir: &Path, exclude: &[String]) -> color_eyre::Result<()> {
+    if exclude.is_empty() || !mods_dir.is_dir() {
+        return Ok(());
+    }
+
+    for entry in read_dir(mods_dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name()
+            .to_string_lossy()
+            .to_lowercase();
+
+        if exclude.iter().any(|pattern| file_name.contains(&pattern.to_lowercase())) {
+            info!("Excluding {file_name}");
+            remove_file(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+fn url_file_name(url: &str) -> color_eyre::Result<String> {
+    let segment = url.split(['?', '#'])
+        .next()
+        .and_then(|path| path.rsplit('/').next())
+        .filter(|segment| !segment.is_empty())
+        .ok_or_else(|| ConfigError::NoFileName(url.to_string()))?;
+
+    Ok(urlencoding::decode(segment)?.into_owned())
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("No config found at `{0}`")]
+    ConfigNotFound(String),
+    #[error("An api key is required to install CurseForge packs")]
+    MissingApiKey,
+    #[error("An ftb source needs either an `id` or `search_terms`")]
+    NoFtbPack,
+    #[error("Overrides dir `{0}` doesn't exist")]
+    OverridesNotFound(String),
+    #[error("Can't tell the file name of `{0}`, set `file_name`")]
+    NoFileName(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::path::PathBuf;
+    use crate::modpack::config::{config_sha1, exclude_mods, url_file_name, ServerConfig, SourceConfig};
+
+    #[test]
+    fn parses_config() {
+        let config: ServerConfig = toml::from_str(r#"
+            loader = "forge-47.2.0"
+            mc_version = "1.20.1"
+            overrides = "overrides"
+
+            [source]
+            type = "flame"
+            project_id = 351508
+
+            [mods]
+            exclude = ["optifine"]
+            extra = [{ url = "https://example.com/mods/Extra%20Mod.jar" }]
+
+            [jvm]
+            max_memory = "8G"
+            args = ["-XX:+UseG1GC"]
+
+            [server]
+            accept_eula = true
+            properties = { server-port = 25570 }
+        "#).unwrap();
+
+        assert!(matches!(config.source, SourceConfig::Flame { project_id: 351508, ref version, skip_server_pack: false } if version == "latest"));
+        assert_eq!(config.server.properties["server-port"], "25570");
+        assert_eq!(config.jvm.user_jvm_args(&[]).unwrap(), "-Xms128M\n-Xmx8192M\n-XX:+UseG1GC\n");
+        assert_eq!(url_file_name(&config.mods.extra[0].url).unwrap(), "Extra Mod.jar");
+        assert!(toml::from_str::<ServerConfig>("[source]\ntype = \"ftb\"\nunknown = 1").is_err());
+    }
+
+    #[test]
+    fn excludes_mods_by_name() {
+        let mods_dir = PathBuf::from("./.mcsi-test-dir")
+            .join("tests")
+            .join("config-exclude")
+            .join("mods");
+        if mods_dir.is_dir() {
+            remove_dir_all(&mods_dir).unwrap();
+        }
+        create_dir_all(&mods_dir).unwrap();
+        write(mods_dir.join("OptiFine_1.20.1.jar"), "").unwrap();
+        write(mods_dir.join("jei-1.20.1.jar"), "").unwrap();
+
+        exclude_mods(&mods_dir, &[String::from("optifine")]).unwrap();
+
+        assert!(!mods_dir.join("OptiFine_1.20.1.jar").exists());
+        assert!(mods_dir.join("jei-1.20.1.jar").is_file());
+    }
+
+    #[test]
+    fn hashes_config_with_overrides() {
+        let dir = PathBuf::from("./.mcsi-test-dir")
+            .join("tests")
+            .join("config-sha1");
+        if dir.is_dir() {
+            remove_dir_all(&dir).unwrap();
+        }
+        create_dir_all(dir.join("overrides").join("config")).unwrap();
+        let config_file = dir.join("mcsi.toml");
+        write(&config_file, "overrides = \"overrides\"\n[source]\ntype = \"ftb\"\nid = \"1\"").unwrap();
+        write(dir.join("overrides").join("config").join("a.toml"), "a = 1").unwrap();
+        let config = ServerConfig::load_file(&config_file).unwrap();
+
+        let before = config_sha1(&config_file, &config, &dir).unwrap();
+        assert_eq!(config_sha1(&config_file, &config, &dir).unwrap(), before);
+
+        write(dir.join("overrides").join("config").join("a.toml"), "a = 2").unwrap();
+        assert_ne!(config_sha1(&config_file, &config, &dir).unwrap(), before);
+    }
+}
@@ -0,0 +1,91 @@
+use std::path::Path;
+use std::process::Command;
+use log::info;
+use crate::fs_utils::ensure_dir;
+use crate::modpack::flame::FlameSource;
+use crate::modpack::ftb::{FtbSource, IdOrSearch};
+use crate::modpack::service::{ServiceError, START_SCRIPT};
+use crate::modpack::{pipeline, refresh, InstallOptions};
+use crate::context::Context;
+
+#[derive(Clone, Debug)]
+pub enum ContainerPack {
+    Flame {
+        api_key: String,
+        project_id: u64,
+        version: String,
+        skip_server_pack: bool,
+    },
+    Ftb {
+        id: String,
+        version: String,
+    },
+}
+
+// Brings `target_dir` to the requested pack, then replaces this process with the server.
+// Without a pack, whatever is installed is started as is.
+pub async fn handle_container_entrypoint<T: AsRef<Path>>(
+    ctx: &Context,
+    target_dir: T,
+    pack: Option<ContainerPack>,
+    options: &InstallOptions,
+) -> color_eyre::Result<()> {
+    let target_dir = target_dir.as_ref();
+    ensure_dir(target_dir)?;
+
+    let installed = match pack {
+        Some(ContainerPack::Flame { api_key, project_id, version, skip_server_pack }) => {
+            let source = FlameSource::new(ctx, &api_key, project_id, version, skip_server_pack)?;
+            pipeline::install_if_changed(ctx, source, target_dir, options)
+                .await?
+        }
+        Some(ContainerPack::Ftb { id, version }) => {
+            let source = FtbSource::new(ctx, IdOrSearch::Id(id), version);
+            pipeline::install_if_changed(ctx, source, target_dir, options)
+                .await?
+        }
+        None => false,
+    };
+
+    // Installing already wrote them, but the settings may have changed since
+    if !installed {
+        refresh(ctx, target_dir, options)
+            .await?;
+    }
+
+    if ctx.is_dry_run() {
+        ctx.plan.command(format!("sh ./{START_SCRIPT} (in {:?})", target_dir));
+        return Ok(());
+    }
+
+    if !target_dir.join(START_SCRIPT).is_file() {
+        return Err(ServiceError::NoStartScript(target_dir.to_string_lossy().to_string()))?;
+    }
+
+    // A successful exec never returns, so the result is only sent when starting the server fails
+    info!("Starting the server...");
+    exec_start_script(target_dir)
+}
+
+// The start script ends in `exec java`, so the JVM takes over this process and receives the container's signals.
+#[cfg(unix)]
+fn exec_start_script(target_dir: &Path) -> color_eyre::Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    let err = Command::new("sh")
+        .arg(START_SCRIPT)
+        .current_dir(target_dir)
+        .exec();
+
+    Err(err)?
+}
+
+#[cfg(not(unix))]
+fn exec_start_script(target_dir: &Path) -> color_eyre::Result<()> {
+    let status = Command::new("sh")
+        .arg(START_SCRIPT)
+        .current_dir(target_dir)
+        .status()?;
+
+    std::process::exit(status.code().unwrap_or(1));
+}
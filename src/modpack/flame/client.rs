@@ -1,5 +1,8 @@
-use reqwest::Client;
+use reqwest::{Client, RequestBuilder, StatusCode};
+use reqwest::header::{ACCEPT, HeaderMap};
 use serde::de::DeserializeOwned;
+use crate::context::Context;
+use crate::modpack::flame::FlameError;
 use crate::modpack::flame::model::{FileEntry, FilesList};
 
 use super::model::ModInfo;
@@ -7,53 +10,68 @@ use super::model::ModInfo;
 #[derive(Clone, Debug)]
 pub struct FlameClient {
     client: Client,
+    headers: HeaderMap,
+    base_url: String,
 }
 
 impl FlameClient {
-    pub fn new(client: Client) -> Self {
-        FlameClient {
-            client,
-        }
+    pub fn new(ctx: &Context, api_key: &str) -> color_eyre::Result<Self> {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, "application/json".parse()?);
+        headers.insert("x-api-key", api_key.parse()?);
+
+        Ok(FlameClient {
+            client: ctx.client().clone(),
+            headers,
+            base_url: ctx.endpoints().curseforge_api.clone(),
+        })
     }
 
-    pub async fn get_mod_info(&mut self, project_id: u64) -> color_eyre::Result<ModInfo> {
-        let url = format!("https://api.curseforge.com/v1/mods/{0}", project_id);
-        let resp = self.client.get(url)
-            .send().await?
-            .text().await?;
+    fn get(&self, path: String) -> RequestBuilder {
+        self.client.get(format!("{0}{path}", self.base_url))
+            .headers(self.headers.clone())
+    }
 
-        let info: ModInfo = data_root(resp)?;
+    // Sends the request and returns the `data` root of the response.
+    async fn fetch<T: DeserializeOwned>(&self, path: String) -> color_eyre::Result<T> {
+        let resp = self.get(path)
+            .send()
+            .await?;
 
-        Ok(info)
-    }
+        let status = resp.status();
+        let body = resp.text()
+            .await?;
 
-    pub async fn get_file_info(&mut self, project_id: u64, file_id: u64) -> color_eyre::Result<FileEntry> {
-        let url = format!("https://api.curseforge.com/v1/mods/{0}/files/{1}", project_id, file_id);
-        let resp = self.client.get(url)
-            .send().await?
-            .text().await?;
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(FlameError::InvalidApiKey(body))?,
+            status if !status.is_success() => Err(FlameError::Api { status, body })?,
+            _ => data_root(body),
+        }
+    }
 
-        let file_info: FileEntry = data_root(resp)?;
+    pub async fn get_mod_info(&mut self, project_id: u64) -> color_eyre::Result<ModInfo> {
+        self.fetch(format!("/v1/mods/{0}", project_id))
+            .await
+    }
 
-        Ok(file_info)
+    pub async fn get_file_info(&mut self, project_id: u64, file_id: u64) -> color_eyre::Result<FileEntry> {
+        self.fetch(format!("/v1/mods/{0}/files/{1}", project_id, file_id))
+            .await
     }
 
     // TODO: pagination support.
     pub async fn get_files(&mut self, project_id: u64, _page: u32) -> color_eyre::Result<FilesList> {
-        let url = format!("https://api.curseforge.com/v1/mods/{0}/files", project_id);
-        let resp = self.client.get(url)
-            .send().await?
-            .text().await?;
-
-        let files: FilesList = data_root(resp)?;
-
-        Ok(files)
+        self.fetch(format!("/v1/mods/{0}/files", project_id))
+            .await
     }
 }
 
 fn data_root<T: DeserializeOwned>(resp: String) -> color_eyre::Result<T> {
-    let json: serde_json::Value = serde_json::from_str(resp.as_str())?;
-    let root: T = serde_json::from_value(json.get("data").expect("root data").clone())?;
+    let mut json: serde_json::Value = serde_json::from_str(resp.as_str())?;
+    let Some(data) = json.get_mut("data") else {
+        return Err(FlameError::UnexpectedResponse(resp))?;
+    };
+    let root: T = serde_json::from_value(data.take())?;
 
     Ok(root)
-}
\ No newline at end of file
+}
@@ -1,16 +1,18 @@
 use std::fs::File;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use std::str::FromStr;
-use log::{debug, error, info};
-use reqwest::{Client, header::HeaderMap};
+use log::{debug, info};
+use reqwest::StatusCode;
 use thiserror::Error;
 use tokio::fs::{create_dir, create_dir_all, remove_dir_all, remove_file};
-use crate::fs_utils::{download_file, get_closest_common_parent, recursive_copy_to_dir, work_dir};
-use crate::modloader::fabric::install_fabric;
-use crate::modloader::forge::install_forge;
+use crate::context::Context;
+use crate::lock::{LockError, PackLock};
+use crate::fs_utils::{download_file_with_hash, recursive_copy_to_dir, resolve_file_with_hash};
 use crate::modloader::ModLoader;
-use crate::modloader::neoforge::install_neoforge;
-use crate::modpack::{check_manifest, ensure_server_start_script, post_process};
+use crate::modpack::jvm::JvmConfig;
+use crate::modpack::pipeline::{ModpackSource, StagedPack};
+use crate::modpack::server_pack::ServerPack;
+use crate::modpack::{pipeline, InstallOptions, PackSource};
 use crate::modpack::flame::model::{ClientManifest, FileEntry, ManifestFileEntry};
 use crate::version::McVersion;
 
@@ -19,8 +21,11 @@ use self::client::FlameClient;
 mod model;
 mod client;
 
+const CLIENT_MANIFEST: &str = "manifest.json";
+
+// Installs CurseForge packs, preferring the server pack when there is one.
 #[derive(Clone, Debug)]
-struct Context {
+pub struct FlameSource {
     client: FlameClient,
     project_id: u64,
     version: String,
@@ -29,73 +34,142 @@ struct Context {
     mc_version: Option<McVersion>,
     mod_loader: Option<ModLoader>,
     mod_list: Option<Vec<ManifestFileEntry>>,
-    target_dir: PathBuf,
     skip_server_pack: bool,
 }
 
+impl FlameSource {
+    // `version` is a file id, a file name or `latest`.
+    pub fn new(
+        ctx: &Context,
+        api_key: &str,
+        project_id: u64,
+        version: String,
+        skip_server_pack: bool,
+    ) -> color_eyre::Result<Self> {
+        debug!("api_key: \'{api_key}\' project_id: \'{project_id}\' version: \'{version}\'");
+
+        Ok(FlameSource {
+            client: FlameClient::new(ctx, api_key)?,
+            project_id,
+            version,
+            main_file: None,
+            parent_file: None,
+            mc_version: None,
+            mod_loader: None,
+            mod_list: None,
+            skip_server_pack,
+        })
+    }
+
+    fn main_file(&self) -> Result<&FileEntry, FlameError> {
+        self.main_file.as_ref()
+            .ok_or(FlameError::NotResolved)
+    }
+}
+
+impl ModpackSource for FlameSource {
+    async fn resolve(&mut self, ctx: &Context) -> color_eyre::Result<()> {
+        resolve_main_file(self).await?;
+        ensure_server_pack(self).await?;
+
+        let main_file = self.main_file()?;
+        let pack_kind = if self.parent_file.is_some() { "server pack" } else { "client pack" };
+        ctx.plan.resolved(format!("CurseForge project {}, file {} `{}` ({pack_kind})", self.project_id, main_file.id, main_file.display_name));
+
+        Ok(())
+    }
+
+    async fn fetch(&mut self, ctx: &Context, work_dir: &Path) -> color_eyre::Result<()> {
+        download_modpack(ctx, self, work_dir)
+            .await
+    }
+
+    fn mod_loader(&self) -> Option<(ModLoader, Option<McVersion>)> {
+        self.mod_loader.clone()
+            .map(|mod_loader| (mod_loader, self.mc_version.clone()))
+    }
+
+    fn pack_source(&self) -> color_eyre::Result<PackSource> {
+        Ok(PackSource::Flame {
+            project_id: self.project_id,
+            file_id: self.main_file()?.id,
+            skip_server_pack: self.skip_server_pack,
+        })
+    }
+}
+
 pub async fn handle_flame<T: AsRef<Path>>(
+    ctx: &Context,
     api_key: String,
     project_id: u64,
     version: String,
     target_dir: T,
     skip_server_pack: bool,
+    options: &InstallOptions,
 ) -> color_eyre::Result<()> {
-    debug!("api_key: \'{api_key}\' project_id: \'{project_id}\' version: \'{version}\'");
-
-    let mut headers = HeaderMap::new();
-    headers.insert("Accept", "application/json".parse()?);
-    headers.insert("x-api-key", api_key.parse()?);
-
-    let client = Client::builder()
-        .default_headers(headers)
-        .build()?;
-
-    let mut ctx = Context {
-        client: FlameClient::new(client),
-        project_id,
-        version,
-        main_file: None,
-        parent_file: None,
-        mc_version: None,
-        mod_loader: None,
-        mod_list: None,
-        target_dir: target_dir.as_ref().to_path_buf(),
-        skip_server_pack
+    let source = FlameSource::new(ctx, &api_key, project_id, version, skip_server_pack)?;
+
+    pipeline::install(ctx, source, target_dir, options)
+        .await
+}
+
+pub async fn handle_flame_locked<T: AsRef<Path>>(
+    ctx: &Context,
+    api_key: String,
+    target_dir: T,
+    options: &InstallOptions,
+) -> color_eyre::Result<()> {
+    let target_dir = target_dir.as_ref();
+
+    let lock = PackLock::load_from(target_dir)?;
+    let PackSource::Flame { project_id, file_id, skip_server_pack } = lock.source else {
+        return Err(LockError::WrongSource)?;
+    };
+    let options = InstallOptions {
+        locked: Some(lock),
+        ..options.clone()
     };
 
-    check_manifest(&ctx.target_dir).await?;
-    setup(&mut ctx).await?;
-    resolve_main_file(&mut ctx).await?;
-    ensure_server_pack(&mut ctx).await?;
-    download_modpack(&mut ctx).await?;
-    ensure_server_start_script(ctx.mod_loader).await?;
-    post_process(&ctx.target_dir).await?;
+    handle_flame(ctx, api_key, project_id, file_id.to_string(), target_dir, skip_server_pack, &options)
+        .await
+}
 
-    Ok(())
+pub async fn stage_flame(
+    ctx: &Context,
+    api_key: String,
+    project_id: u64,
+    version: String,
+    skip_server_pack: bool,
+    jvm: &JvmConfig,
+) -> color_eyre::Result<StagedPack> {
+    let mut source = FlameSource::new(ctx, &api_key, project_id, version, skip_server_pack)?;
+
+    pipeline::stage(ctx, &mut source, jvm)
+        .await
 }
 
-async fn resolve_main_file(ctx: &mut Context) -> color_eyre::Result<()> {
-    if ctx.version.eq_ignore_ascii_case("latest") {
+async fn resolve_main_file(source: &mut FlameSource) -> color_eyre::Result<()> {
+    if source.version.eq_ignore_ascii_case("latest") {
         info!("Version set to \'latest\', determining file id...");
 
-        let info = ctx.client.get_mod_info(ctx.project_id)
+        let info = source.client.get_mod_info(source.project_id)
             .await?;
 
         let file_id = info.main_file_id;
-        let main_file = ctx.client.get_file_info(ctx.project_id, file_id)
+        let main_file = source.client.get_file_info(source.project_id, file_id)
             .await?;
 
-        ctx.main_file = Some(main_file);
+        source.main_file = Some(main_file);
     } else {
-        if let Ok(file_id) = u64::from_str(ctx.version.as_str()) {
+        if let Ok(file_id) = u64::from_str(source.version.as_str()) {
             info!("Version recognized as a file id, validating id...");
 
-            let main_file = ctx.client.get_file_info(ctx.project_id, file_id)
+            let main_file = source.client.get_file_info(source.project_id, file_id)
                 .await;
 
-            if main_file.is_ok() {
-                ctx.main_file = Some(main_file?);
-                info!("file id is: {0}", ctx.main_file.clone().expect("file info must exist").id);
+            if let Ok(main_file) = main_file {
+                info!("file id is: {0}", main_file.id);
+                source.main_file = Some(main_file);
                 return Ok(())
             }
         }
@@ -106,14 +180,14 @@ async fn resolve_main_file(ctx: &mut Context) -> color_eyre::Result<()> {
         let mut total = u32::MAX;
 
         while items < total {
-            let file_list = ctx.client.get_files(ctx.project_id, page)
+            let file_list = source.client.get_files(source.project_id, page)
                 .await?;
 
             let main_file = file_list.files.iter()
-                .find(|file| file.display_name.contains(ctx.version.as_str()));
+                .find(|file| file.display_name.contains(source.version.as_str()));
 
-            if main_file.is_some() {
-                ctx.main_file = Some(main_file.expect("file must exist").clone());
+            if let Some(main_file) = main_file {
+                source.main_file = Some(main_file.clone());
                 break;
             }
 
@@ -123,75 +197,63 @@ async fn resolve_main_file(ctx: &mut Context) -> color_eyre::Result<()> {
         }
     }
 
-    info!("file id is: {0}", ctx.main_file.clone().expect("file info must exist").id);
+    let Some(main_file) = &source.main_file else {
+        return Err(FlameError::FileNotFound {
+            project_id: source.project_id,
+            version: source.version.clone(),
+        })?;
+    };
+    info!("file id is: {0}", main_file.id);
 
     Ok(())
 }
 
-async fn ensure_server_pack(ctx: &mut Context) -> color_eyre::Result<()> {
-    let main_file = ctx.main_file
-        .clone()
-        .expect("main file must exist");
+async fn ensure_server_pack(source: &mut FlameSource) -> color_eyre::Result<()> {
+    let main_file = source.main_file()?
+        .clone();
 
     if main_file.is_server_pack {
         if let Some(parent_id) = main_file.parent_project_file_id {
-            let parent_file = ctx.client.get_file_info(ctx.project_id, parent_id)
+            let parent_file = source.client.get_file_info(source.project_id, parent_id)
                 .await?;
-            ctx.parent_file = Some(parent_file);
+            source.parent_file = Some(parent_file);
         }
 
         return Ok(());
     }
 
-    if main_file.server_pack_file_id.is_none() {
+    let Some(file_id) = main_file.server_pack_file_id else {
         return Ok(());
-    }
+    };
 
-    ctx.parent_file = Some(main_file.clone());
+    source.parent_file = Some(main_file);
 
-    let file_id = main_file.server_pack_file_id
-        .expect("server pack file id can't be none!");
-    let main_file = ctx.client.get_file_info(ctx.project_id, file_id)
+    let main_file = source.client.get_file_info(source.project_id, file_id)
         .await?;
 
-    ctx.main_file = Some(main_file);
+    source.main_file = Some(main_file);
     Ok(())
 }
 
-async fn setup(_ctx: &mut Context) -> color_eyre::Result<()> {
-    let dir = PathBuf::from(".mcsi");
-    if !dir.exists() {
-        std::fs::create_dir(dir)?;
-    }
-
-    Ok(())
-}
+async fn download_modpack(ctx: &Context, source: &mut FlameSource, work_dir: &Path) -> color_eyre::Result<()> {
+    download_client(ctx, source).await?;
+    download_server(ctx, source).await?;
 
-async fn download_modpack(ctx: &mut Context) -> color_eyre::Result<()> {
-    download_client(ctx).await?;
-    download_server(ctx).await?;
+    resolve_mc_info(ctx, source).await?;
 
-    resolve_mc_info(ctx).await?;
-
-    let work_dir = work_dir();
-    if work_dir.exists() {
-        remove_dir_all(&work_dir)
-            .await?;
-    }
-    create_dir(&work_dir)
-        .await?;
-
-    let server_path = PathBuf::from("./.mcsi")
+    let server_path = ctx.staging_dir()
         .join("server");
-    let client_path = PathBuf::from("./.mcsi")
+    let client_path = ctx.staging_dir()
         .join("client");
 
-    if ctx.parent_file.is_some() {
+    if source.parent_file.is_some() && ctx.is_dry_run() {
+        ctx.plan.change(String::from("Copy the server files of the server pack into the work dir"));
+    } else if source.parent_file.is_some() {
         // Extract server pack
-        let server_files = get_closest_common_parent(&server_path)
-            .await?;
+        let server_pack = ServerPack::find(&server_path)?;
+        info!("Found the server files in {:?}", server_pack.root);
 
-        recursive_copy_to_dir(&server_files, work_dir.clone())
+        recursive_copy_to_dir(ctx, &server_pack.root, work_dir)
             .await?;
     } else {
         // Extract client pack
@@ -199,37 +261,37 @@ async fn download_modpack(ctx: &mut Context) -> color_eyre::Result<()> {
             .join("overrides");
 
         if overrides.is_dir() {
-            recursive_copy_to_dir(overrides, work_dir.clone())
+            recursive_copy_to_dir(ctx, overrides, work_dir)
                 .await?;
         }
 
-        let mods_dir = work_dir.clone()
+        let mods_dir = work_dir
             .join("mods");
         if !mods_dir.is_dir() {
             create_dir_all(&mods_dir)
                 .await?;
         }
 
-        let Some(mod_list) = &ctx.mod_list.clone() else { return Err(FlameError::NoModList)? };
+        let Some(mod_list) = &source.mod_list.clone() else { return Err(FlameError::NoModList)? };
         for entry in mod_list {
             if !entry.required {
                 continue;
             }
 
-            let mod_info = ctx.client.get_mod_info(entry.project_id as u64)
+            let mod_info = source.client.get_mod_info(entry.project_id as u64)
                 .await?;
 
             if mod_info.class_id != 6 {
                 continue;
             }
 
-            let info = ctx.client.get_file_info(entry.project_id as u64, entry.file_id as u64)
+            let info = source.client.get_file_info(entry.project_id as u64, entry.file_id as u64)
                 .await?;
 
             let dst = mods_dir.clone()
-                .join(info.file_name);
+                .join(&info.file_name);
 
-            download_file(&info.download_url, dst)
+            download_file_with_hash(ctx, &info.download_url, dst, info.sha1())
                 .await?;
         }
     }
@@ -243,68 +305,48 @@ async fn download_modpack(ctx: &mut Context) -> color_eyre::Result<()> {
             .await?;
     }
 
-    match &ctx.mod_loader.clone().unwrap() {
-        ModLoader::NeoForge { version } => {
-            info!("Detected mod loader: NeoForge, Version: {}", version);
-
-            install_neoforge(version, &work_dir)
-                .await?;
-        }
-        ModLoader::Forge { version } => {
-            info!("Detected mod loader: Forge, Version: {}", version);
-
-            install_forge(ctx.mc_version.clone().unwrap(), version, &work_dir)
-                .await?;
-        }
-        ModLoader::Fabric { version } => {
-            info!("Detected mod loader: Fabric, Version: {}", version);
-
-            install_fabric(ctx.mc_version.clone().unwrap(), version, &work_dir)
-                .await?;
-        }
-        ModLoader::Quilt { .. } => {}
-    }
-
     Ok(())
 }
 
-async fn resolve_mc_info(ctx: &mut Context) -> color_eyre::Result<()> {
-    let client_manifest_path = PathBuf::from("./.mcsi")
+async fn resolve_mc_info(ctx: &Context, source: &mut FlameSource) -> color_eyre::Result<()> {
+    let client_manifest_path = ctx.staging_dir()
         .join("client")
-        .join("manifest.json");
+        .join(CLIENT_MANIFEST);
     let manifest_contents = std::fs::read_to_string(client_manifest_path)?;
     let flame_manifest: ClientManifest = serde_json::from_str(manifest_contents.as_str())?;
 
     let mc_version = McVersion::from_str(&flame_manifest.minecraft.version)?;
-    ctx.mc_version = Some(mc_version);
-
-    let primary_loader = &flame_manifest.minecraft.mod_loaders.iter().find(|loader| loader.primary).unwrap();
+    let primary_loader = flame_manifest.minecraft.mod_loaders.iter()
+        .find(|loader| loader.primary)
+        .ok_or(FlameError::NoPrimaryLoader)?;
     let mod_loader = ModLoader::from_str(&primary_loader.id)?;
-    ctx.mod_loader = Some(mod_loader);
+
+    ctx.plan.resolved(format!("Minecraft {}, {}", mc_version, mod_loader));
+    source.mc_version = Some(mc_version);
+    source.mod_loader = Some(mod_loader);
 
     let mod_list = &flame_manifest.files;
-    ctx.mod_list = Some(mod_list.to_owned());
+    source.mod_list = Some(mod_list.to_owned());
 
     Ok(())
 }
 
 
-async fn download_client(ctx: &mut Context) -> color_eyre::Result<()> {
-    let client_file = if ctx.main_file.clone().is_some_and(|entry| !entry.is_server_pack) {
-        ctx.main_file.clone().unwrap()
-    } else if ctx.parent_file.clone().is_some_and(|entry| !entry.is_server_pack) {
-        ctx.parent_file.clone().unwrap()
-    } else {
-        panic!()
+async fn download_client(ctx: &Context, source: &mut FlameSource) -> color_eyre::Result<()> {
+    let main_file = source.main_file()?;
+    let client_file = match &source.parent_file {
+        _ if !main_file.is_server_pack => main_file.clone(),
+        Some(parent_file) if !parent_file.is_server_pack => parent_file.clone(),
+        _ => return Err(FlameError::NoClientPack(main_file.id))?,
     };
 
-    let file_path = download_file(&client_file.download_url, PathBuf::from("./.mcsi/").join(client_file.file_name))
+    let file_path = resolve_file_with_hash(ctx, &client_file.download_url, ctx.staging_dir().join(&client_file.file_name), client_file.sha1())
         .await?;
     {
         let file = File::open(&file_path)?;
         let mut archive = zip::ZipArchive::new(file)?;
 
-        let client_path = PathBuf::from("./.mcsi")
+        let client_path = ctx.staging_dir()
             .join("client");
         if client_path.exists() {
             remove_dir_all(&client_path)
@@ -312,7 +354,14 @@ async fn download_client(ctx: &mut Context) -> color_eyre::Result<()> {
         }
         create_dir(&client_path)
             .await?;
-        archive.extract(&client_path)?;
+
+        if ctx.is_dry_run() {
+            // The manifest is all it takes to resolve the mod loader and the mods
+            let mut manifest = archive.by_name(CLIENT_MANIFEST)?;
+            std::io::copy(&mut manifest, &mut File::create(client_path.join(CLIENT_MANIFEST))?)?;
+        } else {
+            archive.extract(&client_path)?;
+        }
     }
 
     remove_file(file_path)
@@ -321,24 +370,30 @@ async fn download_client(ctx: &mut Context) -> color_eyre::Result<()> {
     Ok(())
 }
 
-async fn download_server(ctx: &mut Context) -> color_eyre::Result<()> {
-    if ctx.skip_server_pack {
+async fn download_server(ctx: &Context, source: &mut FlameSource) -> color_eyre::Result<()> {
+    if source.skip_server_pack {
         return Ok(());
     }
 
-    let server_pack = if ctx.main_file.clone().is_some_and(|entry| entry.is_server_pack) {
-        ctx.main_file.clone().unwrap()
-    } else {
-        return Ok(())
-    };
+    let server_pack = source.main_file()?;
+    if !server_pack.is_server_pack {
+        return Ok(());
+    }
+    let server_pack = server_pack.clone();
+
+    if ctx.is_dry_run() {
+        ctx.lock.locked_sha1(&server_pack.download_url)?;
+        ctx.plan.download(&server_pack.download_url, &ctx.staging_dir().join(&server_pack.file_name), server_pack.file_length);
+        return Ok(());
+    }
 
-    let file_path = download_file(&server_pack.download_url, PathBuf::from("./.mcsi/").join(server_pack.file_name))
+    let file_path = resolve_file_with_hash(ctx, &server_pack.download_url, ctx.staging_dir().join(&server_pack.file_name), server_pack.sha1())
         .await?;
     {
         let file = File::open(&file_path)?;
         let mut archive = zip::ZipArchive::new(file)?;
 
-        let server_path = PathBuf::from("./.mcsi")
+        let server_path = ctx.staging_dir()
             .join("server");
         if server_path.exists() {
             remove_dir_all(&server_path)
@@ -359,4 +414,24 @@ async fn download_server(ctx: &mut Context) -> color_eyre::Result<()> {
 pub enum FlameError {
     #[error("Client manifest has no mod list!")]
     NoModList,
+    #[error("Client manifest doesn't name a primary mod loader")]
+    NoPrimaryLoader,
+    #[error("No file of project {project_id} matches `{version}`, use `latest`, a file id or part of a file name")]
+    FileNotFound {
+        project_id: u64,
+        version: String,
+    },
+    #[error("File {0} is a server pack without a client pack, which is needed to find its mod loader")]
+    NoClientPack(u64),
+    #[error("The pack has to be resolved first")]
+    NotResolved,
+    #[error("CurseForge rejected the api key, check that it is valid: {0}")]
+    InvalidApiKey(String),
+    #[error("CurseForge api request failed with status {status}: {body}")]
+    Api {
+        status: StatusCode,
+        body: String,
+    },
+    #[error("Unexpected CurseForge api response: {0}")]
+    UnexpectedResponse(String),
 }
\ No newline at end of file
@@ -1,3 +1,5 @@
+#![allow(dead_code)]
+
 use serde::Deserialize;
 
 #[derive(Clone, Debug, Deserialize)]
@@ -20,12 +22,32 @@ pub struct FileEntry {
     pub file_name: String,
     #[serde(rename = "downloadUrl")]
     pub download_url: String,
+    #[serde(rename = "fileLength")]
+    pub file_length: Option<u64>,
     #[serde(rename = "isServerPack")]
     pub is_server_pack: bool,
     #[serde(rename = "serverPackFileId")]
     pub server_pack_file_id: Option<u64>,
     #[serde(rename = "parentProjectFileId")]
     pub parent_project_file_id: Option<u64>,
+    #[serde(default)]
+    pub hashes: Vec<FileHash>,
+}
+
+impl FileEntry {
+    pub fn sha1(&self) -> Option<&str> {
+        self.hashes.iter()
+            .find(|hash| hash.algo == HASH_ALGO_SHA1)
+            .map(|hash| hash.value.as_str())
+    }
+}
+
+const HASH_ALGO_SHA1: u32 = 1;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct FileHash {
+    pub value: String,
+    pub algo: u32,
 }
 
 #[derive(Clone, Debug, Deserialize)]
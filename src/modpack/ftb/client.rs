@@ -1,25 +1,47 @@
-use reqwest::Client;
-use reqwest::header::HeaderMap;
-use crate::modpack::ftb::model::{PackDetails, SearchResults};
+use reqwest::{Client, RequestBuilder};
+use reqwest::header::ACCEPT;
+use serde::de::DeserializeOwned;
+use crate::context::Context;
+use crate::modpack::ftb::FtbError;
+use crate::modpack::ftb::model::{PackDetails, SearchResults, VersionDetails};
 
 #[derive(Clone, Debug)]
 pub struct FtbClient {
     client: Client,
+    base_url: String,
 }
 
 impl FtbClient {
-    pub fn new() -> Self {
-        let mut headers = HeaderMap::new();
-        headers.insert("Accept", "application/json".parse().unwrap());
+    pub fn new(ctx: &Context) -> Self {
+        FtbClient {
+            client: ctx.client().clone(),
+            base_url: ctx.endpoints().ftb_api.clone(),
+        }
+    }
 
-        let client = Client::builder()
-            .default_headers(headers)
-            .build()
-            .unwrap();
+    fn get(&self, path: String) -> RequestBuilder {
+        self.client.get(format!("{0}{path}", self.base_url))
+            .header(ACCEPT, "application/json")
+    }
 
-        FtbClient {
-            client,
+    async fn fetch<T: DeserializeOwned>(&self, path: String) -> color_eyre::Result<T> {
+        let resp = self.get(path)
+            .send()
+            .await?;
+
+        let status = resp.status();
+        let body = resp.text()
+            .await?;
+
+        if !status.is_success() {
+            return Err(FtbError::Api { status, body })?;
         }
+
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    pub fn server_installer_url(&self, pack_id: usize, version_id: usize, target_os: &str) -> String {
+        format!("{0}/public/modpack/{pack_id}/{version_id}/server/{target_os}", self.base_url)
     }
 
     pub async fn search(
@@ -37,31 +59,24 @@ impl FtbClient {
             query += &term;
         }
 
-        let url = format!("https://api.modpacks.ch/public/modpack/search/8?term={query}");
-        let resp = self.client.get(url)
-            .send()
-            .await?
-            .text()
-            .await?;
-
-        let results = serde_json::from_str(&resp)?;
-
-        Ok(results)
+        self.fetch(format!("/public/modpack/search/8?term={query}"))
+            .await
     }
 
     pub async fn get_pack_details(
         &mut self,
         pack_id: usize,
     ) -> color_eyre::Result<PackDetails> {
-        let url = format!("https://api.modpacks.ch/public/modpack/{pack_id}");
-        let resp = self.client.get(url)
-            .send()
-            .await?
-            .text()
-            .await?;
-
-        let details = serde_json::from_str(&resp)?;
+        self.fetch(format!("/public/modpack/{pack_id}"))
+            .await
+    }
 
-        Ok(details)
+    pub async fn get_version_details(
+        &mut self,
+        pack_id: usize,
+        version_id: usize,
+    ) -> color_eyre::Result<VersionDetails> {
+        self.fetch(format!("/public/modpack/{pack_id}/{version_id}"))
+            .await
     }
-}
\ No newline at end of file
+}
@@ -1,17 +1,24 @@
-use std::path::{Path, PathBuf};
-use std::process::Stdio;
-use std::time::Duration;
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+use std::str::FromStr;
 use async_process::Command;
-use futures_util::{AsyncBufReadExt, io, StreamExt};
+use futures_util::{AsyncBufReadExt, StreamExt};
 use futures_util::io::BufReader;
-use indicatif::ProgressBar;
-use log::{error, info};
+use log::info;
+use reqwest::StatusCode;
 use thiserror::Error;
-use tokio::fs::{remove_dir_all, remove_file};
-use crate::cli;
-use crate::fs_utils::{download_file, work_dir};
+use tokio::fs::remove_file;
+use crate::context::Context;
+use crate::events::Stage;
+use crate::lock::{LockError, PackLock};
+use crate::fs_utils::{download_file, sha1_file, DownloadError};
 use crate::modpack::ftb::client::FtbClient;
-use crate::modpack::{check_manifest, post_process};
+use crate::modpack::ftb::model::{PackDetails, VersionFile};
+use crate::modpack::jvm::JvmConfig;
+use crate::modpack::pipeline::{ModpackSource, StagedPack};
+use crate::modpack::{pipeline, InstallOptions, PackSource};
+use crate::progress::Task;
+use crate::version::{McVersion, McVersionRange};
 
 mod model;
 mod client;
@@ -21,125 +28,275 @@ pub enum IdOrSearch {
     Id(String),
     Search {
         terms: Vec<String>,
-        mc_version: Option<String>,
+        mc_version: Option<McVersionRange>,
     }
 }
 
+// Installs FTB packs through their server installer, which also installs the mod loader.
 #[derive(Clone, Debug)]
-struct Context {
+pub struct FtbSource {
     client: FtbClient,
     args: IdOrSearch,
     version: String,
     pack_id: Option<usize>,
     version_id: Option<usize>,
     installer_path: Option<String>,
-    target_dir: PathBuf,
+    // Files of the resolved version the installer puts on the server
+    files: Vec<VersionFile>,
+}
+
+impl FtbSource {
+    // `version` is a version id or `latest`.
+    pub fn new(ctx: &Context, args: IdOrSearch, version: String) -> Self {
+        FtbSource {
+            client: FtbClient::new(ctx),
+            args,
+            version,
+            pack_id: None,
+            version_id: None,
+            installer_path: None,
+            files: Vec::new(),
+        }
+    }
+
+    fn resolved(&self) -> Result<(usize, usize), FtbError> {
+        self.pack_id.zip(self.version_id)
+            .ok_or(FtbError::NotResolved)
+    }
+
+    fn installer_path(&self) -> Result<String, FtbError> {
+        self.installer_path.clone()
+            .ok_or(FtbError::NotResolved)
+    }
+}
+
+impl ModpackSource for FtbSource {
+    async fn resolve(&mut self, ctx: &Context) -> color_eyre::Result<()> {
+        resolve_pack_id(self).await?;
+        resolve_version_id(self).await?;
+        resolve_files(ctx, self).await?;
+
+        let (pack_id, version_id) = self.resolved()?;
+        ctx.plan.resolved(format!("FTB pack {pack_id}, version {version_id}"));
+
+        Ok(())
+    }
+
+    async fn fetch(&mut self, ctx: &Context, _work_dir: &Path) -> color_eyre::Result<()> {
+        download_server_installer(ctx, self).await?;
+        #[cfg(target_os = "linux")]
+        linux_make_installer_executable(ctx, self).await?;
+
+        Ok(())
+    }
+
+    async fn install(&mut self, ctx: &Context, work_dir: &Path) -> color_eyre::Result<()> {
+        ctx.events.stage(Stage::RunInstaller, install_server(ctx, self, work_dir))
+            .await?;
+
+        if !ctx.is_dry_run() {
+            check_files(&self.files, work_dir)?;
+        }
+
+        Ok(())
+    }
+
+    fn pack_source(&self) -> color_eyre::Result<PackSource> {
+        let (pack_id, version_id) = self.resolved()?;
+
+        Ok(PackSource::Ftb {
+            pack_id,
+            version_id,
+        })
+    }
 }
 
 pub async fn handle_ftb<T: AsRef<Path>>(
+    ctx: &Context,
     args: IdOrSearch,
     version: String,
     target_dir: T,
+    options: &InstallOptions,
 ) -> color_eyre::Result<()> {
-    let mut ctx = Context {
-        client: FtbClient::new(),
-        args,
-        version,
-        pack_id: None,
-        version_id: None,
-        installer_path: None,
-        target_dir: target_dir.as_ref().to_path_buf(),
-    };
-
-    check_manifest(&ctx.target_dir).await?;
-    setup()?;
-    resolve_pack_id(&mut ctx).await?;
-    resolve_version_id(&mut ctx).await?;
-    download_server_installer(&mut ctx).await?;
-    #[cfg(target_os = "linux")]
-    linux_make_installer_executable(&mut ctx).await?;
-    install_server(&mut ctx).await?;
-    post_process(&ctx.target_dir).await?;
-
-    Ok(())
+    pipeline::install(ctx, FtbSource::new(ctx, args, version), target_dir, options)
+        .await
 }
 
+pub async fn handle_ftb_locked<T: AsRef<Path>>(
+    ctx: &Context,
+    target_dir: T,
+    options: &InstallOptions,
+) -> color_eyre::Result<()> {
+    let target_dir = target_dir.as_ref();
 
+    let lock = PackLock::load_from(target_dir)?;
+    let PackSource::Ftb { pack_id, version_id } = lock.source else {
+        return Err(LockError::WrongSource)?;
+    };
+    let options = InstallOptions {
+        locked: Some(lock),
+        ..options.clone()
+    };
 
-fn setup() -> color_eyre::Result<()> {
-    let dir = PathBuf::from(".mcsi");
-    if !dir.exists() {
-        std::fs::create_dir(dir)?;
-    }
+    handle_ftb(ctx, IdOrSearch::Id(pack_id.to_string()), version_id.to_string(), target_dir, &options)
+        .await
+}
 
-    Ok(())
+pub async fn stage_ftb(
+    ctx: &Context,
+    args: IdOrSearch,
+    version: String,
+    jvm: &JvmConfig,
+) -> color_eyre::Result<StagedPack> {
+    pipeline::stage(ctx, &mut FtbSource::new(ctx, args, version), jvm)
+        .await
 }
 
-async fn resolve_pack_id(ctx: &mut Context) -> color_eyre::Result<()> {
-    let id = match &ctx.args {
-        IdOrSearch::Id(id) => id.parse::<usize>().unwrap(),
+async fn resolve_pack_id(source: &mut FtbSource) -> color_eyre::Result<()> {
+    let id = match &source.args {
+        IdOrSearch::Id(id) => id.parse::<usize>()
+            .map_err(|_| FtbError::InvalidPackId(id.clone()))?,
         IdOrSearch::Search {
             terms,
-            mc_version: _
+            mc_version,
         } => {
             info!("Searching for best matching pack...");
-            let results = ctx.client.search(terms)
+            let results = source.client.search(terms)
                 .await?;
 
-            results.packs.first().unwrap().clone()
+            let first = *results.packs.first()
+                .ok_or_else(|| FtbError::NoSearchResults(terms.join(" ")))?;
+
+            match mc_version {
+                Some(mc_version) => first_targeting(&mut source.client, results.packs, mc_version)
+                    .await?
+                    .ok_or_else(|| FtbError::NoPackForVersion {
+                        terms: terms.join(" "),
+                        range: mc_version.to_string(),
+                    })?,
+                None => first,
+            }
         }
     };
 
-    ctx.pack_id = Some(id);
+    source.pack_id = Some(id);
 
     Ok(())
 }
 
-async fn resolve_version_id(ctx: &mut Context) -> color_eyre::Result<()> {
-    let pack_id = ctx.pack_id.unwrap();
-    let mut details = ctx.client.get_pack_details(pack_id)
+// The first of `packs` with a version for a minecraft version in `mc_version`.
+async fn first_targeting(client: &mut FtbClient, packs: Vec<usize>, mc_version: &McVersionRange) -> color_eyre::Result<Option<usize>> {
+    for pack_id in packs {
+        let details = client.get_pack_details(pack_id)
+            .await?;
+
+        if targets_mc_version(&details, mc_version) {
+            return Ok(Some(pack_id));
+        }
+    }
+
+    Ok(None)
+}
+
+fn targets_mc_version(details: &PackDetails, mc_version: &McVersionRange) -> bool {
+    details.versions.iter()
+        .flat_map(|entry| entry.targets.iter())
+        .filter(|target| target.name == "minecraft")
+        .any(|target| McVersion::from_str(&target.version).is_ok_and(|version| mc_version.matches(&version)))
+}
+
+async fn resolve_version_id(source: &mut FtbSource) -> color_eyre::Result<()> {
+    let pack_id = source.pack_id.ok_or(FtbError::NotResolved)?;
+    let mut details = source.client.get_pack_details(pack_id)
         .await?;
 
-    if ctx.version.eq_ignore_ascii_case("latest") {
-        details.versions.sort_by(|a, b| b.updated.cmp(&a.updated));
+    if source.version.eq_ignore_ascii_case("latest") {
+        details.versions.sort_by_key(|entry| std::cmp::Reverse(entry.updated));
 
-        let latest = details.versions.first().unwrap();
+        let latest = details.versions.first()
+            .ok_or(FtbError::NoVersions(pack_id))?;
 
-        ctx.version_id = Some(latest.id);
+        source.version_id = Some(latest.id);
     } else {
-        let preferred_version_id: usize = ctx.version.parse()?;
+        let invalid_version = || FtbError::InvalidVersion {
+            pack_id,
+            version: source.version.clone(),
+        };
+        let preferred_version_id: usize = source.version.parse()
+            .map_err(|_| invalid_version())?;
 
         let version_valid = details.versions.iter().any(|entry| entry.id == preferred_version_id);
         if !version_valid {
-            return Err(FtbError::InvalidVersion)?;
+            return Err(invalid_version())?;
         }
 
-        ctx.version_id = Some(preferred_version_id);
+        source.version_id = Some(preferred_version_id);
+    }
+
+    Ok(())
+}
+
+// Records the files of the version in the lock, the installer downloads them itself.
+async fn resolve_files(ctx: &Context, source: &mut FtbSource) -> color_eyre::Result<()> {
+    let (pack_id, version_id) = source.resolved()?;
+    let details = source.client.get_version_details(pack_id, version_id)
+        .await?;
+
+    source.files = details.files.into_iter()
+        .filter(|file| !file.clientonly)
+        .collect();
+
+    for file in source.files.iter().filter(|file| !file.url.is_empty()) {
+        ctx.lock.record_file(&file.url, &file.sha1)?;
+    }
+
+    Ok(())
+}
+
+// Checks what the installer put into `work_dir` against the hashes of the version.
+fn check_files(files: &[VersionFile], work_dir: &Path) -> color_eyre::Result<()> {
+    for file in files {
+        let file_path = work_dir.join(&file.path).join(&file.name);
+        if !file_path.is_file() {
+            continue;
+        }
+
+        let actual = sha1_file(&file_path)?;
+        if !actual.eq_ignore_ascii_case(&file.sha1) {
+            Err(DownloadError::HashMismatch {
+                url: file.url.clone(),
+                expected: file.sha1.clone(),
+                actual,
+            })?;
+        }
     }
 
     Ok(())
 }
 
-async fn download_server_installer(ctx: &mut Context) -> color_eyre::Result<()> {
-    let pack_id = ctx.pack_id.unwrap();
-    let version_id = ctx.version_id.unwrap();
+async fn download_server_installer(ctx: &Context, source: &mut FtbSource) -> color_eyre::Result<()> {
+    let (pack_id, version_id) = source.resolved()?;
 
-    let url = format!("https://api.modpacks.ch/public/modpack/{pack_id}/{version_id}/server/{TARGET_OS}");
-    let dst = PathBuf::from("./.mcsi")
+    let url = source.client.server_installer_url(pack_id, version_id, TARGET_OS);
+    let dst = ctx.staging_dir()
         .join(installer_file_name(pack_id, version_id));
 
     info!("downloading installer...");
-    let path = download_file(&url, &dst)
+    let path = download_file(ctx, &url, &dst)
         .await?;
 
-    ctx.installer_path = Some(path.to_str().unwrap().to_string());
+    source.installer_path = Some(path.to_string_lossy().to_string());
 
     Ok(())
 }
 
 #[cfg(target_os = "linux")]
-async fn linux_make_installer_executable(ctx: &mut Context) -> color_eyre::Result<()> {
-    let installer = ctx.installer_path.clone().unwrap();
+async fn linux_make_installer_executable(ctx: &Context, source: &mut FtbSource) -> color_eyre::Result<()> {
+    if ctx.is_dry_run() {
+        return Ok(());
+    }
+
+    let installer = source.installer_path()?;
 
     Command::new("chmod")
         .args(["+x", &installer])
@@ -149,50 +306,56 @@ async fn linux_make_installer_executable(ctx: &mut Context) -> color_eyre::Resul
     Ok(())
 }
 
-async fn install_server(ctx: &mut Context) -> color_eyre::Result<()> {
-    let work_dir = work_dir();
-    if work_dir.is_dir() {
-        remove_dir_all(&work_dir)
-            .await?;
-    }
-
-    let installer = ctx.installer_path.clone().unwrap();
+async fn install_server(ctx: &Context, source: &mut FtbSource, work_dir: &Path) -> color_eyre::Result<()> {
+    let installer = source.installer_path()?;
+    let work_dir = work_dir.to_string_lossy();
 
-    let install_progress = ProgressBar::new_spinner()
-        .with_style(cli::spinner_progress_style())
-        .with_prefix("Running FTB installer");
+    if ctx.is_dry_run() {
+        // The installer downloads the pack files itself, so they can't be listed up front
+        ctx.plan.command(format!("{installer} --auto --path {work_dir} --nojava"));
+        return Ok(());
+    }
 
-    install_progress.enable_steady_tick(Duration::from_millis(500));
+    let install_progress = ctx.progress(Task::Installer { name: String::from("Running FTB installer") });
 
     let mut child = Command::new(installer.clone())
-        .args(["--auto", "--path", "./.mcsi/work_dir", "--nojava"])
+        .args(["--auto", "--path", &work_dir, "--nojava"])
         .stdout(Stdio::piped())
         .spawn()?;
 
-    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
-    while let Some(line) = lines.next().await {
-        if let Ok(line) = line {
-            install_progress.set_message(line);
+    if let Some(stdout) = child.stdout.take() {
+        let mut lines = BufReader::new(stdout).lines();
+        while let Some(line) = lines.next().await {
+            if let Ok(line) = line {
+                install_progress.set_message(line);
+            }
         }
     }
 
-    let wait = async move {
-        child.status().await?;
-        io::Result::Ok(false)
-    };
-    wait.await?;
+    let status = child.status()
+        .await?;
 
     install_progress.finish();
+    check_installer_status(status)?;
+
     remove_file(installer)
         .await?;
 
     Ok(())
 }
 
+fn check_installer_status(status: ExitStatus) -> Result<(), FtbError> {
+    if !status.success() {
+        return Err(FtbError::InstallerError(status.code()));
+    }
+
+    Ok(())
+}
+
 #[cfg(target_os = "windows")]
-const TARGET_OS: &'static str = "windows";
+const TARGET_OS: &str = "windows";
 #[cfg(target_os = "linux")]
-const TARGET_OS: &'static str = "linux";
+const TARGET_OS: &str = "linux";
 
 #[cfg(target_os = "windows")]
 fn installer_file_name(
@@ -212,8 +375,75 @@ fn installer_file_name(
 
 #[derive(Error, Debug)]
 pub enum FtbError {
-    #[error("Invalid version!")]
-    InvalidVersion,
-    #[error("Ftb installer error")]
-    InstallerError,
-}
\ No newline at end of file
+    #[error("`{version}` is not a version of pack {pack_id}, use `latest` or a version id")]
+    InvalidVersion {
+        pack_id: usize,
+        version: String,
+    },
+    #[error("`{0}` is not a pack id")]
+    InvalidPackId(String),
+    #[error("No pack matches `{0}`, try other search terms")]
+    NoSearchResults(String),
+    #[error("No pack matching `{terms}` has a version for minecraft {range}")]
+    NoPackForVersion {
+        terms: String,
+        range: String,
+    },
+    #[error("Pack {0} has no versions")]
+    NoVersions(usize),
+    #[error("The pack has to be resolved first")]
+    NotResolved,
+    #[error("FTB installer exited with code {0:?}")]
+    InstallerError(Option<i32>),
+    #[error("FTB api request failed with status {status}: {body}")]
+    Api {
+        status: StatusCode,
+        body: String,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::path::PathBuf;
+    use std::str::FromStr;
+    use crate::modpack::ftb::model::{PackDetails, VersionFile};
+    use crate::modpack::ftb::{check_files, targets_mc_version};
+    use crate::version::McVersionRange;
+
+    #[test]
+    fn filters_packs_by_mc_version() {
+        let details: PackDetails = serde_json::from_str(r#"{"versions": [
+            {"id": 1, "name": "1.0.0", "type": "Release", "updated": 1, "private": false, "specs": {"id": 1, "minimum": 4096, "recommended": 6144}, "targets": [{"id": 1, "name": "forge", "version": "47.2.0", "type": "modloader", "updated": 1}, {"id": 2, "name": "minecraft", "version": "1.19.2", "type": "game", "updated": 1}]},
+            {"id": 2, "name": "2.0.0", "type": "Release", "updated": 2, "private": false, "specs": {"id": 1, "minimum": 4096, "recommended": 6144}, "targets": [{"id": 3, "name": "minecraft", "version": "1.20.1", "type": "game", "updated": 1}]}
+        ]}"#).unwrap();
+
+        assert!(targets_mc_version(&details, &McVersionRange::from_str(">=1.20").unwrap()));
+        assert!(targets_mc_version(&details, &McVersionRange::from_str("1.19.2").unwrap()));
+        assert!(!targets_mc_version(&details, &McVersionRange::from_str(">=1.21").unwrap()));
+    }
+
+    #[test]
+    fn checks_installed_files_against_the_version() {
+        let work_dir = PathBuf::from("./.mcsi-test-dir")
+            .join("tests")
+            .join("ftb-files");
+        if work_dir.is_dir() {
+            remove_dir_all(&work_dir).unwrap();
+        }
+        create_dir_all(work_dir.join("mods")).unwrap();
+        write(work_dir.join("mods").join("a.jar"), "a").unwrap();
+
+        let file = |name: &str, sha1: &str| VersionFile {
+            path: String::from("./mods/"),
+            name: name.to_string(),
+            url: format!("https://example.com/{name}"),
+            sha1: sha1.to_string(),
+            clientonly: false,
+        };
+
+        // The installer may skip files, only the ones it put there are checked
+        check_files(&[file("a.jar", "86f7e437faa5a7fce15d1ddcb9eaeaea377667b8"), file("b.jar", "")], &work_dir).unwrap();
+        assert!(check_files(&[file("a.jar", "da39a3ee5e6b4b0d3255bfef95601890afd80709")], &work_dir).is_err());
+    }
+}
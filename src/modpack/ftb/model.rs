@@ -1,3 +1,5 @@
+#![allow(dead_code)]
+
 use serde::Deserialize;
 
 #[derive(Deserialize, Clone, Debug)]
@@ -37,4 +39,20 @@ pub struct Target {
     #[serde(rename = "type")]
     pub target_type: String,
     pub updated: u64,
-}
\ No newline at end of file
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct VersionDetails {
+    pub files: Vec<VersionFile>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct VersionFile {
+    pub path: String,
+    pub name: String,
+    #[serde(default)]
+    pub url: String,
+    pub sha1: String,
+    #[serde(default)]
+    pub clientonly: bool,
+}
@@ -0,0 +1,207 @@
+use serde::Deserialize;
+use thiserror::Error;
+use crate::cache::parse_size;
+
+pub const USER_JVM_ARGS_FILE: &str = "user_jvm_args.txt";
+
+const DEFAULT_MIN_MEMORY: &str = "128M";
+const DEFAULT_MAX_MEMORY: &str = "4G";
+
+// Aikar's G1GC flags for heaps below 12G, see https://docs.papermc.io/paper/aikars-flags
+const AIKAR_FLAGS: [&str; 20] = [
+    "-XX:+UseG1GC",
+    "-XX:+ParallelRefProcEnabled",
+    "-XX:MaxGCPauseMillis=200",
+    "-XX:+UnlockExperimentalVMOptions",
+    "-XX:+DisableExplicitGC",
+    "-XX:+AlwaysPreTouch",
+    "-XX:G1NewSizePercent=30",
+    "-XX:G1MaxNewSizePercent=40",
+    "-XX:G1HeapRegionSize=8M",
+    "-XX:G1ReservePercent=20",
+    "-XX:G1HeapWastePercent=5",
+    "-XX:G1MixedGCCountTarget=4",
+    "-XX:InitiatingHeapOccupancyPercent=15",
+    "-XX:G1MixedGCLiveThresholdPercent=90",
+    "-XX:G1RSetUpdatingPauseTimePercent=5",
+    "-XX:SurvivorRatio=32",
+    "-XX:+PerfDisableSharedMem",
+    "-XX:MaxTenuringThreshold=1",
+    "-Dusing.aikars.flags=https://mcflags.emc.gs",
+    "-Daikars.new.flags=true",
+];
+
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct JvmConfig {
+    // e.g. `2G`, defaults to 128M
+    pub min_memory: Option<String>,
+    // e.g. `8G`, only used when `SERVER_MEMORY` is unset, defaults to 4G
+    pub max_memory: Option<String>,
+    pub preset: Option<JvmPreset>,
+    #[serde(default)]
+    pub args: Vec<String>,
+    // Passed to the server, like `nogui`
+    #[serde(default)]
+    pub program_args: Vec<String>,
+    // Java executable, defaults to `java` on the path
+    pub java: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum JvmPreset {
+    Aikar,
+}
+
+impl JvmConfig {
+    // Settings of `other` win over the ones of `self`.
+    pub fn merge(self, other: JvmConfig) -> Self {
+        let or_self = |other: Vec<String>, this: Vec<String>| if other.is_empty() { this } else { other };
+
+        JvmConfig {
+            min_memory: other.min_memory.or(self.min_memory),
+            max_memory: other.max_memory.or(self.max_memory),
+            preset: other.preset.or(self.preset),
+            args: or_self(other.args, self.args),
+            program_args: or_self(other.program_args, self.program_args),
+            java: other.java.or(self.java),
+        }
+    }
+
+    pub fn java(&self) -> &str {
+        self.java.as_deref().unwrap_or("java")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min_memory.is_none()
+            && self.max_memory.is_none()
+            && self.preset.is_none()
+            && self.args.is_empty()
+            && self.program_args.is_empty()
+            && self.java.is_none()
+    }
+
+    // Preset flags, then `pack_args` the pack starts with, then the configured args.
+    fn jvm_args(&self, pack_args: &[String]) -> Vec<String> {
+        let preset = match self.preset {
+            Some(JvmPreset::Aikar) => AIKAR_FLAGS.as_slice(),
+            None => &[],
+        };
+
+        preset.iter()
+            .map(|arg| arg.to_string())
+            .chain(pack_args.iter().cloned())
+            .chain(self.args.iter().cloned())
+            .collect()
+    }
+
+    // Contents of `user_jvm_args.txt`, which the Forge and NeoForge run scripts pass to java.
+    pub fn user_jvm_args(&self, pack_args: &[String]) -> color_eyre::Result<String> {
+        let memory = [
+            format!("-Xms{}M", memory_mb(self.min_memory.as_deref().unwrap_or(DEFAULT_MIN_MEMORY))?),
+            format!("-Xmx{}M", memory_mb(self.max_memory.as_deref().unwrap_or(DEFAULT_MAX_MEMORY))?),
+        ];
+
+        Ok(memory.into_iter()
+            .chain(self.jvm_args(pack_args))
+            .map(|arg| format!("{arg}\n"))
+            .collect())
+    }
+
+    pub fn start_script(&self, pack_args: &[String], launch_args: &[String]) -> color_eyre::Result<String> {
+        let min_memory = memory_mb(self.min_memory.as_deref().unwrap_or(DEFAULT_MIN_MEMORY))?;
+        let max_memory = memory_mb(self.max_memory.as_deref().unwrap_or(DEFAULT_MAX_MEMORY))?;
+        let java = shell_quote(self.java());
+
+        // The same args are passed here, reading user_jvm_args.txt again would only override the memory
+        let args = self.jvm_args(pack_args)
+            .into_iter()
+            .chain(launch_args.iter().filter(|arg| arg.as_str() != format!("@{USER_JVM_ARGS_FILE}")).cloned())
+            .chain(self.program_args.iter().cloned())
+            .map(|arg| shell_quote(&arg))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        Ok(format!("#!/usr/bin/env sh\nexec {java} -Xms{min_memory}M -Xmx${{SERVER_MEMORY:-{max_memory}}}M {args}\n"))
+    }
+}
+
+// Quotes `arg` for sh unless it is made of characters that are never special.
+fn shell_quote(arg: &str) -> String {
+    let plain = !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_=+:,./@%".contains(c));
+
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+// Memory like `8G` or `512M` in megabytes, as `SERVER_MEMORY` is given.
+fn memory_mb(memory: &str) -> Result<u64, JvmError> {
+    parse_size(memory)
+        .ok()
+        .map(|bytes| bytes >> 20)
+        .filter(|mb| *mb > 0)
+        .ok_or_else(|| JvmError::InvalidMemory(memory.to_string()))
+}
+
+#[derive(Error, Debug)]
+pub enum JvmError {
+    #[error("`{0}` is not a valid amount of memory, expected something like `512M` or `8G`")]
+    InvalidMemory(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::modpack::jvm::{shell_quote, JvmConfig, JvmPreset};
+
+    #[test]
+    fn generates_start_script_and_user_jvm_args() {
+        let launch_args = [String::from("@user_jvm_args.txt"), String::from("@libraries/unix_args.txt"), String::from("nogui")];
+
+        let script = JvmConfig::default()
+            .start_script(&[], &launch_args)
+            .unwrap();
+        assert_eq!(script, "#!/usr/bin/env sh\nexec java -Xms128M -Xmx${SERVER_MEMORY:-4096}M @libraries/unix_args.txt nogui\n");
+
+        let cli = JvmConfig {
+            max_memory: Some(String::from("6G")),
+            java: Some(String::from("/opt/java/21/bin/java")),
+            ..JvmConfig::default()
+        };
+        let config = JvmConfig {
+            min_memory: Some(String::from("2G")),
+            max_memory: Some(String::from("8G")),
+            preset: Some(JvmPreset::Aikar),
+            args: vec![String::from("-Dfml.queryResult=confirm")],
+            program_args: vec![String::from("--port"), String::from("25570")],
+            ..JvmConfig::default()
+        };
+        let merged = cli.merge(config);
+
+        let script = merged.start_script(&[String::from("-Dpack=true")], &launch_args).unwrap();
+        assert!(script.starts_with("#!/usr/bin/env sh\nexec /opt/java/21/bin/java -Xms2048M -Xmx${SERVER_MEMORY:-8192}M -XX:+UseG1GC "));
+        assert!(script.ends_with(" -Daikars.new.flags=true -Dpack=true -Dfml.queryResult=confirm @libraries/unix_args.txt nogui --port 25570\n"));
+
+        let user_jvm_args = merged.user_jvm_args(&[]).unwrap();
+        assert!(user_jvm_args.starts_with("-Xms2048M\n-Xmx8192M\n-XX:+UseG1GC\n"));
+        assert!(user_jvm_args.ends_with("-Dfml.queryResult=confirm\n"));
+
+        let invalid = JvmConfig {
+            max_memory: Some(String::from("lots")),
+            ..JvmConfig::default()
+        };
+        assert!(invalid.start_script(&[], &launch_args).is_err());
+    }
+
+    #[test]
+    fn quotes_args_for_the_shell() {
+        assert_eq!(shell_quote("-XX:+UseG1GC"), "-XX:+UseG1GC");
+        assert_eq!(shell_quote("-Dmotd=A Server"), "'-Dmotd=A Server'");
+        assert_eq!(shell_quote("-Dname=it's"), "'-Dname=it'\\''s'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+        assert_eq!(shell_quote(""), "''");
+    }
+}
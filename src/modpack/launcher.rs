@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::str::FromStr;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use crate::modloader::ModLoader;
+use crate::version::McVersion;
+
+pub(crate) const VARIABLES_FILE: &str = "variables.txt";
+pub(crate) const SERVER_STARTER_CONFIG: &str = "server-setup-config.yaml";
+
+// The convention a server pack's own start script follows, which installs the mod loader on first boot.
+#[derive(Serialize, Eq, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum LauncherKind {
+    // `variables.txt`, read by the ServerPackCreator start scripts
+    Variables,
+    // `server-setup-config.yaml`, read by ServerStarter
+    ServerStarter,
+    // Loader version assigned at the top of the start script, like the ATM packs do
+    Script,
+}
+
+// What a pack's launcher would install and run, so it can be done ahead of time instead.
+#[derive(Serialize, Clone, Debug)]
+pub struct PackLauncher {
+    pub kind: LauncherKind,
+    pub mod_loader: Option<ModLoader>,
+    pub mc_version: Option<McVersion>,
+    // JVM args the pack starts the server with, without the memory settings
+    pub jvm_args: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ServerStarterConfig {
+    install: ServerStarterInstall,
+    #[serde(default)]
+    launch: ServerStarterLaunch,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ServerStarterInstall {
+    mc_version: Option<String>,
+    loader_version: Option<String>,
+    #[serde(default)]
+    installer_url: String,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ServerStarterLaunch {
+    #[serde(default)]
+    java_args: Vec<String>,
+}
+
+impl PackLauncher {
+    pub(crate) fn detect<T: AsRef<Path>>(root: T, start_scripts: &[&Path]) -> color_eyre::Result<Option<Self>> {
+        let root = root.as_ref();
+
+        let variables = root.join(VARIABLES_FILE);
+        if variables.is_file() {
+            let vars = parse_assignments(&std::fs::read_to_string(variables)?);
+            let loader = match (vars.get("MODLOADER"), vars.get("MODLOADER_VERSION")) {
+                (Some(name), Some(version)) => Some((name.as_str(), version.as_str())),
+                _ => None,
+            };
+
+            return Ok(Some(PackLauncher::new(LauncherKind::Variables, loader, vars.get("MINECRAFT_VERSION"), vars.get("JAVA_ARGS"))));
+        }
+
+        let server_starter = root.join(SERVER_STARTER_CONFIG);
+        if server_starter.is_file() {
+            let config: ServerStarterConfig = serde_yaml_ng::from_str(&std::fs::read_to_string(server_starter)?)?;
+            let install = config.install;
+            let name = if install.installer_url.contains("neoforged") {
+                "neoforge"
+            } else if install.installer_url.contains("fabric") {
+                "fabric"
+            } else {
+                "forge"
+            };
+
+            let mut launcher = PackLauncher::new(LauncherKind::ServerStarter, install.loader_version.as_deref().map(|version| (name, version)), install.mc_version.as_ref(), None);
+            launcher.jvm_args = without_memory_args(config.launch.java_args);
+
+            return Ok(Some(launcher));
+        }
+
+        for script in start_scripts {
+            let vars = parse_assignments(&std::fs::read_to_string(root.join(script))?);
+            let loader = ["NEOFORGE_VERSION", "FORGE_VERSION", "FABRIC_VERSION"].into_iter()
+                .find_map(|key| vars.get(key).map(|version| (key.trim_end_matches("_VERSION"), version.as_str())));
+
+            if loader.is_some() {
+                let mc_version = vars.get("MINECRAFT_VERSION").or(vars.get("MC_VERSION"));
+                return Ok(Some(PackLauncher::new(LauncherKind::Script, loader, mc_version, vars.get("JAVA_ARGS"))));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn new(kind: LauncherKind, loader: Option<(&str, &str)>, mc_version: Option<&String>, jvm_args: Option<&String>) -> Self {
+        let mod_loader = loader.and_then(|(name, version)| match ModLoader::from_parts(&name.to_ascii_lowercase(), version) {
+            Ok(ModLoader::Quilt { .. }) => {
+                warn!("Quilt can't be installed ahead of time, the pack installs it on first boot");
+                None
+            }
+            Ok(mod_loader) => Some(mod_loader),
+            Err(err) => {
+                warn!("The {kind} of the pack names an unknown mod loader: {err}");
+                None
+            }
+        });
+
+        let mc_version = mc_version.and_then(|mc_version| McVersion::from_str(mc_version)
+            .inspect_err(|err| warn!("The {kind} of the pack names an unknown minecraft version: {err}"))
+            .ok());
+
+        let jvm_args = jvm_args.map(|args| args.split_whitespace().map(String::from).collect())
+            .unwrap_or_default();
+
+        PackLauncher {
+            kind,
+            mod_loader,
+            mc_version,
+            jvm_args: without_memory_args(jvm_args),
+        }
+    }
+
+    pub(crate) fn loader(&self) -> Option<(ModLoader, Option<McVersion>)> {
+        self.mod_loader.clone()
+            .map(|mod_loader| (mod_loader, self.mc_version.clone()))
+    }
+}
+
+impl Display for LauncherKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LauncherKind::Variables => write!(f, "{VARIABLES_FILE}"),
+            LauncherKind::ServerStarter => write!(f, "ServerStarter config"),
+            LauncherKind::Script => write!(f, "start script"),
+        }
+    }
+}
+
+// Reads `KEY=value` lines like the ones of `variables.txt` and sh scripts.
+fn parse_assignments(content: &str) -> HashMap<String, String> {
+    content.lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.strip_prefix("export ").unwrap_or(line).split_once('='))
+        .filter(|(key, _)| !key.is_empty() && key.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_'))
+        .map(|(key, value)| (key.to_string(), value.trim().trim_matches(['"', '\'']).to_string()))
+        .collect()
+}
+
+// The generated start script sets the memory, so the pack's own settings would only override it.
+fn without_memory_args(args: Vec<String>) -> Vec<String> {
+    args.into_iter()
+        .filter(|arg| !arg.starts_with("-Xmx") && !arg.starts_with("-Xms"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use crate::modloader::ModLoader;
+    use crate::modpack::launcher::{LauncherKind, PackLauncher};
+
+    fn launcher_dir(name: &str) -> PathBuf {
+        let dir = PathBuf::from("./.mcsi-test-dir")
+            .join("tests")
+            .join("launcher")
+            .join(name);
+        if dir.is_dir() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+        std::fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn reads_every_launcher_config() {
+        let dir = launcher_dir("variables");
+        std::fs::write(dir.join("variables.txt"), "# Minecraft version\nMINECRAFT_VERSION=1.20.1\nMODLOADER=Forge\nMODLOADER_VERSION=47.2.0\nJAVA_ARGS=\"-Xmx4G -Xms4G -XX:+UseG1GC\"\n").unwrap();
+        let launcher = PackLauncher::detect(&dir, &[]).unwrap().unwrap();
+        assert_eq!(launcher.kind, LauncherKind::Variables);
+        assert_eq!(launcher.mod_loader, Some(ModLoader::Forge { version: String::from("47.2.0") }));
+        assert_eq!(launcher.mc_version.unwrap().to_string(), "1.20.1");
+        assert_eq!(launcher.jvm_args, ["-XX:+UseG1GC"]);
+
+        let dir = launcher_dir("server-starter");
+        std::fs::write(dir.join("server-setup-config.yaml"), "_specver: 2\ninstall:\n  mcVersion: 1.16.5\n  loaderVersion: 36.2.39\n  installerUrl: https://maven.minecraftforge.net/net/minecraftforge/forge/1.16.5-36.2.39/forge-1.16.5-36.2.39-installer.jar\nlaunch:\n  maxRam: 6G\n  javaArgs:\n    - \"-XX:+UseG1GC\"\n").unwrap();
+        let launcher = PackLauncher::detect(&dir, &[]).unwrap().unwrap();
+        assert_eq!(launcher.kind, LauncherKind::ServerStarter);
+        assert_eq!(launcher.mod_loader, Some(ModLoader::Forge { version: String::from("36.2.39") }));
+        assert_eq!(launcher.jvm_args, ["-XX:+UseG1GC"]);
+
+        let dir = launcher_dir("script");
+        std::fs::write(dir.join("startserver.sh"), "#!/bin/bash\nNEOFORGE_VERSION=21.1.90\nif [ ! -d libraries ]; then\n  java -jar installer.jar --installServer\nfi\n").unwrap();
+        let launcher = PackLauncher::detect(&dir, &[Path::new("startserver.sh")]).unwrap().unwrap();
+        assert_eq!(launcher.kind, LauncherKind::Script);
+        assert_eq!(launcher.mod_loader, Some(ModLoader::NeoForge { version: String::from("21.1.90") }));
+
+        std::fs::write(dir.join("startserver.sh"), "#!/bin/sh\njava -jar server.jar\n").unwrap();
+        assert!(PackLauncher::detect(&dir, &[Path::new("startserver.sh")]).unwrap().is_none());
+    }
+}
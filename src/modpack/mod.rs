@@ -1,28 +1,91 @@
+use std::collections::BTreeMap;
 use std::fs::{File, read, remove_file};
 use std::io::Write;
-use std::path::{Path, PathBuf};
-use chrono::Utc;
-use log::{debug, info};
+use std::path::{MAIN_SEPARATOR, Path, PathBuf};
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tokio::fs::{create_dir_all, remove_dir_all};
+use tokio::fs::{create_dir_all, remove_dir_all, write};
 use walkdir::WalkDir;
-use crate::fs_utils::{backup_and_remove_files, file_path_relative_to, get_server_start_script, logs_dir, mcsi_dir, recursive_copy_to_dir, work_dir};
+use crate::fs_utils::{file_path_relative_to, logs_dir, recursive_copy_to_dir, remove_files, sha1_file};
 #[cfg(target_os = "linux")]
 use crate::fs_utils::set_as_executable;
-use crate::modloader::ModLoader;
-
+use crate::lock::PackLock;
+use crate::modloader::LoaderInstall;
+use crate::context::Context;
+use crate::events::Stage;
+use crate::modpack::backup::BackupFormat;
+use crate::modpack::jvm::{JvmConfig, USER_JVM_ARGS_FILE};
+use crate::modpack::pipeline::StagedPack;
+use crate::modpack::players::{MojangResolver, ResolvedPlayers};
+use crate::modpack::server_files::{ServerSettings, USER_FILES};
+use crate::modpack::server_pack::{ServerPack, Shell};
+use crate::modpack::service::{ServiceConfig, START_SCRIPT};
+
+pub mod backup;
+pub mod bundle;
+pub mod config;
+pub mod container;
 pub mod flame;
 pub mod ftb;
+pub mod jvm;
+pub mod launcher;
+pub mod pipeline;
+pub mod players;
+pub mod server_files;
+pub mod server_pack;
+pub mod service;
+pub mod verify;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct PackManifest {
     pub files: Vec<String>,
+    // Sha1 of every file in `files`, keyed by the relative path.
+    #[serde(default)]
+    pub hashes: BTreeMap<String, String>,
+    // Where the pack was installed from, used to restore broken files.
+    #[serde(default)]
+    pub source: Option<PackSource>,
+    // The mod loader installed with the pack and how to start it.
+    #[serde(default)]
+    pub loader: Option<LoaderInstall>,
+    // Sha1 of the config `apply` installed the pack from.
+    #[serde(default)]
+    pub config: Option<String>,
+}
+
+#[derive(Default, Clone, Debug)]
+pub struct InstallOptions {
+    // How the replaced pack files are kept, `None` removes them
+    pub backup: Option<BackupFormat>,
+    // Everything the install resolves has to match this lock
+    pub locked: Option<PackLock>,
+    pub jvm: JvmConfig,
+    pub server: ServerSettings,
+    pub service: ServiceConfig,
 }
 
 #[derive(Debug)]
 pub struct PackManifestBuilder {
     files: Vec<String>,
+    hashes: BTreeMap<String, String>,
+    source: Option<PackSource>,
+    loader: Option<LoaderInstall>,
+    config: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum PackSource {
+    Flame {
+        project_id: u64,
+        file_id: u64,
+        skip_server_pack: bool,
+    },
+    Ftb {
+        pack_id: usize,
+        version_id: usize,
+    },
 }
 
 impl PackManifest {
@@ -36,8 +99,17 @@ impl PackManifest {
     ) -> color_eyre::Result<()> {
         let file_path = mcsi_dir.as_ref()
             .join("manifest.json");
+
+        self.save_as(file_path)
+    }
+
+    pub fn save_as<T: AsRef<Path>>(
+        &self,
+        file_path: T,
+    ) -> color_eyre::Result<()> {
+        let file_path = file_path.as_ref();
         if file_path.is_file() {
-            remove_file(&file_path)?;
+            remove_file(file_path)?;
         }
 
         let bytes = serde_json::to_vec_pretty(&self)?;
@@ -52,6 +124,19 @@ impl PackManifest {
     ) -> color_eyre::Result<Self> {
         let file_path = mcsi_dir.as_ref()
             .join("manifest.json");
+        let mut manifest = Self::load_file(file_path)?;
+
+        // Manifests from before the server files were left to the user still list them
+        manifest.files.retain(|file| !USER_FILES.contains(&file.as_str()));
+        manifest.hashes.retain(|file, _| !USER_FILES.contains(&file.as_str()));
+
+        Ok(manifest)
+    }
+
+    pub fn load_file<T: AsRef<Path>>(
+        file_path: T,
+    ) -> color_eyre::Result<Self> {
+        let file_path = file_path.as_ref();
         if !file_path.is_file() {
             return Err(ManifestError::ManifestNotFound)?;
         }
@@ -67,34 +152,81 @@ impl PackManifestBuilder {
     fn new() -> Self {
         PackManifestBuilder {
             files: Vec::new(),
+            hashes: BTreeMap::new(),
+            source: None,
+            loader: None,
+            config: None,
         }
     }
 
     pub fn finish(self) -> PackManifest {
         PackManifest {
             files: self.files,
+            hashes: self.hashes,
+            source: self.source,
+            loader: self.loader,
+            config: self.config,
         }
     }
 
+    pub fn with_source(
+        mut self,
+        source: PackSource,
+    ) -> Self {
+        self.source = Some(source);
+
+        self
+    }
+
+    pub fn with_loader(
+        mut self,
+        loader: Option<LoaderInstall>,
+    ) -> Self {
+        self.loader = loader;
+
+        self
+    }
+
+    pub fn with_config(
+        mut self,
+        config: Option<String>,
+    ) -> Self {
+        self.config = config;
+
+        self
+    }
+
     pub fn with_files_from_dir<T: AsRef<Path>>(
         mut self,
         dir: T,
-    ) -> Self {
+    ) -> color_eyre::Result<Self> {
         let mut files = 0;
         for entry in WalkDir::new(&dir) {
-            let entry = entry.unwrap();
+            let entry = entry?;
             if entry.path().is_dir() {
                 continue;
             }
 
-            let relative = file_path_relative_to(entry.path(), &dir)
-                .unwrap();
+            let relative = file_path_relative_to(entry.path(), &dir)?;
+            let relative = relative.to_string_lossy().to_string();
+            let hash = sha1_file(entry.path())?;
 
-            self.files.push(String::from(relative.to_str().unwrap()));
+            self.hashes.insert(relative.clone(), hash);
+            self.files.push(relative);
             files += 1;
         }
         debug!("Added {} files to manifest. {} Total files", files, self.files.len());
 
+        Ok(self)
+    }
+
+    pub fn exclude_files(
+        mut self,
+        files: &[&str],
+    ) -> Self {
+        self.files.retain(|entry| !files.contains(&entry.as_str()));
+        self.hashes.retain(|entry, _| !files.contains(&entry.as_str()));
+
         self
     }
 
@@ -109,10 +241,11 @@ impl PackManifestBuilder {
 
         let prev = self.files.len();
 
-        let dir = dir.to_str().unwrap().to_owned();
+        let dir = dir.to_string_lossy().to_string();
         debug!("Excluding files from {}", dir);
 
         self.files.retain(|entry| !entry.contains(&dir));
+        self.hashes.retain(|entry, _| !entry.contains(&dir));
 
         debug!("Excluded {} files from manifest", prev - self.files.len());
 
@@ -126,7 +259,7 @@ pub enum ManifestError {
     ManifestNotFound,
 }
 
-pub async fn check_manifest<T: AsRef<Path>>(target_dir: T) -> color_eyre::Result<()> {
+pub(crate) async fn check_manifest<T: AsRef<Path>>(ctx: &Context, target_dir: T, backup: Option<BackupFormat>) -> color_eyre::Result<()> {
     let target_dir = target_dir.as_ref();
     let mcsi_dir = target_dir
         .join(".mcsi");
@@ -141,88 +274,197 @@ pub async fn check_manifest<T: AsRef<Path>>(target_dir: T) -> color_eyre::Result
     let manifest = PackManifest::load_from(&mcsi_dir)?;
     info!("Existing pack manifest found!");
 
-    let now = Utc::now().format("%Y-%m-%d-%H%M%S").to_string();
-    let backup_dir = mcsi_dir
-        .join("backups")
-        .join(format!("backup-{now}"));
+    if ctx.is_dry_run() {
+        ctx.plan.remove(manifest.files, backup);
+        return Ok(());
+    }
 
-    create_dir_all(&backup_dir)
-        .await?;
+    ctx.events.stage(Stage::RemoveOld, async {
+        if let Some(format) = backup {
+            backup::create_backup(ctx, target_dir, manifest, format)
+                .await?;
+        } else {
+            info!("Skipping backup, removing old pack files...");
+            remove_files(ctx, target_dir, manifest.files)
+                .await?;
+        }
+
+        Ok(())
+    }).await
+}
+
+// Removes the installed pack files, leaving worlds and any other files created after the install alone.
+pub async fn uninstall<T: AsRef<Path>>(ctx: &Context, target_dir: T, backup: Option<BackupFormat>) -> color_eyre::Result<()> {
+    let target_dir = target_dir.as_ref();
+    let manifest_path = target_dir
+        .join(".mcsi")
+        .join("manifest.json");
+
+    if !manifest_path.is_file() {
+        return Err(ManifestError::ManifestNotFound)?;
+    }
 
-    backup_and_remove_files(target_dir, backup_dir, manifest.files)
+    check_manifest(ctx, target_dir, backup)
         .await?;
 
+    if ctx.is_dry_run() {
+        ctx.plan.change(format!("Remove {:?}", manifest_path));
+        return Ok(());
+    }
+
+    remove_file(manifest_path)?;
+
+    info!("Pack uninstalled!");
     Ok(())
 }
 
-fn create_mc_start_script() -> color_eyre::Result<File> {
-    let start_script_path = work_dir()
-        .join("mc-start.sh");
+fn write_mc_start_script(server_dir: &Path, script: &str) -> color_eyre::Result<()> {
+    let start_script_path = server_dir
+        .join(START_SCRIPT);
 
-    let file = File::create(&start_script_path)?;
+    let mut file = File::create(&start_script_path)?;
+    file.write_all(script.as_bytes())?;
+    file.flush()?;
 
-    Ok(file)
+    #[cfg(target_os = "linux")]
+    set_as_executable(start_script_path)?;
+
+    Ok(())
 }
 
-#[cfg(target_os = "linux")]
-fn make_mc_start_script_executable() -> color_eyre::Result<()> {
-    let start_script_path = work_dir()
-        .join("mc-start.sh");
+// Writes `mc-start.sh` into `server_dir`, starting either the pack's own script or the installed `loader`.
+pub(crate) async fn ensure_server_start_script(ctx: &Context, server_dir: &Path, loader: Option<&LoaderInstall>, jvm: &JvmConfig) -> color_eyre::Result<()> {
+    let server_pack = ServerPack::at(server_dir)?;
+    server_pack.report(ctx);
 
-    set_as_executable(start_script_path)
-}
+    // A launcher would install the loader again on first boot, so it is only used when we couldn't install it
+    let launcher = server_pack.launcher.as_ref();
+    let pack_script = server_pack.start_script(Shell::Sh)
+        .filter(|_| launcher.is_none() || loader.is_none());
+
+    let pack_args = launcher.map(|launcher| launcher.jvm_args.as_slice())
+        .unwrap_or_default();
+
+    // Forge and NeoForge run scripts read the JVM args from there
+    let user_jvm_args = server_dir.join(USER_JVM_ARGS_FILE);
+    if !jvm.is_empty() && user_jvm_args.is_file() {
+        write(&user_jvm_args, jvm.user_jvm_args(pack_args)?)
+            .await?;
+    }
 
-pub async fn ensure_server_start_script(mod_loader: Option<ModLoader>) -> color_eyre::Result<()> {
-    let start_script = get_server_start_script(work_dir());
+    if let Some(start_script) = pack_script {
+        if !jvm.is_empty() {
+            warn!("The pack's own start script is used, only the JVM args in {USER_JVM_ARGS_FILE} apply to it");
+        }
 
-    if start_script.is_some() {
-        let mut mc_start_file = create_mc_start_script()?;
-        write!(&mut mc_start_file, "#!/usr/bin/env sh\n{0}", start_script.unwrap().to_str().unwrap())?;
-        mc_start_file.flush()?;
+        ctx.plan.resolved(format!("Start script: {:?} from the pack", start_script.path));
 
+        // Pack scripts are rarely executable once extracted, and may be written for bash
         #[cfg(target_os = "linux")]
-        make_mc_start_script_executable()?;
+        set_as_executable(server_dir.join(&start_script.path))?;
+        write_mc_start_script(server_dir, &format!("#!/usr/bin/env sh\nexec \"./{0}\"", start_script.path.to_string_lossy()))?;
 
         return Ok(());
     }
 
-    if let Some(mod_loader) = mod_loader {
-        match mod_loader {
-            ModLoader::NeoForge { .. } => {
-                let mut mc_start_file = create_mc_start_script()?;
-                #[cfg(target_os = "linux")]
-                write!(&mut mc_start_file, "#!/usr/bin/env sh\njava -Xms128M -Xmx${{SERVER_MEMORY}}M -jar server.jar")?;
+    if let Some(loader) = loader {
+        let script = jvm.start_script(pack_args, &loader.launch_args)?;
 
-                #[cfg(target_os = "linux")]
-                make_mc_start_script_executable()?;
-            }
-            ModLoader::Forge { .. } => {
-                let mut mc_start_file = create_mc_start_script()?;
-                #[cfg(target_os = "linux")]
-                write!(&mut mc_start_file, "#!/usr/bin/env sh\njava -Xms128M -Xmx${{SERVER_MEMORY}}M -jar server.jar")?;
+        match launcher {
+            Some(launcher) => ctx.plan.resolved(format!("Start script: generated for {} in place of the pack's {}, needs java {}", loader.mod_loader, launcher.kind, loader.java_version)),
+            None => ctx.plan.resolved(format!("Start script: generated for {}, needs java {}", loader.mod_loader, loader.java_version)),
+        }
 
-                #[cfg(target_os = "linux")]
-                make_mc_start_script_executable()?;
-            }
-            ModLoader::Fabric { .. } => {
-                todo!()
-            }
-            ModLoader::Quilt { .. } => {
-                todo!()
+        write_mc_start_script(server_dir, &script)?;
+    }
+
+    Ok(())
+}
+
+// Brings what is generated around an installed pack up to date with `options`, without touching the pack.
+pub(crate) async fn refresh<T: AsRef<Path>>(ctx: &Context, target_dir: T, options: &InstallOptions) -> color_eyre::Result<()> {
+    let target_dir = target_dir.as_ref();
+    let target_mcsi_dir = target_dir.join(".mcsi");
+
+    // Servers we didn't install keep their start script
+    if !target_mcsi_dir.join("manifest.json").is_file() {
+        debug!("No pack installed in {:?}, leaving {START_SCRIPT} alone", target_dir);
+    } else if ctx.is_dry_run() {
+        ctx.plan.change(format!("Regenerate {START_SCRIPT}"));
+    } else {
+        let mut manifest = PackManifest::load_from(&target_mcsi_dir)?;
+        ensure_server_start_script(ctx, target_dir, manifest.loader.as_ref(), &options.jvm)
+            .await?;
+
+        // Otherwise verify reports the regenerated files as corrupted
+        for file in [START_SCRIPT, USER_JVM_ARGS_FILE] {
+            if manifest.hashes.contains_key(file) && target_dir.join(file).is_file() {
+                manifest.hashes.insert(file.to_string(), sha1_file(target_dir.join(file))?);
             }
         }
+        manifest.save_to(&target_mcsi_dir)?;
     }
 
-    Ok(())
+    let players = resolve_players(ctx, &[target_dir], options)
+        .await?;
+    server_files::apply(ctx, target_dir, &options.server, &players)
+        .await?;
+    service::write_unit(ctx, target_dir, &options.service)
+        .await
+}
+
+pub(crate) async fn resolve_players(ctx: &Context, server_dirs: &[&Path], options: &InstallOptions) -> color_eyre::Result<ResolvedPlayers> {
+    server_files::resolve_players(ctx, &options.server, server_dirs, &MojangResolver::new(ctx))
+        .await
 }
 
-pub async fn post_process<T: AsRef<Path>>(target_dir: T) -> color_eyre::Result<()> {
+// Copies the staged pack into `target_dir`, with `players` resolved before the previous install was removed.
+pub(crate) async fn post_process<T: AsRef<Path>>(ctx: &Context, target_dir: T, staged: StagedPack, players: &ResolvedPlayers, options: &InstallOptions) -> color_eyre::Result<()> {
     info!("Finishing up...");
 
     let target_dir = target_dir.as_ref();
-    let work_dir = work_dir();
+    let work_dir = ctx.work_dir();
+    let StagedPack { source, loader, config } = staged;
 
-    recursive_copy_to_dir(&work_dir, &target_dir)
+    ensure_server_start_script(ctx, &work_dir, loader.as_ref(), &options.jvm)
+        .await?;
+
+    ctx.events.record_outcome(|outcome| {
+        outcome.source = Some(source.clone());
+        outcome.target_dir = Some(target_dir.to_string_lossy().to_string());
+    });
+
+    if ctx.is_dry_run() {
+        ctx.plan.change(format!("Copy the staged pack into {:?} and write its manifest", target_dir));
+        server_files::apply(ctx, target_dir, &options.server, players)
+            .await?;
+        service::write_unit(ctx, target_dir, &options.service)
+            .await?;
+        remove_dir_all(&work_dir)
+            .await?;
+        return Ok(());
+    }
+
+    // The server owns these once installed, a pack update must not reset them
+    for file in USER_FILES {
+        let staged_file = work_dir.join(file);
+        if target_dir.join(file).is_file() && staged_file.is_file() {
+            debug!("Keeping the installed {file}");
+            remove_file(staged_file)?;
+        }
+    }
+
+    // Only the files we staged belong to the pack, anything already in the target dir is left alone.
+    let pack_manifest = PackManifest::builder()
+        .with_files_from_dir(&work_dir)?
+        .exclude_files_from_dir(format!(".mcsi{MAIN_SEPARATOR}"))
+        .exclude_files(&USER_FILES)
+        .with_source(source)
+        .with_loader(loader)
+        .with_config(config)
+        .finish();
+
+    ctx.events.stage(Stage::Install, recursive_copy_to_dir(ctx, &work_dir, &target_dir))
         .await?;
 
     remove_dir_all(&work_dir)
@@ -242,28 +484,18 @@ pub async fn post_process<T: AsRef<Path>>(target_dir: T) -> color_eyre::Result<(
             .await?;
     }
 
-    #[cfg(target_os = "linux")]
-    let pack_manifest = PackManifest::builder()
-        .with_files_from_dir(target_dir)
-        .exclude_files_from_dir(".mcsi/")
-        .finish();
-
-    #[cfg(target_os = "windows")]
-        let pack_manifest = PackManifest::builder()
-        .with_files_from_dir(target_dir)
-        .exclude_files_from_dir(".mcsi\\")
-        .finish();
-
     pack_manifest.save_to(&target_mcsi_dir)?;
 
-    recursive_copy_to_dir(logs_dir(), target_logs_dir)
+    server_files::apply(ctx, target_dir, &options.server, players)
+        .await?;
+    service::write_unit(ctx, target_dir, &options.service)
+        .await?;
+
+    recursive_copy_to_dir(ctx, logs_dir(), target_logs_dir)
         .await?;
 
-    let src_mcsi_dir = mcsi_dir();
-    if !src_mcsi_dir.eq(&target_mcsi_dir) {
-        remove_dir_all(src_mcsi_dir)
-            .await?;
-    }
+    remove_dir_all(ctx.staging_dir())
+        .await?;
 
     info!("Server is installed!");
     Ok(())
@@ -271,11 +503,14 @@ pub async fn post_process<T: AsRef<Path>>(target_dir: T) -> color_eyre::Result<(
 
 #[cfg(test)]
 mod tests {
+    use std::fs::{create_dir_all, remove_dir_all, write};
     use std::path::PathBuf;
     use log::LevelFilter;
     use simplelog::{ColorChoice, CombinedLogger, TermLogger, TerminalMode, WriteLogger};
+    use crate::context::Context;
     use crate::fs_utils::get_log_file;
-    use crate::modpack::flame;
+    use crate::modpack::backup::BackupFormat;
+    use crate::modpack::{flame, uninstall, InstallOptions, PackManifest};
 
     fn init_logging() {
         CombinedLogger::init(
@@ -295,12 +530,53 @@ mod tests {
             .join("tests")
             .join(format!("flame-{project_id}-{version}"));
 
-        flame::handle_flame(api_key, project_id, version.to_string(), target_dir, skip_server_pack)
+        let options = InstallOptions {
+            backup: Some(BackupFormat::Dir),
+            ..InstallOptions::default()
+        };
+        flame::handle_flame(&Context::builder().finish()?, api_key, project_id, version.to_string(), target_dir, skip_server_pack, &options)
             .await?;
 
         Ok(())
     }
 
+    #[tokio::test]
+    async fn uninstall_keeps_user_files() {
+        let target_dir = PathBuf::from("./.mcsi-test-dir")
+            .join("tests")
+            .join("uninstall");
+        if target_dir.is_dir() {
+            remove_dir_all(&target_dir).unwrap();
+        }
+        create_dir_all(target_dir.join("mods")).unwrap();
+        create_dir_all(target_dir.join("config").join("nested")).unwrap();
+        create_dir_all(target_dir.join(".mcsi")).unwrap();
+
+        write(target_dir.join("mods").join("a.jar"), "a").unwrap();
+        write(target_dir.join("config").join("nested").join("b.toml"), "b").unwrap();
+        write(target_dir.join("server.properties"), "motd=A Server").unwrap();
+        PackManifest::builder()
+            .with_files_from_dir(&target_dir)
+            .unwrap()
+            .exclude_files_from_dir(".mcsi")
+            .finish()
+            .save_to(target_dir.join(".mcsi"))
+            .unwrap();
+
+        create_dir_all(target_dir.join("world")).unwrap();
+        write(target_dir.join("world").join("level.dat"), "world").unwrap();
+        write(target_dir.join("config").join("user.toml"), "user").unwrap();
+
+        uninstall(&Context::builder().finish().unwrap(), &target_dir, None).await.unwrap();
+
+        assert!(!target_dir.join("mods").exists());
+        assert!(!target_dir.join("config").join("nested").exists());
+        assert!(target_dir.join("config").join("user.toml").is_file());
+        assert!(target_dir.join("world").join("level.dat").is_file());
+        assert!(target_dir.join("server.properties").is_file());
+        assert!(!target_dir.join(".mcsi").join("manifest.json").exists());
+    }
+
     #[tokio::test]
     async fn download_flame_pack_with_no_server_pack_1() {
         let project_id = 351508;
@@ -0,0 +1,315 @@
+use std::path::Path;
+use log::{debug, info, warn};
+use tokio::fs::{create_dir_all, remove_dir_all};
+use crate::context::Context;
+use crate::events::Stage;
+use crate::fs_utils::ensure_dir;
+use crate::modloader::{install_mod_loader, LoaderInstall, ModLoader};
+use crate::modpack::jvm::JvmConfig;
+use crate::modpack::server_pack::{Marker, ScriptPurpose, ServerPack};
+use crate::modpack::{check_manifest, post_process, resolve_players, InstallOptions, PackManifest, PackSource};
+use crate::version::McVersion;
+
+const LOADER_JAR_PREFIXES: [&str; 6] = ["forge-", "neoforge-", "fabric-server-", "fabric-installer", "quilt-server-", "quilt-installer"];
+
+// Somewhere packs are installed from, driven through its phases by `install` and `stage`.
+// The pipeline is generic over the source, so there is no need for the futures to be `Send`
+#[allow(async_fn_in_trait)]
+pub trait ModpackSource {
+    async fn resolve(&mut self, ctx: &Context) -> color_eyre::Result<()>;
+
+    async fn fetch(&mut self, ctx: &Context, work_dir: &Path) -> color_eyre::Result<()>;
+
+    // Runs anything the pack needs once it is fetched, like an external installer.
+    async fn install(&mut self, _ctx: &Context, _work_dir: &Path) -> color_eyre::Result<()> {
+        Ok(())
+    }
+
+    // The mod loader for the pipeline to install, only known once the pack is fetched.
+    fn mod_loader(&self) -> Option<(ModLoader, Option<McVersion>)> {
+        None
+    }
+
+    fn pack_source(&self) -> color_eyre::Result<PackSource>;
+}
+
+// A pack resolved and installed into the work dir, ready to be copied into the target dir.
+#[derive(Clone, Debug)]
+pub struct StagedPack {
+    pub source: PackSource,
+    // The mod loader installed for the pack, `None` when the pack brings its own
+    pub loader: Option<LoaderInstall>,
+    pub config: Option<String>,
+}
+
+pub async fn install<S: ModpackSource, T: AsRef<Path>>(
+    ctx: &Context,
+    mut source: S,
+    target_dir: T,
+    options: &InstallOptions,
+) -> color_eyre::Result<()> {
+    let _enforced = ctx.lock.enforce(options.locked.clone())?;
+
+    resolve(ctx, &mut source).await?;
+    install_resolved(ctx, source, target_dir.as_ref(), options).await
+}
+
+// Installs the pack unless `target_dir` already holds the exact version it resolves to, returns whether it did.
+pub async fn install_if_changed<S: ModpackSource, T: AsRef<Path>>(
+    ctx: &Context,
+    mut source: S,
+    target_dir: T,
+    options: &InstallOptions,
+) -> color_eyre::Result<bool> {
+    let target_dir = target_dir.as_ref();
+    let _enforced = ctx.lock.enforce(options.locked.clone())?;
+
+    let installed = PackManifest::load_from(target_dir.join(".mcsi"))
+        .ok()
+        .and_then(|manifest| manifest.source);
+
+    // A server that is already installed still starts while the source is unreachable
+    if let Err(err) = resolve(ctx, &mut source).await {
+        if installed.is_none() {
+            return Err(err);
+        }
+
+        warn!("Couldn't resolve the pack, keeping the installed one: {err}");
+        return Ok(false);
+    }
+    let requested = source.pack_source()?;
+
+    if installed.as_ref() == Some(&requested) {
+        info!("The installed pack is up to date");
+        ctx.plan.resolved(format!("Pack: {requested:?} is already installed"));
+        return Ok(false);
+    }
+
+    install_resolved(ctx, source, target_dir, options).await?;
+    Ok(true)
+}
+
+async fn install_resolved<S: ModpackSource>(
+    ctx: &Context,
+    mut source: S,
+    target_dir: &Path,
+    options: &InstallOptions,
+) -> color_eyre::Result<()> {
+    let staged = stage_resolved(ctx, &mut source, None, &options.jvm).await?;
+    let lock = ctx.lock.finish(staged.source.clone(), ctx.is_dry_run())?;
+
+    // Only touch the current install once everything is staged, players included
+    let players = resolve_players(ctx, &[target_dir, &ctx.work_dir()], options)
+        .await?;
+    check_manifest(ctx, target_dir, options.backup).await?;
+    post_process(ctx, target_dir, staged, &players, options).await?;
+    lock.save_to(ctx, target_dir)?;
+
+    Ok(())
+}
+
+pub async fn stage<S: ModpackSource>(ctx: &Context, source: &mut S, jvm: &JvmConfig) -> color_eyre::Result<StagedPack> {
+    resolve(ctx, source).await?;
+    stage_resolved(ctx, source, None, jvm).await
+}
+
+pub(crate) async fn resolve<S: ModpackSource>(ctx: &Context, source: &mut S) -> color_eyre::Result<()> {
+    ensure_dir(ctx.staging_dir())?;
+    ctx.events.stage(Stage::Resolve, source.resolve(ctx)).await
+}
+
+// Installs the resolved pack into the work dir, with `loader` in place of the mod loader of the pack if given.
+pub(crate) async fn stage_resolved<S: ModpackSource>(
+    ctx: &Context,
+    source: &mut S,
+    loader: Option<(ModLoader, Option<McVersion>)>,
+    jvm: &JvmConfig,
+) -> color_eyre::Result<StagedPack> {
+    let work_dir = ctx.work_dir();
+    if work_dir.exists() {
+        remove_dir_all(&work_dir)
+            .await?;
+    }
+    create_dir_all(&work_dir)
+        .await?;
+
+    ctx.events.stage(Stage::Download, source.fetch(ctx, &work_dir)).await?;
+    source.install(ctx, &work_dir).await?;
+
+    let mod_loader = match loader {
+        Some((mod_loader, mc_version)) => {
+            info!("Overriding mod loader with {mod_loader}...");
+            remove_pack_loader(&work_dir)?;
+            Some((mod_loader, mc_version))
+        }
+        // Packs started through a launcher name the loader it would install on first boot
+        None => source.mod_loader().or_else(|| {
+            let launcher = ServerPack::at(&work_dir).ok()?.launcher?;
+            let (mod_loader, mc_version) = launcher.loader()?;
+            info!("Installing {mod_loader} ahead of time, in place of the pack's {}", launcher.kind);
+
+            Some((mod_loader, mc_version))
+        }),
+    };
+
+    let loader = match mod_loader {
+        Some((mod_loader, mc_version)) => {
+            ctx.lock.record_mod_loader(&mod_loader)?;
+            let loader = install_mod_loader(ctx, &mod_loader, mc_version, jvm.java(), &work_dir)
+                .await?;
+            Some(loader)
+        }
+        None => None,
+    };
+
+    Ok(StagedPack {
+        source: source.pack_source()?,
+        loader,
+        config: None,
+    })
+}
+
+// Removes a mod loader the pack installed itself and the scripts starting it, so the generated start script is used.
+fn remove_pack_loader(work_dir: &Path) -> color_eyre::Result<()> {
+    let server_pack = ServerPack::at(work_dir)?;
+    let jars = server_pack.markers.iter()
+        .filter_map(|marker| match marker {
+            Marker::Jar(name) if is_loader_jar(name) => Some(work_dir.join(name)),
+            _ => None,
+        });
+    let scripts = server_pack.scripts.iter()
+        .filter(|script| script.purpose != ScriptPurpose::Other)
+        .map(|script| work_dir.join(&script.path));
+
+    for file in jars.chain(scripts) {
+        debug!("Removing {:?} of the pack's mod loader", file);
+        std::fs::remove_file(file)?;
+    }
+
+    let libraries = work_dir.join("libraries");
+    if libraries.is_dir() {
+        std::fs::remove_dir_all(libraries)?;
+    }
+
+    Ok(())
+}
+
+// Other jars at the root, like a vanilla `server.jar` or a helper of the pack, are kept
+fn is_loader_jar(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+
+    LOADER_JAR_PREFIXES.iter()
+        .any(|prefix| name.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::path::{Path, PathBuf};
+    use color_eyre::eyre::eyre;
+    use crate::context::Context;
+    use crate::modpack::pipeline::{install_if_changed, remove_pack_loader, ModpackSource};
+    use crate::modpack::{InstallOptions, PackSource};
+
+    // Stands in for a pack whose version `version` has a single mod.
+    struct FakeSource {
+        version: usize,
+        reachable: bool,
+    }
+
+    impl ModpackSource for FakeSource {
+        async fn resolve(&mut self, _ctx: &Context) -> color_eyre::Result<()> {
+            if !self.reachable {
+                return Err(eyre!("unreachable"));
+            }
+
+            Ok(())
+        }
+
+        async fn fetch(&mut self, _ctx: &Context, work_dir: &Path) -> color_eyre::Result<()> {
+            create_dir_all(work_dir.join("mods"))?;
+            write(work_dir.join("mods").join(format!("pack-{}.jar", self.version)), "")?;
+
+            Ok(())
+        }
+
+        fn pack_source(&self) -> color_eyre::Result<PackSource> {
+            Ok(PackSource::Ftb { pack_id: 1, version_id: self.version })
+        }
+    }
+
+    #[tokio::test]
+    async fn reinstalls_only_a_changed_version() {
+        let target_dir = PathBuf::from("./.mcsi-test-dir")
+            .join("tests")
+            .join("install-if-changed");
+        if target_dir.is_dir() {
+            remove_dir_all(&target_dir).unwrap();
+        }
+        let ctx = Context::builder().finish().unwrap();
+        let options = InstallOptions::default();
+        let install = |version, reachable| install_if_changed(&ctx, FakeSource { version, reachable }, &target_dir, &options);
+
+        assert!(install(1, false).await.is_err());
+        assert!(install(1, true).await.unwrap());
+        assert!(target_dir.join("mods").join("pack-1.jar").is_file());
+
+        assert!(!install(1, true).await.unwrap());
+        assert!(!install(2, false).await.unwrap());
+        assert!(target_dir.join("mods").join("pack-1.jar").is_file());
+
+        assert!(install(2, true).await.unwrap());
+        assert!(!target_dir.join("mods").join("pack-1.jar").exists());
+        assert!(target_dir.join("mods").join("pack-2.jar").is_file());
+    }
+
+    #[tokio::test]
+    async fn stages_concurrent_installs_apart() {
+        let tests_dir = PathBuf::from("./.mcsi-test-dir")
+            .join("tests");
+        let targets = [tests_dir.join("concurrent-install-1"), tests_dir.join("concurrent-install-2")];
+        for target_dir in &targets {
+            if target_dir.is_dir() {
+                remove_dir_all(target_dir).unwrap();
+            }
+        }
+        let contexts = [Context::builder().finish().unwrap(), Context::builder().finish().unwrap()];
+        assert_ne!(contexts[0].staging_dir(), contexts[1].staging_dir());
+        let options = InstallOptions::default();
+
+        let (first, second) = tokio::join!(
+            install_if_changed(&contexts[0], FakeSource { version: 1, reachable: true }, &targets[0], &options),
+            install_if_changed(&contexts[1], FakeSource { version: 2, reachable: true }, &targets[1], &options),
+        );
+        assert!(first.unwrap() && second.unwrap());
+
+        assert!(targets[0].join("mods").join("pack-1.jar").is_file());
+        assert!(!targets[0].join("mods").join("pack-2.jar").exists());
+        assert!(targets[1].join("mods").join("pack-2.jar").is_file());
+        assert!(!targets[1].join("mods").join("pack-1.jar").exists());
+    }
+
+    #[test]
+    fn removes_only_the_loader_of_the_pack() {
+        let work_dir = PathBuf::from("./.mcsi-test-dir")
+            .join("tests")
+            .join("remove-pack-loader");
+        if work_dir.is_dir() {
+            remove_dir_all(&work_dir).unwrap();
+        }
+        create_dir_all(work_dir.join("libraries")).unwrap();
+        create_dir_all(work_dir.join("mods")).unwrap();
+        for jar in ["forge-1.20.1-47.2.0-shim.jar", "Forge-1.12.2-14.23.5.2860.jar", "server.jar", "pack-helper.jar"] {
+            write(work_dir.join(jar), "").unwrap();
+        }
+
+        remove_pack_loader(&work_dir).unwrap();
+
+        assert!(!work_dir.join("forge-1.20.1-47.2.0-shim.jar").exists());
+        assert!(!work_dir.join("Forge-1.12.2-14.23.5.2860.jar").exists());
+        assert!(!work_dir.join("libraries").exists());
+        assert!(work_dir.join("server.jar").is_file());
+        assert!(work_dir.join("pack-helper.jar").is_file());
+        assert!(work_dir.join("mods").is_dir());
+    }
+}
@@ -0,0 +1,333 @@
+use std::path::Path;
+use chrono::Utc;
+use log::{info, warn};
+use md5::{Digest, Md5};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::fs::{read, write};
+use reqwest::{Client, StatusCode};
+use crate::context::Context;
+use crate::modpack::server_files::ServerSettings;
+
+pub const OPS_FILE: &str = "ops.json";
+pub const WHITELIST_FILE: &str = "whitelist.json";
+pub const BANNED_PLAYERS_FILE: &str = "banned-players.json";
+
+const DEFAULT_OP_LEVEL: u8 = 4;
+
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct Player {
+    // Hyphenated, like `b50ad385-829d-3141-a216-7e7d7539ba7f`
+    pub uuid: String,
+    pub name: String,
+}
+
+#[allow(async_fn_in_trait)]
+pub trait PlayerResolver {
+    async fn resolve(&self, player: &str) -> color_eyre::Result<Player>;
+}
+
+// Derives the UUIDs an offline-mode server gives players from their names.
+pub struct OfflineResolver;
+
+// Looks players up with Mojang, falling back to the offline UUID for names it doesn't know.
+pub struct MojangResolver {
+    client: Client,
+    base_url: String,
+}
+
+// The players of `ServerSettings`, resolved before anything is written.
+#[derive(Default, Clone, Debug)]
+pub struct ResolvedPlayers {
+    op_level: u8,
+    ops: Vec<Player>,
+    whitelist: Vec<Player>,
+    banned: Vec<Player>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Profile {
+    id: String,
+    name: String,
+}
+
+impl PlayerResolver for OfflineResolver {
+    async fn resolve(&self, player: &str) -> color_eyre::Result<Player> {
+        if parse_uuid(player).is_some() {
+            return Err(PlayerError::NameUnknown(player.to_string()))?;
+        }
+
+        Ok(Player {
+            uuid: offline_uuid(player),
+            name: player.to_string(),
+        })
+    }
+}
+
+impl MojangResolver {
+    pub fn new(ctx: &Context) -> Self {
+        MojangResolver {
+            client: ctx.client().clone(),
+            base_url: ctx.endpoints().mojang_api.clone(),
+        }
+    }
+
+    // `None` when Mojang has no such player.
+    async fn lookup(&self, path: String) -> color_eyre::Result<Option<Profile>> {
+        let response = self.client
+            .get(format!("{0}{path}", self.base_url))
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let body = response.error_for_status()?
+            .bytes()
+            .await?;
+
+        Ok(Some(serde_json::from_slice(&body)?))
+    }
+}
+
+impl PlayerResolver for MojangResolver {
+    async fn resolve(&self, player: &str) -> color_eyre::Result<Player> {
+        if let Some(uuid) = parse_uuid(player) {
+            let profile = self.lookup(format!("/minecraft/profile/lookup/{uuid}"))
+                .await?
+                .ok_or_else(|| PlayerError::NotFound(player.to_string()))?;
+            return profile_player(profile);
+        }
+
+        match self.lookup(format!("/minecraft/profile/lookup/name/{player}")).await? {
+            Some(profile) => profile_player(profile),
+            None => {
+                warn!("Mojang doesn't know {player}, using its offline UUID");
+                OfflineResolver.resolve(player)
+                    .await
+            }
+        }
+    }
+}
+
+fn profile_player(profile: Profile) -> color_eyre::Result<Player> {
+    let uuid = parse_uuid(&profile.id)
+        .ok_or_else(|| PlayerError::InvalidUuid(profile.id.clone()))?;
+
+    Ok(Player {
+        uuid,
+        name: profile.name,
+    })
+}
+
+// Looks up every player of `settings`, so a failed lookup leaves the lists untouched.
+pub async fn resolve_players<R: PlayerResolver>(settings: &ServerSettings, resolver: &R) -> color_eyre::Result<ResolvedPlayers> {
+    let op_level = settings.op_level.unwrap_or(DEFAULT_OP_LEVEL);
+    if !(1..=4).contains(&op_level) {
+        return Err(PlayerError::InvalidOpLevel(op_level))?;
+    }
+
+    Ok(ResolvedPlayers {
+        op_level,
+        ops: resolve_all(&settings.ops, resolver).await?,
+        whitelist: resolve_all(&settings.whitelist, resolver).await?,
+        banned: resolve_all(&settings.banned, resolver).await?,
+    })
+}
+
+async fn resolve_all<R: PlayerResolver>(players: &[String], resolver: &R) -> color_eyre::Result<Vec<Player>> {
+    let mut resolved = Vec::with_capacity(players.len());
+    for player in players {
+        resolved.push(resolver.resolve(player).await?);
+    }
+
+    Ok(resolved)
+}
+
+// Adds the players to `ops.json`, `whitelist.json` and `banned-players.json` in `target_dir`, keeping every other entry.
+pub async fn write_lists(target_dir: &Path, players: &ResolvedPlayers) -> color_eyre::Result<()> {
+    let level = players.op_level;
+    write_list(target_dir.join(OPS_FILE), &players.ops, true, |player| json!({
+        "uuid": player.uuid,
+        "name": player.name,
+        "level": level,
+        "bypassesPlayerLimit": false,
+    })).await?;
+
+    write_list(target_dir.join(WHITELIST_FILE), &players.whitelist, true, |player| json!({
+        "uuid": player.uuid,
+        "name": player.name,
+    })).await?;
+
+    // Bans are only added, so they keep when and why they were issued
+    let created = Utc::now().format("%Y-%m-%d %H:%M:%S %z").to_string();
+    write_list(target_dir.join(BANNED_PLAYERS_FILE), &players.banned, false, |player| json!({
+        "uuid": player.uuid,
+        "name": player.name,
+        "created": created,
+        "source": "Server",
+        "expires": "forever",
+        "reason": "Banned by an operator.",
+    })).await?;
+
+    Ok(())
+}
+
+async fn write_list(
+    file_path: impl AsRef<Path>,
+    players: &[Player],
+    replace: bool,
+    entry: impl Fn(&Player) -> Value,
+) -> color_eyre::Result<()> {
+    let file_path = file_path.as_ref();
+    if players.is_empty() {
+        return Ok(());
+    }
+
+    let mut entries: Vec<Value> = if file_path.is_file() {
+        serde_json::from_slice(&read(file_path).await?)?
+    } else {
+        Vec::new()
+    };
+
+    for player in players {
+        let existing = entries.iter()
+            .position(|entry| entry["uuid"].as_str().is_some_and(|uuid| uuid.eq_ignore_ascii_case(&player.uuid)));
+
+        match existing {
+            Some(index) if replace => entries[index] = entry(player),
+            Some(_) => {}
+            None => entries.push(entry(player)),
+        }
+    }
+
+    info!("Writing {} players to {:?}", players.len(), file_path.file_name().unwrap_or_default());
+    write(file_path, serde_json::to_vec_pretty(&entries)?)
+        .await?;
+
+    Ok(())
+}
+
+// The UUID an offline-mode server derives from `name`, a version 3 UUID of `OfflinePlayer:<name>`.
+pub fn offline_uuid(name: &str) -> String {
+    let mut hash: [u8; 16] = Md5::digest(format!("OfflinePlayer:{name}")).into();
+    hash[6] = hash[6] & 0x0f | 0x30;
+    hash[8] = hash[8] & 0x3f | 0x80;
+
+    let hex = hash.iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+
+    hyphenate(&hex)
+}
+
+fn parse_uuid(uuid: &str) -> Option<String> {
+    let hex = uuid.replace('-', "").to_ascii_lowercase();
+
+    (hex.len() == 32 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+        .then(|| hyphenate(&hex))
+}
+
+fn hyphenate(hex: &str) -> String {
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+#[derive(Error, Debug)]
+pub enum PlayerError {
+    #[error("Can't tell the name of `{0}` without looking it up, use the name instead")]
+    NameUnknown(String),
+    #[error("Mojang has no player `{0}`")]
+    NotFound(String),
+    #[error("`{0}` is not a UUID")]
+    InvalidUuid(String),
+    #[error("Op level {0} is not between 1 and 4")]
+    InvalidOpLevel(u8),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use serde_json::Value;
+    use crate::context::Context;
+    use crate::http::tests::stand_in_server;
+    use crate::http::{Endpoints, HttpConfig};
+    use crate::modpack::players::{offline_uuid, resolve_players, write_lists, MojangResolver, OfflineResolver, Player, PlayerResolver};
+    use crate::modpack::server_files::ServerSettings;
+
+    struct StaffResolver;
+
+    impl PlayerResolver for StaffResolver {
+        async fn resolve(&self, player: &str) -> color_eyre::Result<Player> {
+            Ok(Player {
+                uuid: format!("00000000-0000-0000-0000-{:0>12}", player.len()),
+                name: player.to_string(),
+            })
+        }
+    }
+
+    #[test]
+    fn derives_offline_uuids() {
+        assert_eq!(offline_uuid("Notch"), "b50ad385-829d-3141-a216-7e7d7539ba7f");
+    }
+
+    #[tokio::test]
+    async fn adds_players_and_keeps_other_entries() {
+        let dir = PathBuf::from("./.mcsi-test-dir")
+            .join("tests")
+            .join("players");
+        if dir.is_dir() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("ops.json"), r#"[{"uuid":"00000000-0000-0000-0000-000000000003","name":"Bob","level":1,"bypassesPlayerLimit":true},{"uuid":"11111111-1111-1111-1111-111111111111","name":"Owner","level":4,"bypassesPlayerLimit":true}]"#).unwrap();
+
+        let settings = ServerSettings {
+            ops: vec![String::from("Bob"), String::from("Alice")],
+            op_level: Some(3),
+            whitelist: vec![String::from("Carol")],
+            ..ServerSettings::default()
+        };
+        let players = resolve_players(&settings, &StaffResolver).await.unwrap();
+        write_lists(&dir, &players).await.unwrap();
+
+        let ops: Vec<Value> = serde_json::from_slice(&std::fs::read(dir.join("ops.json")).unwrap()).unwrap();
+        assert_eq!(ops.len(), 3);
+        assert_eq!(ops[0]["level"], 3);
+        assert_eq!(ops[1]["name"], "Owner");
+        assert_eq!(ops[2]["name"], "Alice");
+
+        let whitelist: Vec<Value> = serde_json::from_slice(&std::fs::read(dir.join("whitelist.json")).unwrap()).unwrap();
+        assert_eq!(whitelist[0]["uuid"], "00000000-0000-0000-0000-000000000005");
+        assert!(!dir.join("banned-players.json").exists());
+
+        let settings = ServerSettings {
+            ops: vec![String::from("Dave")],
+            op_level: Some(5),
+            ..ServerSettings::default()
+        };
+        assert!(resolve_players(&settings, &OfflineResolver).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_offline_uuids_only_for_unknown_names() {
+        let (base_url, _) = stand_in_server(vec![
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        ]);
+        let ctx = Context::builder()
+            .with_http(HttpConfig {
+                endpoints: Endpoints {
+                    mojang_api: base_url,
+                    ..Endpoints::default()
+                },
+                ..HttpConfig::default()
+            })
+            .finish()
+            .unwrap();
+        let resolver = MojangResolver::new(&ctx);
+
+        assert_eq!(resolver.resolve("Notch").await.unwrap().uuid, offline_uuid("Notch"));
+        assert!(resolver.resolve("Notch").await.is_err());
+    }
+}
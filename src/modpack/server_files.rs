@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use log::info;
+use serde::{Deserialize, Deserializer};
+use thiserror::Error;
+use tokio::fs::{read_to_string, write};
+use crate::context::Context;
+use crate::modpack::players;
+use crate::modpack::players::{OfflineResolver, PlayerResolver, ResolvedPlayers, BANNED_PLAYERS_FILE, OPS_FILE, WHITELIST_FILE};
+
+pub const EULA_FILE: &str = "eula.txt";
+pub const SERVER_PROPERTIES_FILE: &str = "server.properties";
+
+// Files the server owns once it is installed, kept across updates instead of belonging to the pack.
+pub const USER_FILES: [&str; 5] = [SERVER_PROPERTIES_FILE, EULA_FILE, OPS_FILE, WHITELIST_FILE, BANNED_PLAYERS_FILE];
+
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ServerSettings {
+    // Agree to the minecraft EULA, see https://aka.ms/MinecraftEULA
+    #[serde(default)]
+    pub accept_eula: bool,
+    // Keys set in `server.properties`, all others are left as they are
+    #[serde(default, deserialize_with = "property_values")]
+    pub properties: BTreeMap<String, String>,
+    #[serde(default)]
+    pub ops: Vec<String>,
+    // Permission level of the ops, 1 to 4, defaults to 4
+    pub op_level: Option<u8>,
+    #[serde(default)]
+    pub whitelist: Vec<String>,
+    #[serde(default)]
+    pub banned: Vec<String>,
+}
+
+impl ServerSettings {
+    // Keys and the op level of `other` win, player lists are joined.
+    pub fn merge(mut self, other: ServerSettings) -> Self {
+        self.accept_eula |= other.accept_eula;
+        self.properties.extend(other.properties);
+        self.op_level = other.op_level.or(self.op_level);
+
+        for (players, other) in [(&mut self.ops, other.ops), (&mut self.whitelist, other.whitelist), (&mut self.banned, other.banned)] {
+            for player in other {
+                if !players.contains(&player) {
+                    players.push(player);
+                }
+            }
+        }
+
+        self
+    }
+}
+
+// Resolves the players of `settings` with `resolver`, before anything of the install is touched.
+// `server_dirs` are searched in order for the `server.properties` the server will run with.
+pub async fn resolve_players<R: PlayerResolver>(
+    ctx: &Context,
+    settings: &ServerSettings,
+    server_dirs: &[&Path],
+    resolver: &R,
+) -> color_eyre::Result<ResolvedPlayers> {
+    if ctx.is_dry_run() {
+        return Ok(ResolvedPlayers::default());
+    }
+
+    // Names only map to the UUIDs mojang knows when the server checks them with mojang too
+    if is_offline(settings, server_dirs).await? {
+        players::resolve_players(settings, &OfflineResolver)
+            .await
+    } else {
+        players::resolve_players(settings, resolver)
+            .await
+    }
+}
+
+async fn is_offline(settings: &ServerSettings, server_dirs: &[&Path]) -> color_eyre::Result<bool> {
+    if let Some(online_mode) = settings.properties.get("online-mode") {
+        return Ok(online_mode == "false");
+    }
+
+    let properties = server_dirs.iter()
+        .map(|dir| dir.join(SERVER_PROPERTIES_FILE))
+        .find(|file| file.is_file());
+    let Some(properties) = properties else { return Ok(false) };
+
+    Ok(read_to_string(&properties).await?
+        .lines()
+        .any(|line| line.replace(' ', "") == "online-mode=false"))
+}
+
+// Writes `eula.txt`, the configured `server.properties` keys and the `players` into `target_dir`.
+pub async fn apply<T: AsRef<Path>>(ctx: &Context, target_dir: T, settings: &ServerSettings, players: &ResolvedPlayers) -> color_eyre::Result<()> {
+    let target_dir = target_dir.as_ref();
+
+    if ctx.is_dry_run() {
+        if settings.accept_eula {
+            ctx.plan.change(format!("Accept the EULA in {EULA_FILE}"));
+        }
+        for (key, value) in &settings.properties {
+            ctx.plan.change(format!("Set {key}={value} in {SERVER_PROPERTIES_FILE}"));
+        }
+        for (players, file) in [(&settings.ops, OPS_FILE), (&settings.whitelist, WHITELIST_FILE), (&settings.banned, BANNED_PLAYERS_FILE)] {
+            if !players.is_empty() {
+                ctx.plan.change(format!("Add {} to {file}", players.join(", ")));
+            }
+        }
+        return Ok(());
+    }
+
+    if settings.accept_eula {
+        info!("Accepting the EULA...");
+        write(target_dir.join(EULA_FILE), "#By changing the setting below to TRUE you are indicating your agreement to our EULA (https://aka.ms/MinecraftEULA).\neula=true\n")
+            .await?;
+    }
+
+    if !settings.properties.is_empty() {
+        info!("Setting {} keys in {SERVER_PROPERTIES_FILE}...", settings.properties.len());
+        let file_path = target_dir.join(SERVER_PROPERTIES_FILE);
+        let current = if file_path.is_file() {
+            read_to_string(&file_path).await?
+        } else {
+            String::new()
+        };
+
+        write(&file_path, set_properties(&current, &settings.properties))
+            .await?;
+    }
+
+    players::write_lists(target_dir, players)
+        .await?;
+
+    Ok(())
+}
+
+// Replaces the values of `properties` in place, appending the keys that aren't set yet.
+fn set_properties(current: &str, properties: &BTreeMap<String, String>) -> String {
+    let mut remaining = properties.clone();
+
+    let mut lines = current.lines()
+        .map(|line| {
+            let key = line.split_once('=')
+                .map(|(key, _)| key.trim())
+                .filter(|_| !line.trim_start().starts_with(['#', '!']));
+
+            match key.and_then(|key| remaining.remove_entry(key)) {
+                Some((key, value)) => format!("{key}={value}"),
+                None => line.to_string(),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    lines.extend(remaining.into_iter().map(|(key, value)| format!("{key}={value}")));
+
+    lines.into_iter()
+        .map(|line| format!("{line}\n"))
+        .collect()
+}
+
+pub fn parse_property(property: &str) -> Result<(String, String), ServerFilesError> {
+    property.split_once('=')
+        .filter(|(key, _)| !key.trim().is_empty())
+        .map(|(key, value)| (key.trim().to_string(), value.to_string()))
+        .ok_or_else(|| ServerFilesError::InvalidProperty(property.to_string()))
+}
+
+// Property values may be written as any toml value, like `server-port = 25565`.
+fn property_values<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<String, String>, D::Error> {
+    let values = BTreeMap::<String, toml::Value>::deserialize(deserializer)?;
+
+    Ok(values.into_iter()
+        .map(|(key, value)| match value {
+            toml::Value::String(value) => (key, value),
+            value => (key, value.to_string()),
+        })
+        .collect())
+}
+
+#[derive(Error, Debug)]
+pub enum ServerFilesError {
+    #[error("`{0}` is not a server property like `motd=My Server`")]
+    InvalidProperty(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use crate::modpack::server_files::{parse_property, set_properties, ServerSettings};
+
+    #[test]
+    fn sets_properties_and_keeps_the_rest() {
+        let settings: ServerSettings = toml::from_str(r#"
+            accept_eula = true
+
+            [properties]
+            motd = "Modded = fun"
+            server-port = 25570
+            online-mode = false
+        "#).unwrap();
+        let settings = ServerSettings {
+            ops: vec![String::from("Alice"), String::from("Bob")],
+            ..settings
+        };
+        assert!(settings.accept_eula);
+
+        let current = "#Minecraft server properties\ndifficulty=easy\nmotd=A Minecraft Server\nserver-port=25565\n";
+        assert_eq!(
+            set_properties(current, &settings.properties),
+            "#Minecraft server properties\ndifficulty=easy\nmotd=Modded = fun\nserver-port=25570\nonline-mode=false\n",
+        );
+
+        let cli = ServerSettings {
+            properties: BTreeMap::from([parse_property("difficulty=hard").unwrap(), parse_property("motd=From the cli").unwrap()]),
+            ops: vec![String::from("Alice")],
+            ..ServerSettings::default()
+        };
+        let merged = cli.merge(settings);
+        assert!(merged.accept_eula);
+        assert_eq!(merged.properties["motd"], "Modded = fun");
+        assert_eq!(merged.properties["difficulty"], "hard");
+        assert_eq!(merged.ops, ["Alice", "Bob"]);
+
+        assert!(parse_property("=value").is_err());
+        assert!(parse_property("motd").is_err());
+    }
+}
@@ -0,0 +1,377 @@
+use std::cmp::Reverse;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use log::{info, warn};
+use serde::Serialize;
+use walkdir::WalkDir;
+use crate::context::Context;
+use crate::fs_utils::get_closest_common_parent;
+use crate::modpack::jvm::USER_JVM_ARGS_FILE;
+use crate::modpack::launcher::{PackLauncher, SERVER_STARTER_CONFIG, VARIABLES_FILE};
+use crate::modpack::service::START_SCRIPT;
+
+// How far below the extracted archive the server files are looked for.
+const MAX_DEPTH: usize = 4;
+
+// Usual names of the script starting the server, in the order they are preferred.
+const START_SCRIPT_NAMES: [&str; 3] = ["startserver", "start", "run"];
+
+// Dirs that only ever hold files of the server, never the server itself.
+const CONTENT_DIRS: [&str; 6] = ["mods", "config", "libraries", "defaultconfigs", "kubejs", "world"];
+
+// What an extracted server pack looks like: where its files start and how it is started.
+#[derive(Serialize, Clone, Debug)]
+pub struct ServerPack {
+    #[serde(skip)]
+    pub root: PathBuf,
+    // What identified the root as the server files
+    pub markers: Vec<Marker>,
+    pub scripts: Vec<Script>,
+    // The launcher the pack's start script follows, if it is a known one
+    pub launcher: Option<PackLauncher>,
+}
+
+// A file or dir only found next to the server files.
+#[derive(Serialize, Eq, PartialEq, Clone, Debug)]
+#[serde(tag = "kind", content = "name", rename_all = "snake_case")]
+pub enum Marker {
+    ModsDir,
+    ConfigDir,
+    LibrariesDir,
+    Jar(String),
+    StartScript(String),
+    // ServerPackCreator config
+    VariablesTxt,
+    ServerStarterConfig,
+    UserJvmArgs,
+    ServerProperties,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Script {
+    pub path: PathBuf,
+    pub shell: Shell,
+    pub purpose: ScriptPurpose,
+}
+
+#[derive(Serialize, Eq, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Shell {
+    Sh,
+    Bat,
+    Ps1,
+}
+
+#[derive(Serialize, Eq, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptPurpose {
+    Start,
+    // Only installs the server or the mod loader
+    Install,
+    // Anything else, like a helper the start script calls
+    Other,
+}
+
+impl ServerPack {
+    // Finds the server files in an extracted archive, wherever they are nested.
+    pub(crate) fn find<T: AsRef<Path>>(dir: T) -> color_eyre::Result<Self> {
+        let dir = dir.as_ref();
+        let mut best: Option<((u32, Reverse<usize>), ServerPack)> = None;
+
+        let candidates = WalkDir::new(dir)
+            .max_depth(MAX_DEPTH)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|entry| entry.depth() == 0 || !is_content_dir(entry.path()));
+
+        for entry in candidates {
+            let entry = entry?;
+            if !entry.file_type().is_dir() {
+                continue;
+            }
+
+            let pack = ServerPack::at(entry.path())?;
+            let key = (pack.score(), Reverse(entry.depth()));
+            if key.0 > 0 && best.as_ref().is_none_or(|(best_key, _)| key > *best_key) {
+                best = Some((key, pack));
+            }
+        }
+
+        match best {
+            Some((_, pack)) => Ok(pack),
+            None => {
+                warn!("No server files found in {:?}, using the closest common parent", dir);
+                ServerPack::at(get_closest_common_parent(dir)?)
+            }
+        }
+    }
+
+    // Analyzes `root` as the server files, without looking for them any deeper.
+    pub(crate) fn at<T: AsRef<Path>>(root: T) -> color_eyre::Result<Self> {
+        let root = root.as_ref();
+        let mut entries = std::fs::read_dir(root)?
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        let mut markers = Vec::new();
+        let mut scripts = Vec::new();
+
+        for entry in entries {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().to_string();
+            let lower = name.to_ascii_lowercase();
+
+            if path.is_dir() {
+                match lower.as_str() {
+                    "mods" => markers.push(Marker::ModsDir),
+                    "config" => markers.push(Marker::ConfigDir),
+                    "libraries" => markers.push(Marker::LibrariesDir),
+                    _ => {}
+                }
+                continue;
+            }
+
+            match lower.as_str() {
+                // Ours, written after the pack is analyzed
+                START_SCRIPT => {}
+                VARIABLES_FILE => markers.push(Marker::VariablesTxt),
+                SERVER_STARTER_CONFIG => markers.push(Marker::ServerStarterConfig),
+                USER_JVM_ARGS_FILE => markers.push(Marker::UserJvmArgs),
+                "server.properties" => markers.push(Marker::ServerProperties),
+                _ if lower.ends_with(".jar") => markers.push(Marker::Jar(name)),
+                _ => if let Some(shell) = Shell::of(&lower) {
+                    let purpose = ScriptPurpose::of(&path, &lower);
+                    if purpose == ScriptPurpose::Start {
+                        markers.push(Marker::StartScript(name.clone()));
+                    }
+
+                    scripts.push(Script {
+                        path: PathBuf::from(name),
+                        shell,
+                        purpose,
+                    });
+                },
+            }
+        }
+
+        let start_scripts = scripts.iter()
+            .filter(|script| script.shell == Shell::Sh && script.purpose == ScriptPurpose::Start)
+            .map(|script| script.path.as_path())
+            .collect::<Vec<_>>();
+        // A broken launcher config only costs installing the loader ahead of time
+        let launcher = PackLauncher::detect(root, &start_scripts)
+            .unwrap_or_else(|err| {
+                warn!("Can't read the launcher config of the pack, leaving it to the pack: {err}");
+                None
+            });
+
+        Ok(ServerPack {
+            root: root.to_path_buf(),
+            markers,
+            scripts,
+            launcher,
+        })
+    }
+
+    // The script starting the server with `shell`, by its usual names before anything else that starts it.
+    pub(crate) fn start_script(&self, shell: Shell) -> Option<&Script> {
+        let start_scripts = self.scripts.iter()
+            .filter(|script| script.shell == shell && script.purpose == ScriptPurpose::Start);
+
+        // Scripts without a usual name keep their order after the ones with one
+        start_scripts.min_by_key(|script| {
+            let stem = script.path.file_stem()
+                .map(|stem| stem.to_string_lossy().to_ascii_lowercase())
+                .unwrap_or_default();
+
+            START_SCRIPT_NAMES.iter()
+                .position(|name| *name == stem)
+                .unwrap_or(START_SCRIPT_NAMES.len())
+        })
+    }
+
+    pub(crate) fn report(&self, ctx: &Context) {
+        if self.markers.is_empty() {
+            info!("Server files in {:?}, nothing identifies them as a server", self.root);
+        } else {
+            let markers = self.markers.iter()
+                .map(Marker::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
+            info!("Server files in {:?}, found {markers}", self.root);
+        }
+
+        for script in &self.scripts {
+            info!("Script {:?}: {:?} {:?}", script.path, script.shell, script.purpose);
+        }
+
+        if let Some(launcher) = &self.launcher {
+            match &launcher.mod_loader {
+                Some(mod_loader) => info!("The pack starts through its {}, which installs {mod_loader} on first boot", launcher.kind),
+                None => info!("The pack starts through its {}", launcher.kind),
+            }
+        }
+
+        let pack = self.clone();
+        ctx.events.record_outcome(|outcome| outcome.server_pack = Some(pack));
+    }
+
+    fn score(&self) -> u32 {
+        self.markers.iter()
+            .map(Marker::weight)
+            .sum()
+    }
+}
+
+impl Marker {
+    fn weight(&self) -> u32 {
+        match self {
+            Marker::ModsDir | Marker::VariablesTxt | Marker::ServerStarterConfig => 4,
+            Marker::StartScript(_) | Marker::UserJvmArgs => 3,
+            Marker::ConfigDir | Marker::LibrariesDir | Marker::ServerProperties => 2,
+            Marker::Jar(_) => 1,
+        }
+    }
+}
+
+impl Display for Marker {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Marker::ModsDir => write!(f, "mods/"),
+            Marker::ConfigDir => write!(f, "config/"),
+            Marker::LibrariesDir => write!(f, "libraries/"),
+            Marker::Jar(name) | Marker::StartScript(name) => write!(f, "{name}"),
+            Marker::VariablesTxt => write!(f, "{VARIABLES_FILE}"),
+            Marker::ServerStarterConfig => write!(f, "{SERVER_STARTER_CONFIG}"),
+            Marker::UserJvmArgs => write!(f, "user_jvm_args.txt"),
+            Marker::ServerProperties => write!(f, "server.properties"),
+        }
+    }
+}
+
+impl Shell {
+    fn of(file_name: &str) -> Option<Self> {
+        match Path::new(file_name).extension()?.to_str()? {
+            "sh" => Some(Shell::Sh),
+            "bat" | "cmd" => Some(Shell::Bat),
+            "ps1" => Some(Shell::Ps1),
+            _ => None,
+        }
+    }
+}
+
+impl ScriptPurpose {
+    fn of(path: &Path, file_name: &str) -> Self {
+        if file_name.contains("install") {
+            return ScriptPurpose::Install;
+        }
+
+        if ["start", "run", "launch", "server"].iter().any(|name| file_name.contains(name)) {
+            return ScriptPurpose::Start;
+        }
+
+        // Anything else starts the server if it runs java itself
+        let content = std::fs::read_to_string(path).unwrap_or_default();
+        if content.lines().any(|line| line.trim_start().starts_with("java ") || line.contains("-jar ")) {
+            ScriptPurpose::Start
+        } else {
+            ScriptPurpose::Other
+        }
+    }
+}
+
+fn is_content_dir(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_ascii_lowercase())
+        .is_some_and(|name| CONTENT_DIRS.contains(&name.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use crate::modpack::server_pack::{Marker, ScriptPurpose, ServerPack, Shell};
+
+    fn create(dir: &Path, files: &[&str]) {
+        for file in files {
+            let path = dir.join(file);
+            if file.ends_with('/') {
+                std::fs::create_dir_all(path).unwrap();
+            } else {
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, "").unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn finds_server_files_by_their_markers() {
+        let dir = PathBuf::from("./.mcsi-test-dir")
+            .join("tests")
+            .join("server-pack");
+        if dir.is_dir() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        // A single folder next to loose files is the root, not the folder
+        let loose = dir.join("loose");
+        create(&loose, &["mods/a.jar", "mods/b.jar", "start.sh", "forge-47.2.0.jar"]);
+        assert_eq!(ServerPack::find(&loose).unwrap().root, loose);
+
+        let wrapped = dir.join("wrapped");
+        create(&wrapped, &["Pack Server/mods/", "Pack Server/config/", "Pack Server/startserver.sh", "README.txt"]);
+        assert_eq!(ServerPack::find(&wrapped).unwrap().root, wrapped.join("Pack Server"));
+
+        let starter = dir.join("starter");
+        create(&starter, &["pack/server/variables.txt", "pack/server/start.bat", "pack/client/"]);
+        let pack = ServerPack::find(&starter).unwrap();
+        assert_eq!(pack.root, starter.join("pack").join("server"));
+        assert!(pack.markers.contains(&Marker::VariablesTxt));
+
+        let broken = dir.join("broken");
+        create(&broken, &["mods/", "server-setup-config.yaml"]);
+        std::fs::write(broken.join("server-setup-config.yaml"), "install: [").unwrap();
+        let pack = ServerPack::find(&broken).unwrap();
+        assert_eq!(pack.root, broken);
+        assert!(pack.launcher.is_none());
+
+        let empty = dir.join("empty");
+        create(&empty, &["only/readme.md"]);
+        assert_eq!(ServerPack::find(&empty).unwrap().root, empty.join("only"));
+    }
+
+    #[test]
+    fn classifies_scripts() {
+        let dir = PathBuf::from("./.mcsi-test-dir")
+            .join("tests")
+            .join("server-pack-scripts");
+        if dir.is_dir() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        create(&dir, &["installserver.sh", "run.bat", "start.ps1", "startserver.sh", "update.sh", "go.sh"]);
+        std::fs::write(dir.join("go.sh"), "#!/bin/sh\njava -Xmx4G -jar server.jar nogui\n").unwrap();
+
+        let pack = ServerPack::at(&dir).unwrap();
+        let purpose = |name: &str| pack.scripts.iter()
+            .find(|script| script.path == Path::new(name))
+            .map(|script| (script.shell, script.purpose))
+            .unwrap();
+
+        assert_eq!(purpose("installserver.sh"), (Shell::Sh, ScriptPurpose::Install));
+        assert_eq!(purpose("run.bat"), (Shell::Bat, ScriptPurpose::Start));
+        assert_eq!(purpose("start.ps1"), (Shell::Ps1, ScriptPurpose::Start));
+        assert_eq!(purpose("update.sh"), (Shell::Sh, ScriptPurpose::Other));
+        assert_eq!(purpose("go.sh"), (Shell::Sh, ScriptPurpose::Start));
+        assert_eq!(pack.start_script(Shell::Sh).unwrap().path, Path::new("startserver.sh"));
+
+        std::fs::remove_file(dir.join("startserver.sh")).unwrap();
+        create(&dir, &["run.sh", "start.sh"]);
+        let pack = ServerPack::at(&dir).unwrap();
+        assert_eq!(pack.start_script(Shell::Sh).unwrap().path, Path::new("start.sh"));
+
+        std::fs::remove_file(dir.join("start.sh")).unwrap();
+        std::fs::remove_file(dir.join("run.sh")).unwrap();
+        let pack = ServerPack::at(&dir).unwrap();
+        assert_eq!(pack.start_script(Shell::Sh).unwrap().path, Path::new("go.sh"));
+    }
+}
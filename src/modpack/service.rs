@@ -0,0 +1,235 @@
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use log::{info, warn};
+use thiserror::Error;
+use tokio::fs::write;
+use crate::fs_utils::ensure_dir;
+use crate::context::Context;
+
+pub const START_SCRIPT: &str = "mc-start.sh";
+pub const UNIT_FILE: &str = "mc-server.service";
+
+#[derive(Clone, Debug)]
+pub struct ServiceConfig {
+    // Write the unit next to the start script after every install
+    pub write_unit: bool,
+    // Defaults to `mc-` and the name of the server dir
+    pub name: Option<String>,
+    // Defaults to the user running the install
+    pub user: Option<String>,
+    // Hard memory limit of the whole service, like `10G`
+    pub memory_max: Option<String>,
+    pub restart: Restart,
+    // Seconds the server gets to save and stop before it is killed
+    pub stop_timeout: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Restart {
+    Always,
+    OnFailure,
+    No,
+}
+
+impl Default for ServiceConfig {
+    fn default() -> Self {
+        ServiceConfig {
+            write_unit: false,
+            name: None,
+            user: None,
+            memory_max: None,
+            restart: Restart::OnFailure,
+            stop_timeout: 120,
+        }
+    }
+}
+
+impl ServiceConfig {
+    // Named after `server_dir`, which must be canonical so `.` names the actual dir.
+    fn name(&self, server_dir: &Path) -> Result<String, ServiceError> {
+        if let Some(name) = &self.name {
+            return Ok(name.clone());
+        }
+
+        let dir_name = server_dir.file_name()
+            .ok_or_else(|| ServiceError::NoDirName(server_dir.to_string_lossy().to_string()))?
+            .to_string_lossy()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '-' })
+            .collect::<String>();
+
+        Ok(format!("mc-{dir_name}"))
+    }
+
+    fn user(&self) -> Option<String> {
+        self.user.clone()
+            .or_else(|| std::env::var("SUDO_USER").ok())
+            .or_else(|| std::env::var("USER").ok())
+    }
+
+    // The unit running the `mc-start.sh` of `server_dir`, which has to be absolute.
+    // The console is read from a FIFO, so `systemctl stop` can send `stop` and wait for the world to save.
+    pub fn unit(&self, server_dir: &Path) -> Result<String, ServiceError> {
+        let name = self.name(server_dir)?;
+        let console = format!("/run/{name}/console");
+        let server_dir = server_dir.to_string_lossy();
+
+        let mut service = vec![
+            String::from("Type=simple"),
+            format!("WorkingDirectory={server_dir}"),
+            format!("RuntimeDirectory={name}"),
+            format!("ExecStartPre=/bin/sh -c 'rm -f {console} && mkfifo -m 600 {console}'"),
+            format!("ExecStart=/bin/sh -c 'exec ./{START_SCRIPT} <> {console}'"),
+            format!("ExecStop=/bin/sh -c 'echo stop > {console}; while kill -0 $MAINPID 2>/dev/null; do sleep 1; done'"),
+            format!("TimeoutStopSec={}", self.stop_timeout),
+            format!("Restart={}", self.restart),
+            String::from("RestartSec=10"),
+            // Java exits with 143 when it is terminated
+            String::from("SuccessExitStatus=0 143"),
+        ];
+
+        if let Some(user) = self.user() {
+            service.insert(1, format!("User={user}"));
+        }
+        if let Some(memory_max) = &self.memory_max {
+            service.push(format!("MemoryMax={memory_max}"));
+        }
+
+        Ok(format!(
+            "[Unit]\nDescription=Minecraft server in {server_dir}\nWants=network-online.target\nAfter=network-online.target\n\n[Service]\n{}\n\n[Install]\nWantedBy=multi-user.target\n",
+            service.join("\n"),
+        ))
+    }
+}
+
+impl Display for Restart {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Restart::Always => write!(f, "always"),
+            Restart::OnFailure => write!(f, "on-failure"),
+            Restart::No => write!(f, "no"),
+        }
+    }
+}
+
+pub async fn write_unit<T: AsRef<Path>>(ctx: &Context, target_dir: T, config: &ServiceConfig) -> color_eyre::Result<()> {
+    let target_dir = target_dir.as_ref();
+    if !config.write_unit {
+        return Ok(());
+    }
+
+    if ctx.is_dry_run() {
+        ctx.plan.change(format!("Write the systemd unit {UNIT_FILE}"));
+        return Ok(());
+    }
+
+    if !target_dir.join(START_SCRIPT).is_file() {
+        warn!("The pack has no {START_SCRIPT}, so no systemd unit is written");
+        return Ok(());
+    }
+
+    info!("Writing {UNIT_FILE}...");
+    write(target_dir.join(UNIT_FILE), config.unit(&target_dir.canonicalize()?)?)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn handle_install_service<T: AsRef<Path>>(
+    ctx: &Context,
+    target_dir: T,
+    unit_dir: PathBuf,
+    start: bool,
+    config: &ServiceConfig,
+) -> color_eyre::Result<()> {
+    let target_dir = target_dir.as_ref();
+    if !target_dir.join(START_SCRIPT).is_file() {
+        return Err(ServiceError::NoStartScript(target_dir.to_string_lossy().to_string()))?;
+    }
+
+    let target_dir = target_dir.canonicalize()?;
+    let name = config.name(&target_dir)?;
+    let unit_path = unit_dir.join(format!("{name}.service"));
+
+    let mut enable = vec!["enable"];
+    if start {
+        enable.push("--now");
+    }
+    enable.push(&name);
+
+    if ctx.is_dry_run() {
+        ctx.plan.change(format!("Write the systemd unit {:?}", unit_path));
+        ctx.plan.command(String::from("systemctl daemon-reload"));
+        ctx.plan.command(format!("systemctl {}", enable.join(" ")));
+        return Ok(());
+    }
+
+    info!("Installing {:?}...", unit_path);
+    ensure_dir(&unit_dir)?;
+    write(&unit_path, config.unit(&target_dir)?)
+        .await?;
+
+    systemctl(&["daemon-reload"])?;
+    systemctl(&enable)?;
+
+    info!("{name} is installed, manage it with `systemctl {{start,stop,status}} {name}`");
+    Ok(())
+}
+
+fn systemctl(args: &[&str]) -> color_eyre::Result<()> {
+    let status = Command::new("systemctl")
+        .args(args)
+        .status()
+        .map_err(|err| match err.kind() {
+            ErrorKind::NotFound => ServiceError::NoSystemd.into(),
+            _ => color_eyre::Report::new(err),
+        })?;
+
+    if !status.success() {
+        Err(ServiceError::SystemctlFailed(args.join(" "), status.code()))?;
+    }
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum ServiceError {
+    #[error("`{0}` has no mc-start.sh, install a pack into it first")]
+    NoStartScript(String),
+    #[error("systemctl was not found, systemd is required to install the service")]
+    NoSystemd,
+    #[error("`systemctl {0}` failed with exit code {1:?}")]
+    SystemctlFailed(String, Option<i32>),
+    #[error("Can't name the service after `{0}`, set one with --service-name")]
+    NoDirName(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::modpack::service::{Restart, ServiceConfig};
+
+    #[test]
+    fn generates_unit() {
+        let config = ServiceConfig {
+            write_unit: true,
+            name: None,
+            user: Some(String::from("minecraft")),
+            memory_max: Some(String::from("10G")),
+            restart: Restart::Always,
+            stop_timeout: 300,
+        };
+
+        let unit = config.unit(Path::new("/srv/All The Mods")).unwrap();
+        assert!(unit.contains("\nUser=minecraft\nWorkingDirectory=/srv/All The Mods\nRuntimeDirectory=mc-All-The-Mods\n"));
+        assert!(unit.contains("\nExecStart=/bin/sh -c 'exec ./mc-start.sh <> /run/mc-All-The-Mods/console'\n"));
+        assert!(unit.contains("\nExecStop=/bin/sh -c 'echo stop > /run/mc-All-The-Mods/console;"));
+        assert!(unit.contains("\nTimeoutStopSec=300\nRestart=always\n"));
+        assert!(unit.contains("\nMemoryMax=10G\n"));
+        assert!(unit.ends_with("[Install]\nWantedBy=multi-user.target\n"));
+
+        assert!(config.unit(Path::new("/")).is_err());
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use log::{info, warn};
+use thiserror::Error;
+use tokio::fs::{copy, remove_dir_all};
+use walkdir::WalkDir;
+use crate::fs_utils::{download_file_with_hash, ensure_dir, ensure_parent, file_path_relative_to, sha1_file};
+use crate::lock::PackLock;
+use crate::modpack::{flame, ftb, PackManifest, PackSource};
+use crate::modpack::jvm::JvmConfig;
+use crate::modpack::pipeline::StagedPack;
+use crate::modpack::ftb::IdOrSearch;
+use crate::context::Context;
+use crate::events::Stage;
+
+// Directories that only the pack is expected to put files in.
+const PACK_OWNED_DIRS: [&str; 2] = ["mods", "coremods"];
+
+#[derive(Default, Clone, Debug)]
+pub struct VerifyReport {
+    pub missing: Vec<String>,
+    pub corrupted: Vec<String>,
+    pub extra: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_healthy(&self) -> bool {
+        self.missing.is_empty() && self.corrupted.is_empty()
+    }
+
+    fn broken_files(&self) -> Vec<String> {
+        self.missing.iter()
+            .chain(self.corrupted.iter())
+            .cloned()
+            .collect()
+    }
+}
+
+pub async fn handle_verify<T: AsRef<Path>>(
+    ctx: &Context,
+    target_dir: T,
+    repair: bool,
+    api_key: Option<String>,
+    jvm: &JvmConfig,
+) -> color_eyre::Result<()> {
+    let target_dir = target_dir.as_ref();
+    let target_mcsi_dir = target_dir
+        .join(".mcsi");
+
+    let mut manifest = PackManifest::load_from(&target_mcsi_dir)?;
+
+    info!("Verifying {} files...", manifest.files.len());
+    let mut report = ctx.events.stage(Stage::Verify, async { check_files(target_dir, &manifest) })
+        .await?;
+    log_report(&report);
+
+    if repair && !report.is_healthy() {
+        repair_files(ctx, target_dir, &mut manifest, &report, api_key, jvm)
+            .await?;
+        if ctx.is_dry_run() {
+            return Ok(());
+        }
+        manifest.save_to(&target_mcsi_dir)?;
+
+        report = check_files(target_dir, &manifest)?;
+    }
+
+    if !report.is_healthy() {
+        return Err(VerifyError::Unhealthy {
+            missing: report.missing.len(),
+            corrupted: report.corrupted.len(),
+        })?;
+    }
+
+    info!("Server files are intact!");
+    Ok(())
+}
+
+pub fn check_files<T: AsRef<Path>>(target_dir: T, manifest: &PackManifest) -> color_eyre::Result<VerifyReport> {
+    let target_dir = target_dir.as_ref();
+    let mut report = VerifyReport::default();
+
+    if manifest.hashes.is_empty() {
+        warn!("Manifest has no file hashes, only checking for missing files");
+    }
+
+    for rel_file in &manifest.files {
+        let file = target_dir.join(rel_file);
+        if !file.is_file() {
+            report.missing.push(rel_file.clone());
+            continue;
+        }
+
+        if let Some(expected) = manifest.hashes.get(rel_file)
+            && sha1_file(&file)? != *expected {
+            report.corrupted.push(rel_file.clone());
+        }
+    }
+
+    let pack_files = manifest.files.iter()
+        .map(String::as_str)
+        .collect::<HashSet<_>>();
+    for dir in PACK_OWNED_DIRS {
+        let dir = target_dir.join(dir);
+        if !dir.is_dir() {
+            continue;
+        }
+
+        for entry in WalkDir::new(&dir) {
+            let entry = entry?;
+            if entry.path().is_dir() {
+                continue;
+            }
+
+            let relative = file_path_relative_to(entry.path(), target_dir)?;
+            let relative = relative.to_string_lossy().to_string();
+            if !pack_files.contains(relative.as_str()) {
+                report.extra.push(relative);
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+fn log_report(report: &VerifyReport) {
+    for file in &report.missing {
+        warn!("Missing: {file}");
+    }
+    for file in &report.corrupted {
+        warn!("Corrupted: {file}");
+    }
+    for file in &report.extra {
+        warn!("Not part of the pack: {file}");
+    }
+
+    info!(
+        "{} missing, {} corrupted, {} extra files",
+        report.missing.len(),
+        report.corrupted.len(),
+        report.extra.len(),
+    );
+}
+
+async fn repair_files(
+    ctx: &Context,
+    target_dir: &Path,
+    manifest: &mut PackManifest,
+    report: &VerifyReport,
+    api_key: Option<String>,
+    jvm: &JvmConfig,
+) -> color_eyre::Result<()> {
+    // Files downloaded on their own, like mods, are downloaded again on their own
+    let urls = download_urls(target_dir);
+    let mut from_source = Vec::new();
+
+    for rel_file in report.broken_files() {
+        let download = manifest.hashes.get(&rel_file)
+            .and_then(|sha1| Some((urls.get(sha1.as_str())?, sha1)));
+        let Some((url, sha1)) = download else {
+            from_source.push(rel_file);
+            continue;
+        };
+
+        let dst = target_dir.join(&rel_file);
+        ensure_parent(&dst)
+            .await?;
+        download_file_with_hash(ctx, url, &dst, Some(sha1))
+            .await?;
+        info!("Downloaded {rel_file} again");
+    }
+
+    if from_source.is_empty() {
+        return Ok(());
+    }
+
+    if ctx.is_dry_run() {
+        for rel_file in from_source {
+            ctx.plan.change(format!("Restore {rel_file} from the pack source"));
+        }
+        return Ok(());
+    }
+
+    let Some(source) = manifest.source.clone() else { return Err(VerifyError::NoSource)? };
+
+    info!("Fetching original pack files to repair {} files...", from_source.len());
+    ensure_dir(ctx.staging_dir())?;
+    stage_source(ctx, &source, api_key, jvm)
+        .await?;
+
+    let work_dir = ctx.work_dir();
+    for rel_file in from_source {
+        let staged = work_dir.join(&rel_file);
+        if !staged.is_file() {
+            warn!("{rel_file} is no longer part of the pack source, skipping");
+            continue;
+        }
+
+        let dst = PathBuf::from(target_dir)
+            .join(&rel_file);
+        ensure_parent(&dst)
+            .await?;
+        copy(&staged, &dst)
+            .await?;
+
+        manifest.hashes.insert(rel_file.clone(), sha1_file(&dst)?);
+        info!("Restored {rel_file}");
+    }
+
+    remove_dir_all(work_dir)
+        .await?;
+
+    Ok(())
+}
+
+// The url each file of the lock was downloaded from, keyed by its sha1.
+fn download_urls(target_dir: &Path) -> BTreeMap<String, String> {
+    let Ok(lock) = PackLock::load_from(target_dir) else { return BTreeMap::new() };
+
+    lock.downloads.into_iter()
+        .map(|(url, sha1)| (sha1, url))
+        .collect()
+}
+
+async fn stage_source(ctx: &Context, source: &PackSource, api_key: Option<String>, jvm: &JvmConfig) -> color_eyre::Result<StagedPack> {
+    match source {
+        PackSource::Flame { project_id, file_id, skip_server_pack } => {
+            let Some(api_key) = api_key else { return Err(VerifyError::MissingApiKey)? };
+
+            flame::stage_flame(ctx, api_key, *project_id, file_id.to_string(), *skip_server_pack, jvm)
+                .await
+        }
+        PackSource::Ftb { pack_id, version_id } => {
+            ftb::stage_ftb(ctx, IdOrSearch::Id(pack_id.to_string()), version_id.to_string(), jvm)
+                .await
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum VerifyError {
+    #[error("{missing} missing and {corrupted} corrupted files")]
+    Unhealthy {
+        missing: usize,
+        corrupted: usize,
+    },
+    #[error("The manifest doesn't record where the pack was installed from, reinstall it to enable repairs")]
+    NoSource,
+    #[error("An api key is required to repair CurseForge packs")]
+    MissingApiKey,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, remove_dir_all, remove_file, write};
+    use std::path::PathBuf;
+    use crate::modpack::PackManifest;
+    use crate::modpack::verify::check_files;
+
+    #[test]
+    fn reports_missing_corrupted_and_extra_files() {
+        let target_dir = PathBuf::from("./.mcsi-test-dir")
+            .join("tests")
+            .join("verify");
+        if target_dir.is_dir() {
+            remove_dir_all(&target_dir).unwrap();
+        }
+        create_dir_all(target_dir.join("mods")).unwrap();
+        create_dir_all(target_dir.join("config")).unwrap();
+
+        write(target_dir.join("mods").join("a.jar"), "a").unwrap();
+        write(target_dir.join("mods").join("b.jar"), "b").unwrap();
+        write(target_dir.join("config").join("c.toml"), "c").unwrap();
+
+        let manifest = PackManifest::builder()
+            .with_files_from_dir(&target_dir)
+            .unwrap()
+            .finish();
+
+        remove_file(target_dir.join("mods").join("a.jar")).unwrap();
+        write(target_dir.join("config").join("c.toml"), "changed").unwrap();
+        write(target_dir.join("mods").join("stray.jar"), "stray").unwrap();
+        write(target_dir.join("server.properties"), "motd=hi").unwrap();
+
+        let report = check_files(&target_dir, &manifest).unwrap();
+
+        assert_eq!(report.missing, vec![PathBuf::from("mods").join("a.jar").to_str().unwrap()]);
+        assert_eq!(report.corrupted, vec![PathBuf::from("config").join("c.toml").to_str().unwrap()]);
+        assert_eq!(report.extra, vec![PathBuf::from("mods").join("stray.jar").to_str().unwrap()]);
+        assert!(!report.is_healthy());
+    }
+}
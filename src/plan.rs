@@ -0,0 +1,133 @@
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use indicatif::HumanBytes;
+use serde::Serialize;
+use crate::modpack::backup::BackupFormat;
+
+#[derive(Serialize, Default, Clone, Debug)]
+pub struct Plan {
+    // What the pack, loader and start script resolved to
+    pub resolved: Vec<String>,
+    pub downloads: Vec<PlannedDownload>,
+    // Files of the current install that would be removed
+    pub removals: Vec<String>,
+    // How the removed files would be backed up first
+    pub backup: Option<BackupFormat>,
+    pub commands: Vec<String>,
+    // Any other change to the target dir
+    pub changes: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct PlannedDownload {
+    pub url: String,
+    pub dst: PathBuf,
+    pub size: Option<u64>,
+}
+
+#[derive(Default, Debug)]
+pub(crate) struct Planner {
+    dry_run: bool,
+    plan: Mutex<Plan>,
+}
+
+impl Planner {
+    pub(crate) fn new(dry_run: bool) -> Self {
+        Planner {
+            dry_run,
+            plan: Mutex::default(),
+        }
+    }
+
+    pub(crate) fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    fn record(&self, f: impl FnOnce(&mut Plan)) {
+        if self.dry_run {
+            f(&mut self.plan.lock().unwrap());
+        }
+    }
+
+    pub(crate) fn resolved(&self, what: String) {
+        self.record(|plan| plan.resolved.push(what));
+    }
+
+    pub(crate) fn download(&self, url: &str, dst: &Path, size: Option<u64>) {
+        self.record(|plan| plan.downloads.push(PlannedDownload {
+            url: url.to_string(),
+            dst: dst.to_path_buf(),
+            size,
+        }));
+    }
+
+    pub(crate) fn remove(&self, files: Vec<String>, backup: Option<BackupFormat>) {
+        self.record(|plan| {
+            plan.removals.extend(files);
+            plan.backup = backup;
+        });
+    }
+
+    pub(crate) fn command(&self, command: String) {
+        self.record(|plan| plan.commands.push(command));
+    }
+
+    pub(crate) fn change(&self, change: String) {
+        self.record(|plan| plan.changes.push(change));
+    }
+
+    pub(crate) fn take(&self) -> Plan {
+        std::mem::take(&mut self.plan.lock().unwrap())
+    }
+}
+
+// Downloads into `work_dir` are shown relative to it.
+pub fn print(plan: &Plan, work_dir: &Path) {
+    if !plan.resolved.is_empty() {
+        println!("Resolved:");
+        for what in &plan.resolved {
+            println!("  {what}");
+        }
+    }
+
+    if !plan.downloads.is_empty() {
+        let total: u64 = plan.downloads.iter().filter_map(|download| download.size).sum();
+        println!("Download {} files ({}):", plan.downloads.len(), HumanBytes(total));
+
+        for download in &plan.downloads {
+            let dst = download.dst.strip_prefix(work_dir)
+                .unwrap_or(&download.dst);
+            let size = download.size
+                .map(|size| HumanBytes(size).to_string())
+                .unwrap_or_else(|| String::from("unknown size"));
+
+            println!("  {} ({size}) from {}", dst.display(), download.url);
+        }
+    }
+
+    if !plan.removals.is_empty() {
+        match plan.backup {
+            Some(format) => println!("Back up ({format:?}) and remove {} files:", plan.removals.len()),
+            None => println!("Remove {} files without a backup:", plan.removals.len()),
+        }
+        for file in &plan.removals {
+            println!("  {file}");
+        }
+    }
+
+    if !plan.commands.is_empty() {
+        println!("Run:");
+        for command in &plan.commands {
+            println!("  {command}");
+        }
+    }
+
+    if !plan.changes.is_empty() {
+        println!("Change:");
+        for change in &plan.changes {
+            println!("  {change}");
+        }
+    }
+
+    println!("Dry run, nothing was changed.");
+}
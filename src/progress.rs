@@ -0,0 +1,156 @@
+use std::time::Duration;
+use indicatif::{ProgressBar, ProgressStyle};
+
+// Shows the progress of long-running tasks, drawn with indicatif unless a different reporter is set.
+pub trait Reporter: Send + Sync {
+    fn start(&self, task: Task) -> Box<dyn Progress>;
+}
+
+pub trait Progress: Send + Sync {
+    // `None` when the length isn't known up front
+    fn set_length(&self, len: Option<u64>);
+    fn set_position(&self, pos: u64);
+    fn inc(&self, delta: u64);
+    fn set_message(&self, message: String);
+    fn finish(&self);
+}
+
+#[derive(Clone, Debug)]
+pub enum Task {
+    // Measured in bytes
+    Download {
+        file: String,
+    },
+    // Measured in files, the total isn't known up front
+    Copy,
+    Backup {
+        files: u64,
+    },
+    Remove {
+        files: u64,
+    },
+    // An external installer, only reports its output as messages
+    Installer {
+        name: String,
+    },
+}
+
+pub struct IndicatifReporter;
+
+pub struct NoopReporter;
+
+struct IndicatifProgress {
+    bar: ProgressBar,
+    task: Task,
+}
+
+impl Reporter for IndicatifReporter {
+    fn start(&self, task: Task) -> Box<dyn Progress> {
+        let bar = match &task {
+            Task::Download { file } => ProgressBar::no_length()
+                .with_style(download_progress_style())
+                .with_message(file.clone()),
+            Task::Copy => ProgressBar::new_spinner()
+                .with_style(copy_progress_style()),
+            Task::Backup { files } | Task::Remove { files } => ProgressBar::new(*files)
+                .with_style(backup_progress_style()),
+            Task::Installer { name } => {
+                let bar = ProgressBar::new_spinner()
+                    .with_style(spinner_progress_style())
+                    .with_prefix(name.clone());
+                bar.enable_steady_tick(Duration::from_millis(500));
+                bar
+            }
+        };
+
+        Box::new(IndicatifProgress {
+            bar,
+            task,
+        })
+    }
+}
+
+impl Progress for IndicatifProgress {
+    fn set_length(&self, len: Option<u64>) {
+        let is_download = matches!(self.task, Task::Download { .. });
+
+        match len {
+            Some(len) => {
+                if is_download {
+                    self.bar.set_style(download_progress_style());
+                }
+                self.bar.set_length(len);
+            }
+            None => {
+                if is_download {
+                    self.bar.set_style(download_spinner_style());
+                }
+                self.bar.unset_length();
+            }
+        }
+    }
+
+    fn set_position(&self, pos: u64) {
+        self.bar.set_position(pos);
+    }
+
+    fn inc(&self, delta: u64) {
+        self.bar.inc(delta);
+    }
+
+    fn set_message(&self, message: String) {
+        self.bar.set_message(message);
+    }
+
+    fn finish(&self) {
+        self.bar.finish();
+    }
+}
+
+impl Reporter for NoopReporter {
+    fn start(&self, _task: Task) -> Box<dyn Progress> {
+        Box::new(NoopReporter)
+    }
+}
+
+impl Progress for NoopReporter {
+    fn set_length(&self, _len: Option<u64>) {}
+
+    fn set_position(&self, _pos: u64) {}
+
+    fn inc(&self, _delta: u64) {}
+
+    fn set_message(&self, _message: String) {}
+
+    fn finish(&self) {}
+}
+
+// Only ever fails on a malformed template, and these are fixed
+fn style(template: &'static str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .expect("progress templates are constant and valid")
+}
+
+fn download_progress_style() -> ProgressStyle {
+    style("[File: {msg}]\n{bar:40.cyan/blue} {percent}% [{bytes} / {total_bytes}] [Eta: {eta}]")
+}
+
+// Used when the server doesn't tell us how large the file is
+fn download_spinner_style() -> ProgressStyle {
+    style("[File: {msg}]\n{spinner} [{bytes}] [{bytes_per_sec}] {elapsed}")
+        .tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ ")
+}
+
+fn copy_progress_style() -> ProgressStyle {
+    style("[{pos}/?] {spinner} {msg} {elapsed}")
+        .tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ ")
+}
+
+fn backup_progress_style() -> ProgressStyle {
+    style("{bar:40.cyan/blue} [Eta: {eta}]\n[{pos}/{len}] {wide_msg}")
+}
+
+fn spinner_progress_style() -> ProgressStyle {
+    style("{spinner} {prefix} {elapsed}\n{wide_msg}")
+        .tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ ")
+}
@@ -1,16 +1,162 @@
+use std::cmp::Ordering;
+use std::fmt::{Display, Formatter};
 use std::str::FromStr;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use thiserror::Error;
 
+// `(major, minor, patch)`
+type ReleaseNumber = (u32, u32, u32);
+
+// First weekly snapshot of each release, as `(year, week)`, used to order snapshots among releases.
+const SNAPSHOT_TARGETS: [((u32, u32), ReleaseNumber); 33] = [
+    ((13, 1), (1, 5, 0)),
+    ((13, 16), (1, 6, 0)),
+    ((13, 36), (1, 7, 0)),
+    ((14, 2), (1, 8, 0)),
+    ((15, 31), (1, 9, 0)),
+    ((16, 14), (1, 9, 3)),
+    ((16, 20), (1, 10, 0)),
+    ((16, 32), (1, 11, 0)),
+    ((17, 6), (1, 12, 0)),
+    ((17, 31), (1, 12, 1)),
+    ((17, 43), (1, 13, 0)),
+    ((18, 30), (1, 13, 1)),
+    ((18, 43), (1, 14, 0)),
+    ((19, 34), (1, 15, 0)),
+    ((20, 6), (1, 16, 0)),
+    ((20, 27), (1, 16, 2)),
+    ((20, 45), (1, 17, 0)),
+    ((21, 37), (1, 18, 0)),
+    ((22, 11), (1, 19, 0)),
+    ((22, 24), (1, 19, 1)),
+    ((22, 42), (1, 19, 3)),
+    ((23, 3), (1, 19, 4)),
+    ((23, 12), (1, 20, 0)),
+    ((23, 31), (1, 20, 2)),
+    ((23, 40), (1, 20, 3)),
+    ((23, 51), (1, 20, 5)),
+    ((24, 18), (1, 21, 0)),
+    ((24, 33), (1, 21, 2)),
+    ((24, 44), (1, 21, 4)),
+    ((25, 2), (1, 21, 5)),
+    ((25, 15), (1, 21, 6)),
+    ((25, 31), (1, 21, 9)),
+    ((25, 41), (1, 21, 11)),
+];
+
+// Releases switched to `26.1-snapshot-1` style snapshots after this one.
+const LAST_WEEKLY_SNAPSHOT: (u32, u32) = (25, 46);
+
+// Where snapshots outside of `SNAPSHOT_TARGETS` sort.
+const OLDER_THAN_KNOWN: ReleaseNumber = (0, 0, 0);
+const NEWER_THAN_KNOWN: ReleaseNumber = (u32::MAX, 0, 0);
+
 #[derive(Clone, Debug)]
-pub struct McVersion {
-    pub major: u8,
-    pub minor: u8,
-    pub patch: u8,
+pub enum McVersion {
+    // `1.20.1`, `1.21`, `1.21-pre1`, `1.21-rc1` or `26.1-snapshot-1`
+    Release {
+        major: u32,
+        minor: u32,
+        // `None` for two part versions like `1.21`, which loaders don't write as `1.21.0`
+        patch: Option<u32>,
+        pre_release: Option<PreRelease>,
+    },
+    // Weekly snapshot like `24w14a`
+    Snapshot {
+        year: u32,
+        week: u32,
+        build: char,
+    },
+}
+
+// Builds leading up to a release, in the order they are published.
+#[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Copy, Debug)]
+pub enum PreRelease {
+    Snapshot(u32),
+    Pre(u32),
+    Rc(u32),
 }
 
 impl McVersion {
-    pub fn as_str(&self) -> String {
-        format!("{0}.{1}.{2}", self.major, self.minor, self.patch)
+    // Lowest java major version the server of this version runs on.
+    pub fn java_version(&self) -> u8 {
+        match self.release() {
+            (26.., _, _) => 25,
+            (1, 21.., _) | (1, 20, 5..) => 21,
+            (1, 18.., _) => 17,
+            (1, 17, _) => 16,
+            _ => 8,
+        }
+    }
+
+    fn release(&self) -> ReleaseNumber {
+        match self {
+            McVersion::Release { major, minor, patch, .. } => (*major, *minor, patch.unwrap_or(0)),
+            McVersion::Snapshot { year, week, .. } if (*year, *week) > LAST_WEEKLY_SNAPSHOT => NEWER_THAN_KNOWN,
+            McVersion::Snapshot { year, week, .. } => SNAPSHOT_TARGETS.iter()
+                .rev()
+                .find(|(first, _)| *first <= (*year, *week))
+                .map(|(_, release)| *release)
+                .unwrap_or(OLDER_THAN_KNOWN),
+        }
+    }
+
+    // Orders releases by their number, anything leading up to a release comes right before it.
+    fn sort_key(&self) -> (ReleaseNumber, u8, u32, u32) {
+        match self {
+            McVersion::Release { pre_release, .. } => {
+                let (stage, num) = match pre_release {
+                    Some(PreRelease::Snapshot(num)) => (1, *num),
+                    Some(PreRelease::Pre(num)) => (2, *num),
+                    Some(PreRelease::Rc(num)) => (3, *num),
+                    None => (4, 0),
+                };
+
+                (self.release(), stage, num, 0)
+            }
+            McVersion::Snapshot { year, week, build } => (self.release(), 0, year * 100 + week, *build as u32),
+        }
+    }
+}
+
+impl PartialEq for McVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.sort_key() == other.sort_key()
+    }
+}
+
+impl Eq for McVersion {}
+
+impl PartialOrd for McVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for McVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.sort_key().cmp(&other.sort_key())
+    }
+}
+
+impl Display for McVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            McVersion::Release { major, minor, patch, pre_release } => {
+                write!(f, "{major}.{minor}")?;
+                if let Some(patch) = patch {
+                    write!(f, ".{patch}")?;
+                }
+
+                match pre_release {
+                    Some(PreRelease::Snapshot(num)) => write!(f, "-snapshot-{num}"),
+                    Some(PreRelease::Pre(num)) => write!(f, "-pre{num}"),
+                    Some(PreRelease::Rc(num)) => write!(f, "-rc{num}"),
+                    None => Ok(()),
+                }
+            }
+            McVersion::Snapshot { year, week, build } => write!(f, "{year}w{week:02}{build}"),
+        }
     }
 }
 
@@ -18,25 +164,241 @@ impl FromStr for McVersion {
     type Err = McVersionParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let nums = s.split(".");
-        if nums.clone().count() != 3 {
-            return Err(McVersionParseError::InvalidInput);
+        let invalid = || McVersionParseError::InvalidInput(s.to_string());
+        let s = s.trim();
+
+        if let Some((year, rest)) = s.split_once('w') {
+            let mut chars = rest.chars();
+            let build = chars.next_back()
+                .filter(char::is_ascii_lowercase)
+                .ok_or_else(invalid)?;
+            let week = chars.as_str();
+
+            if year.len() != 2 || week.len() != 2 {
+                return Err(invalid());
+            }
+
+            return Ok(McVersion::Snapshot {
+                year: year.parse().map_err(|_| invalid())?,
+                week: week.parse().map_err(|_| invalid())?,
+                build,
+            });
         }
 
-        let nums: Vec<u8> = nums
-            .map(|entry| u8::from_str(entry).unwrap())
-            .collect();
+        // Older pre-releases are named like `1.14 Pre-Release 1`
+        let lower = s.to_ascii_lowercase()
+            .replace(" pre-release ", "-pre");
+        let (release, pre_release) = match lower.split_once('-') {
+            Some((release, pre_release)) => (release, Some(pre_release)),
+            None => (lower.as_str(), None),
+        };
+
+        let pre_release = match pre_release {
+            None => None,
+            Some(pre_release) => {
+                let parse = |num: &str| num.parse::<u32>().map_err(|_| invalid());
 
-        Ok(McVersion {
-            major: nums[0],
-            minor: nums[1],
-            patch: nums[2]
-        })
+                if let Some(num) = pre_release.strip_prefix("snapshot-") {
+                    Some(PreRelease::Snapshot(parse(num)?))
+                } else if let Some(num) = pre_release.strip_prefix("pre") {
+                    Some(PreRelease::Pre(parse(num)?))
+                } else if let Some(num) = pre_release.strip_prefix("rc") {
+                    Some(PreRelease::Rc(parse(num)?))
+                } else {
+                    return Err(invalid());
+                }
+            }
+        };
+
+        let nums = release.split('.')
+            .map(u32::from_str)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?;
+
+        match nums.as_slice() {
+            [major, minor] => Ok(McVersion::Release {
+                major: *major,
+                minor: *minor,
+                patch: None,
+                pre_release,
+            }),
+            [major, minor, patch] => Ok(McVersion::Release {
+                major: *major,
+                minor: *minor,
+                patch: Some(*patch),
+                pre_release,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Serialize for McVersion {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for McVersion {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let version = String::deserialize(deserializer)?;
+        McVersion::from_str(&version).map_err(serde::de::Error::custom)
+    }
+}
+
+// Versions matching every comparator, like `>=1.20.5` or `>=1.19, <1.21`. A bare version only matches itself.
+#[derive(Clone, Debug)]
+pub struct McVersionRange {
+    comparators: Vec<(Comparison, McVersion)>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Comparison {
+    Eq,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl McVersionRange {
+    pub fn matches(&self, version: &McVersion) -> bool {
+        self.comparators.iter()
+            .all(|(comparison, bound)| match comparison {
+                Comparison::Eq => version == bound,
+                Comparison::Gt => version > bound,
+                Comparison::Ge => version >= bound,
+                Comparison::Lt => version < bound,
+                Comparison::Le => version <= bound,
+            })
+    }
+}
+
+impl Display for McVersionRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.comparators.is_empty() {
+            return write!(f, "*");
+        }
+
+        let comparators = self.comparators.iter()
+            .map(|(comparison, version)| {
+                let op = match comparison {
+                    Comparison::Eq => "",
+                    Comparison::Gt => ">",
+                    Comparison::Ge => ">=",
+                    Comparison::Lt => "<",
+                    Comparison::Le => "<=",
+                };
+                format!("{op}{version}")
+            })
+            .collect::<Vec<_>>();
+
+        write!(f, "{}", comparators.join(", "))
+    }
+}
+
+impl FromStr for McVersionRange {
+    type Err = McVersionParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim() == "*" {
+            return Ok(McVersionRange { comparators: Vec::new() });
+        }
+
+        let comparators = s.split(',')
+            .map(|comparator| {
+                let comparator = comparator.trim();
+                let (comparison, version) = [(">=", Comparison::Ge), ("<=", Comparison::Le), (">", Comparison::Gt), ("<", Comparison::Lt), ("=", Comparison::Eq)]
+                    .into_iter()
+                    .find_map(|(op, comparison)| comparator.strip_prefix(op).map(|version| (comparison, version)))
+                    .unwrap_or((Comparison::Eq, comparator));
+
+                McVersion::from_str(version.trim())
+                    .map(|version| (comparison, version))
+                    .map_err(|_| McVersionParseError::InvalidRange(s.to_string()))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(McVersionRange { comparators })
     }
 }
 
 #[derive(Error, Clone, Debug)]
 pub enum McVersionParseError {
-    #[error("Input is invalid")]
-    InvalidInput,
-}
\ No newline at end of file
+    #[error("`{0}` is not a minecraft version like 1.20.1, 1.21, 1.21-pre1 or 24w14a")]
+    InvalidInput(String),
+    #[error("`{0}` is not a version range like >=1.20.5 or >=1.19, <1.21")]
+    InvalidRange(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use crate::version::{McVersion, McVersionRange, PreRelease};
+
+    fn version(s: &str) -> McVersion {
+        McVersion::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn parses_and_displays_every_kind() {
+        for s in ["1.20.1", "1.21", "1.21-pre1", "1.21-rc2", "26.1-snapshot-3", "24w14a", "23w03a"] {
+            assert_eq!(version(s).to_string(), s);
+        }
+
+        assert_eq!(version("1.14 Pre-Release 2").to_string(), "1.14-pre2");
+        assert!(matches!(version("1.21-rc1"), McVersion::Release { pre_release: Some(PreRelease::Rc(1)), .. }));
+
+        for s in ["", "1", "1.x.2", "1.2.3.4", "1.21-beta", "24w14", "2024w14a", "-1.20"] {
+            assert!(McVersion::from_str(s).is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn orders_pre_releases_and_snapshots_before_their_release() {
+        assert_eq!(version("1.21"), version("1.21.0"));
+        assert!(version("1.9.4") < version("1.20"));
+        assert!(version("1.20.4") < version("24w14a"));
+        assert!(version("24w14a") < version("1.20.5-pre1"));
+        assert!(version("1.20.5-pre1") < version("1.20.5-rc1"));
+        assert!(version("1.20.5-rc1") < version("1.20.5"));
+        assert!(version("24w13a") < version("24w14a"));
+        assert!(version("1.21.11") < version("26.1-snapshot-1"));
+        assert!(version("1.21.6") < version("25w31a"));
+        assert!(version("25w46a") < version("1.21.11"));
+        assert!(version("1.12.2") < version("17w43a"));
+        assert!(version("17w31a") < version("1.12.1"));
+        assert!(version("12w50a") < version("1.0"));
+        assert!(version("26.1") < version("26w01a"));
+    }
+
+    #[test]
+    fn matches_ranges() {
+        let range = McVersionRange::from_str(">=1.20.5").unwrap();
+        assert!(range.matches(&version("1.21")));
+        assert!(!range.matches(&version("1.20.4")));
+
+        let range = McVersionRange::from_str(">=1.19, <1.21").unwrap();
+        assert!(range.matches(&version("1.20.1")));
+        assert!(!range.matches(&version("1.21.1")));
+
+        assert!(McVersionRange::from_str("1.20.1").unwrap().matches(&version("1.20.1")));
+        assert!(McVersionRange::from_str("*").unwrap().matches(&version("24w14a")));
+        assert!(McVersionRange::from_str(">=banana").is_err());
+        assert_eq!(McVersionRange::from_str(">=1.19,<1.21").unwrap().to_string(), ">=1.19, <1.21");
+    }
+
+    #[test]
+    fn java_version_follows_mc_version() {
+        assert_eq!(version("1.12.2").java_version(), 8);
+        assert_eq!(version("1.17.1").java_version(), 16);
+        assert_eq!(version("1.20.1").java_version(), 17);
+        assert_eq!(version("1.20.5").java_version(), 21);
+        assert_eq!(version("1.21").java_version(), 21);
+        assert_eq!(version("24w14a").java_version(), 21);
+        assert_eq!(version("21w37a").java_version(), 17);
+        assert_eq!(version("21w19a").java_version(), 16);
+        assert_eq!(version("25w41a").java_version(), 21);
+        assert_eq!(version("26.1").java_version(), 25);
+    }
+}